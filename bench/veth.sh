@@ -0,0 +1,76 @@
+#!/bin/sh
+# Packets per second through a UDP tunnel between two network namespaces
+# joined by a veth pair, moving datagrams one at a time (--batch 1, how
+# boringvpn did before batching) and in batches. Needs root and iproute2:
+#
+#     sudo bench/veth.sh [seconds] [size] [batch]
+#
+# For each direction, `flood` sends datagrams of `size` bytes to the far
+# end of the tunnel for `seconds`. Offered is what it got out, delivered
+# what the far end's device took from boringvpn.
+set -e
+cd "$(dirname "$0")/.."
+
+DURATION=${1:-5}
+SIZE=${2:-1200}
+BATCH=${3:-32}
+BIN=${BIN:-target/release/boringvpn}
+FLOOD=${FLOOD:-target/release/examples/flood}
+SRV=bench-srv
+CLI=bench-cli
+
+if [ -z "$NO_BUILD" ]; then
+    cargo build --release --bin boringvpn --example flood
+fi
+
+cleanup() {
+    ip netns pids $CLI 2>/dev/null | xargs -r kill 2>/dev/null || true
+    ip netns pids $SRV 2>/dev/null | xargs -r kill 2>/dev/null || true
+    sleep 0.5
+    ip netns del $CLI 2>/dev/null || true
+    ip netns del $SRV 2>/dev/null || true
+}
+trap cleanup EXIT
+cleanup
+
+ip netns add $SRV
+ip netns add $CLI
+ip link add bench-s type veth peer name bench-c
+ip link set bench-s netns $SRV
+ip link set bench-c netns $CLI
+ip -n $SRV addr add 198.18.0.1/24 dev bench-s
+ip -n $CLI addr add 198.18.0.2/24 dev bench-c
+ip -n $SRV link set bench-s up
+ip -n $CLI link set bench-c up
+ip -n $SRV link set lo up
+ip -n $CLI link set lo up
+
+rx_packets() {
+    ip netns exec $1 cat /sys/class/net/bench0/statistics/rx_packets
+}
+
+# run <batch> <from> <to> <address>
+run() {
+    ip netns exec $SRV $BIN server -k bench --dev bench0 --batch $1 > /dev/null 2>&1 &
+    sleep 0.5
+    ip netns exec $CLI $BIN client -s 198.18.0.1 -p 9527 -k bench -n --dev bench0 --batch $1 > /dev/null 2>&1 &
+    for _ in $(seq 50); do
+        ip -n $CLI addr show dev bench0 2>/dev/null | grep -q 10.10.10.2 && break
+        sleep 0.2
+    done
+    before=$(rx_packets $3)
+    sent=$(ip netns exec $2 $FLOOD $4:9 $DURATION $SIZE)
+    sleep 0.5
+    after=$(rx_packets $3)
+    printf "%-6s %-16s %12s %12s\n" $1 "${2#bench-} -> ${3#bench-}" $((sent / DURATION)) $(((after - before) / DURATION))
+    ip netns pids $CLI | xargs -r kill -INT
+    sleep 0.5
+    ip netns pids $SRV | xargs -r kill -INT
+    sleep 0.5
+}
+
+printf "%-6s %-16s %12s %12s\n" batch direction offered/s delivered/s
+for batch in 1 $BATCH; do
+    run $batch $CLI $SRV 10.10.10.1
+    run $batch $SRV $CLI 10.10.10.2
+done
@@ -1,7 +0,0 @@
-use cc;
-
-fn main() {
-    cc::Build::new()
-        .file("src/c/tuntap.c")
-        .compile("tuntap.a");
-}
\ No newline at end of file
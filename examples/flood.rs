@@ -0,0 +1,39 @@
+//! Sends UDP datagrams as fast as one socket can for a while and prints how
+//! many went out, the load generator of `bench/veth.sh`:
+//!
+//! ```sh
+//! flood 10.10.10.1:9 5 1200
+//! ```
+use std::env;
+use std::net::{SocketAddr, UdpSocket};
+use std::process;
+use std::time::{Duration, Instant};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("usage: flood <address:port> <seconds> [size]");
+        process::exit(2)
+    }
+    let addr: SocketAddr = args[1].parse().expect("invalid address");
+    let seconds: u64 = args[2].parse().expect("invalid seconds");
+    let size: usize = args.get(3).map_or(1200, |size| size.parse().expect("invalid size"));
+
+    let local = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).expect("failed to bind socket");
+    let buf = vec![0u8; size];
+    let end = Instant::now() + Duration::from_secs(seconds);
+    let mut sent: u64 = 0;
+    while Instant::now() < end {
+        for _ in 0..256 {
+            // A full device queue drops datagrams, ENOBUFS says so too.
+            if socket.send_to(&buf, addr).is_ok() {
+                sent += 1;
+            }
+        }
+    }
+    println!("{}", sent);
+}
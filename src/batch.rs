@@ -0,0 +1,432 @@
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use log::{debug,warn};
+
+use crate::types::Error;
+
+// From linux/udp.h, not every libc has them.
+const UDP_SEGMENT: libc::c_int = 103;
+const UDP_GRO: libc::c_int = 104;
+/// Most datagrams the kernel takes in one UDP_SEGMENT message.
+const MAX_SEGMENTS: usize = 64;
+/// Most bytes one datagram carries, also the limit of what GSO and GRO
+/// coalesce into one.
+const MAX_COALESCED: usize = 65507;
+/// Room for one sealed packet, like the other packet buffers. Larger
+/// datagrams are none of ours and dropped.
+const PACKET_SIZE: usize = 1600;
+/// Most bytes a receiver with GRO allocates. Each message then needs room
+/// for `MAX_COALESCED` bytes, so large batches get fewer messages per call.
+const MAX_GRO_BUF: usize = 4 << 20;
+/// Room for the one control message we send or expect, a UDP_SEGMENT or
+/// UDP_GRO size. u64 keeps it aligned for a cmsghdr.
+type Control = [u64; 4];
+
+/// Datagrams moved per syscall unless `--batch` says otherwise.
+pub const BATCH: usize = 32;
+/// Bounds of `--batch`, the kernel takes at most UIO_MAXIOV messages at once.
+pub const MAX_BATCH: usize = 1024;
+
+/// Checks `batch` is a number of datagrams we can move at once.
+pub fn check_batch(batch: usize) -> Result<(),Error> {
+    if batch == 0 || batch > MAX_BATCH {
+        return Err(Error::Config(format!("invalid batch {}, expected 1 to {}", batch, MAX_BATCH)))
+    }
+    Ok(())
+}
+
+fn set_udp_option(fd: RawFd,option: libc::c_int,value: libc::c_int) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(fd, libc::SOL_UDP, option, &value as *const _ as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error())
+    }
+}
+
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage,libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(ref addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+        },
+        _ => None
+    }
+}
+
+/// Receives up to a batch of datagrams with one recvmmsg. Where the kernel
+/// supports UDP GRO, datagrams of one flow arrive coalesced into one
+/// message, `datagrams` splits them up again.
+pub struct Receiver {
+    buf: Vec<u8>,
+    /// Room for each message in `buf`.
+    size: usize,
+    lens: Vec<usize>,
+    /// Size of the datagrams coalesced into each message, all but the last
+    /// one of a message have it.
+    segments: Vec<usize>,
+    addrs: Vec<libc::sockaddr_storage>,
+    controls: Vec<Control>,
+    iovs: Vec<libc::iovec>,
+    msgs: Vec<libc::mmsghdr>,
+    received: usize
+}
+
+impl Receiver {
+    /// A receiver of `batch` messages at once for `socket`, on which it turns
+    /// on GRO unless `batch` is 1. With GRO, it takes no more messages than
+    /// `MAX_GRO_BUF` has room for.
+    pub fn new<S: AsRawFd>(socket: &S,batch: usize) -> Receiver {
+        let gro = batch > 1 && set_udp_option(socket.as_raw_fd(), UDP_GRO, 1).is_ok();
+        let (size, batch) = match gro {
+            true => (MAX_COALESCED, batch.min(MAX_GRO_BUF / MAX_COALESCED)),
+            false => (PACKET_SIZE, batch)
+        };
+        Receiver {
+            buf: vec![0u8; size * batch],
+            size: size,
+            lens: vec![0; batch],
+            segments: vec![0; batch],
+            addrs: vec![unsafe { mem::zeroed() }; batch],
+            controls: vec![[0; 4]; batch],
+            iovs: Vec::with_capacity(batch),
+            msgs: Vec::with_capacity(batch),
+            received: 0
+        }
+    }
+
+    /// Receives what the socket has queued, up to a batch, and returns the
+    /// number of messages. Fails with `WouldBlock` if there is nothing.
+    pub fn recv<S: AsRawFd>(&mut self,socket: &S) -> io::Result<usize> {
+        self.received = 0;
+        self.iovs.clear();
+        self.msgs.clear();
+        for buf in self.buf.chunks_mut(self.size) {
+            self.iovs.push(libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() });
+        }
+        for ((iov, addr), control) in self.iovs.iter_mut().zip(self.addrs.iter_mut()).zip(self.controls.iter_mut()) {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_controllen = mem::size_of::<Control>() as _;
+            self.msgs.push(msg);
+        }
+        let received = loop {
+            let ret = unsafe { libc::recvmmsg(socket.as_raw_fd(), self.msgs.as_mut_ptr(), self.msgs.len() as _, libc::MSG_DONTWAIT, ptr::null_mut()) };
+            if ret >= 0 {
+                break ret as usize
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err)
+            }
+        };
+        for (i, msg) in self.msgs[..received].iter().enumerate() {
+            // Cut short, it would not authenticate anyway.
+            self.lens[i] = match msg.msg_hdr.msg_flags & libc::MSG_TRUNC {
+                0 => msg.msg_len as usize,
+                _ => 0
+            };
+            self.segments[i] = gro_size(&msg.msg_hdr).unwrap_or(self.lens[i]);
+        }
+        self.received = received;
+        Ok(received)
+    }
+
+    /// The datagrams the last `recv` got, with the address each came from.
+    /// Those larger than a sealed packet can be are left out.
+    pub fn datagrams(&mut self) -> impl Iterator<Item=(&mut [u8],SocketAddr)> {
+        let received = self.received;
+        self.buf.chunks_mut(self.size)
+            .zip(self.lens.iter().zip(&self.segments).zip(&self.addrs))
+            .take(received)
+            .filter_map(|(buf, ((&len, &segment), addr))| Some((&mut buf[..len], segment, socket_addr(addr)?)))
+            .flat_map(|(buf, segment, addr)| buf.chunks_mut(segment.max(1)).map(move |datagram| (datagram, addr)))
+            .filter(|(datagram, _)| datagram.len() <= PACKET_SIZE)
+    }
+}
+
+/// The size of the datagrams GRO coalesced into the message `hdr` got.
+fn gro_size(hdr: &libc::msghdr) -> Option<usize> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(hdr) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_UDP && header.cmsg_type == UDP_GRO {
+            let size = unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) };
+            return Some(size as usize)
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(hdr, cmsg) };
+    }
+    None
+}
+
+/// Sends a batch of datagrams with one sendmmsg. Where the kernel supports
+/// UDP GSO, a run of datagrams of the same size to the same address goes
+/// out as one message the kernel splits up again.
+pub struct Sender {
+    buf: Vec<u8>,
+    lens: Vec<usize>,
+    addrs: Vec<SocketAddr>,
+    queued: usize,
+    gso: bool,
+    /// First datagram and number of datagrams of each message.
+    runs: Vec<(usize,usize)>,
+    names: Vec<(libc::sockaddr_storage,libc::socklen_t)>,
+    controls: Vec<Control>,
+    iovs: Vec<libc::iovec>,
+    msgs: Vec<libc::mmsghdr>
+}
+
+impl Sender {
+    /// A sender of `batch` datagrams at once for `socket`, which uses GSO
+    /// unless `batch` is 1.
+    pub fn new<S: AsRawFd>(socket: &S,batch: usize) -> Sender {
+        // Setting the option is how to find out whether the kernel has it,
+        // the size is given with each message.
+        let gso = batch > 1 && set_udp_option(socket.as_raw_fd(), UDP_SEGMENT, 0).is_ok();
+        Sender {
+            buf: vec![0u8; PACKET_SIZE * batch],
+            lens: vec![0; batch],
+            addrs: Vec::with_capacity(batch),
+            queued: 0,
+            gso: gso,
+            runs: Vec::with_capacity(batch),
+            names: Vec::with_capacity(batch),
+            controls: vec![[0; 4]; batch],
+            iovs: Vec::with_capacity(batch),
+            msgs: Vec::with_capacity(batch)
+        }
+    }
+
+    /// Room for the next datagram, which `push` then queues. Up to a batch
+    /// of them is queued between flushes.
+    pub fn next(&mut self) -> &mut [u8] {
+        let start = self.queued * PACKET_SIZE;
+        &mut self.buf[start..start + PACKET_SIZE]
+    }
+
+    /// Queues the first `len` bytes of `next` to be sent to `addr`.
+    pub fn push(&mut self,len: usize,addr: SocketAddr) {
+        self.lens[self.queued] = len;
+        self.addrs.push(addr);
+        self.queued += 1;
+    }
+
+    /// Sends the queued datagrams. Those the socket has no room for, or
+    /// whose destination the kernel refuses, are dropped as the network
+    /// would. Errors of the socket itself are returned.
+    pub fn flush<S: AsRawFd>(&mut self,socket: &S) -> io::Result<()> {
+        let result = self.send(socket.as_raw_fd(), 0, self.queued, self.gso);
+        self.queued = 0;
+        self.addrs.clear();
+        result
+    }
+
+    /// Sends the queued datagrams `from..to`, coalescing runs of them if `gso`.
+    fn send(&mut self,fd: RawFd,from: usize,to: usize,gso: bool) -> io::Result<()> {
+        self.prepare(from, to, gso);
+        let mut sent = 0;
+        while sent < self.msgs.len() {
+            let ret = unsafe { libc::sendmmsg(fd, self.msgs[sent..].as_mut_ptr(), (self.msgs.len() - sent) as _, libc::MSG_DONTWAIT) };
+            if ret >= 0 {
+                sent += ret as usize;
+                continue
+            }
+            let err = io::Error::last_os_error();
+            let (first, count) = self.runs[sent];
+            match err.raw_os_error() {
+                Some(libc::EINTR) => {},
+                Some(libc::EAGAIN) | Some(libc::ENOBUFS) => return Ok(()),
+                // The device does not do checksums for us or the path takes
+                // no datagrams of this size without fragmenting them. Only
+                // this run goes out one by one, other peers' paths may be fine.
+                Some(libc::EIO) | Some(libc::EINVAL) if count > 1 => {
+                    debug!("UDP GSO to {} failed, sending its datagrams one by one: {}", self.addrs[first], err);
+                    self.send(fd, first, first + count, false)?;
+                    return self.send(fd, first + count, to, gso)
+                },
+                // Only this destination is refused, the others still get theirs.
+                Some(libc::EHOSTUNREACH) | Some(libc::ENETUNREACH) | Some(libc::EPERM) | Some(libc::EACCES)
+                | Some(libc::EMSGSIZE) | Some(libc::EINVAL) | Some(libc::EAFNOSUPPORT) | Some(libc::EADDRNOTAVAIL) => {
+                    warn!("Failed to send to {}: {}", self.addrs[first], err);
+                    sent += 1;
+                },
+                _ => return Err(err)
+            }
+        }
+        Ok(())
+    }
+
+    /// Lays out the messages sending the queued datagrams `from..to`.
+    fn prepare(&mut self,from: usize,to: usize,gso: bool) {
+        self.runs.clear();
+        self.names.clear();
+        self.iovs.clear();
+        self.msgs.clear();
+        let mut first = from;
+        while first < to {
+            let size = self.lens[first];
+            let mut count = 1;
+            let mut total = size;
+            // Only the last datagram of a run may be shorter.
+            while gso && first + count < to && count < MAX_SEGMENTS
+                && self.addrs[first + count] == self.addrs[first]
+                && self.lens[first + count - 1] == size && self.lens[first + count] <= size
+                && total + self.lens[first + count] <= MAX_COALESCED {
+                total += self.lens[first + count];
+                count += 1;
+            }
+            self.runs.push((first, count));
+            self.names.push(sockaddr(&self.addrs[first]));
+            first += count;
+        }
+        for (i, buf) in self.buf.chunks_mut(PACKET_SIZE).enumerate().take(to).skip(from) {
+            self.iovs.push(libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: self.lens[i] });
+        }
+        for (&(first, count), (name, control)) in self.runs.iter().zip(self.names.iter_mut().zip(self.controls.iter_mut())) {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = &mut name.0 as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = name.1;
+            msg.msg_hdr.msg_iov = &mut self.iovs[first - from];
+            msg.msg_hdr.msg_iovlen = count as _;
+            if count > 1 {
+                msg.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg.msg_hdr.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+                unsafe {
+                    let cmsg = libc::CMSG_FIRSTHDR(&msg.msg_hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, self.lens[first] as u16);
+                }
+            }
+            self.msgs.push(msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn sockaddr_test() {
+        for addr in &["127.0.0.1:9527", "[fd00::1]:9527", "[::ffff:192.0.2.1]:1"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(socket_addr(&sockaddr(&addr).0), Some(addr));
+        }
+    }
+
+    #[test]
+    fn batch_test() {
+        let receiving = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sending = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = receiving.local_addr().unwrap();
+        let mut receiver = Receiver::new(&receiving, 16);
+        let mut sender = Sender::new(&sending, 16);
+        // A run GSO sends as one, one shorter datagram ending it and one
+        // starting the next.
+        let sizes = [1200, 1200, 1200, 1000, 1200, 600];
+        for (i, &size) in sizes.iter().enumerate() {
+            for byte in &mut sender.next()[..size] {
+                *byte = i as u8;
+            }
+            sender.push(size, to);
+        }
+        sender.flush(&sending).unwrap();
+
+        let mut datagrams = Vec::new();
+        while datagrams.len() < sizes.len() {
+            receiver.recv(&receiving).unwrap();
+            for (datagram, from) in receiver.datagrams() {
+                assert_eq!(from, sending.local_addr().unwrap());
+                datagrams.push(datagram.to_vec());
+            }
+        }
+        for (i, datagram) in datagrams.iter().enumerate() {
+            assert_eq!(datagram.len(), sizes[i]);
+            assert!(datagram.iter().all(|&byte| byte == i as u8));
+        }
+        assert_eq!(receiver.recv(&receiving).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn oversized_test() {
+        // With GRO the whole datagram fits the buffer, without it is cut short.
+        for &batch in &[16, 1] {
+            let receiving = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let sending = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let to = receiving.local_addr().unwrap();
+            let mut receiver = Receiver::new(&receiving, batch);
+            sending.send_to(&[1; 3000], to).unwrap();
+            sending.send_to(&[2; PACKET_SIZE], to).unwrap();
+            let mut datagrams = Vec::new();
+            while receiver.recv(&receiving).is_ok() {
+                datagrams.extend(receiver.datagrams().map(|(datagram, _)| datagram.to_vec()));
+            }
+            assert_eq!(datagrams, vec![vec![2; PACKET_SIZE]]);
+        }
+    }
+
+    #[test]
+    fn refused_destination_test() {
+        let receiving = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sending = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = receiving.local_addr().unwrap();
+        let mut receiver = Receiver::new(&receiving, 16);
+        let mut sender = Sender::new(&sending, 16);
+        // Broadcasts are refused with EACCES without SO_BROADCAST.
+        let refused: SocketAddr = "255.255.255.255:9".parse().unwrap();
+        for (i, &addr) in [to, to, refused, refused, to, to].iter().enumerate() {
+            for byte in &mut sender.next()[..100] {
+                *byte = i as u8;
+            }
+            sender.push(100, addr);
+        }
+        sender.flush(&sending).unwrap();
+
+        let mut datagrams = Vec::new();
+        while datagrams.len() < 4 {
+            receiver.recv(&receiving).unwrap();
+            datagrams.extend(receiver.datagrams().map(|(datagram, _)| datagram[0]));
+        }
+        assert_eq!(datagrams, vec![0, 1, 4, 5]);
+    }
+}
@@ -1,14 +1,1040 @@
 use serde::{Serialize,Deserialize};
 use std::net::IpAddr;
+use std::io::{self,Read,Write};
+use std::time::{Duration,Instant};
+use bincode::{serialize, deserialize};
+use mio;
 
+use crate::crypto::{self,Crypto,CryptoMethod,KeyExchange,NoiseHandshake,ReplayWindow,COOKIE_LEN};
+use crate::device;
+use crate::types::Error;
 
-type Token = u64;
+/// Identifies a session in the header of every packet after the handshake.
+pub type SessionId = u32;
 
+/// Version of the wire format, bumped on incompatible changes.
+pub const VERSION: u8 = 1;
+/// Length of the cleartext header in front of every packet.
+pub const HEADER_LEN: usize = 16;
+/// Longest client id a hello can carry.
+pub const MAX_ID_LEN: usize = 255;
+/// Room to leave behind a payload for the AEAD tag.
+pub const TAG_LEN: usize = 16;
+
+/// Length of the big-endian size prefix in front of every frame on a stream transport.
+pub const FRAME_HEADER_LEN: usize = 2;
+
+/// Start a rekey once this many packets were sealed under the current key.
+const REKEY_AFTER_PACKETS: u64 = 1 << 32;
+/// Start a rekey once the current key is this old.
+const REKEY_AFTER: Duration = Duration::from_secs(10 * 60);
+/// Send a rekey the peer did not answer again after this long.
+const REKEY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a rekey is sent before starting over with a fresh key.
+const REKEY_ATTEMPTS: u32 = 3;
+/// How long packets sealed with the previous key are still accepted, long
+/// enough for every retry of a rekey whose ack got lost to get through.
+const REKEY_OVERLAP: Duration = Duration::from_secs(10 * REKEY_ATTEMPTS as u64);
+
+/// Default interval between keepalives on a quiet tunnel.
+pub const KEEPALIVE: Duration = Duration::from_secs(10);
+/// Default time without hearing from the peer before it is considered dead.
+pub const DEAD_PEER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Control messages. Tunneled IP packets do not go through here, they are
+/// sealed as they are into `PacketType::Data` packets.
 #[derive(Debug,Serialize,Deserialize,PartialEq)]
 pub enum Message {
-    Request{msg: String},
-    Response { ip: IpAddr,netmask: IpAddr,token: u64,dns: IpAddr},
-    Data {ip: IpAddr,token: u64, data: Vec<u8>}
+    /// `ip` and `ip6` ask for the addresses held before a reconnect. `id`
+    /// names a client with its own key in the server's peer database.
+    /// `public_key` is empty in a static key handshake, which brings its
+    /// own ephemeral keys. `mode` tells what the client's tunnel carries,
+    /// which has to be what the server's does. `time` is when the client
+    /// sent it, in seconds since the Unix epoch, to refuse old copies.
+    Request{msg: String,public_key: Vec<u8>,ciphers: Vec<CryptoMethod>,ip: Option<IpAddr>,ip6: Option<IpAddr>,id: Option<String>,mode: device::Type,time: u64},
+    /// `ip6` is the IPv6 address, its prefix length and the server's IPv6
+    /// address, if the server runs a dual-stack tunnel. `mtu` is the MTU of
+    /// the server's device, for the client's to match.
+    Response { ip: IpAddr,netmask: IpAddr,gateway: IpAddr,ip6: Option<(IpAddr,u8,IpAddr)>,session: SessionId,dns: IpAddr,public_key: Vec<u8>,cipher: CryptoMethod,mtu: u32},
+    /// `retry` tells the client the refusal is temporary, like an exhausted address pool.
+    Reject {reason: String,retry: bool},
+    Rekey {public_key: Vec<u8>},
+    RekeyAck {public_key: Vec<u8>},
+    Ping,
+    Pong,
+    /// The sender is shutting down, the session ends without waiting for
+    /// the dead-peer timeout.
+    Disconnect
+}
+
+/// What a packet carries, taken from its header.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum PacketType {
+    /// A `Request`, `Response` or `Reject` sealed with the pre-shared key.
+    Handshake,
+    /// A `Request`, `Response` or `Reject` carried in a message of a Noise
+    /// handshake, which authenticates both ends by their static keys.
+    Noise,
+    /// Any other `Message`, sealed with the session key.
+    Control,
+    /// A tunneled IP packet, or Ethernet frame in TAP mode, sealed with the
+    /// session key.
+    Data,
+    /// A cookie the server wants the MAC of a hello under before it spends
+    /// anything on it, see `append_cookie_mac`.
+    Cookie
+}
+
+/// The cleartext header in front of every packet:
+///
+/// ```text
+/// 0         1         2         3         4              8                  16
+/// +---------+---------+---------+---------+--------------+------------------+
+/// | version |  type   |  epoch  | id len  |  session id  |  packet counter  |
+/// +---------+---------+---------+---------+--------------+------------------+
+/// ```
+///
+/// The header is authenticated as associated data and the counter is the low
+/// part of the AEAD nonce. `epoch` tells which generation of the session keys
+/// sealed the packet, so the receiver can pick the key during a rekey. `id len`
+/// is zero except on a handshake packet carrying a client id, see
+/// `seal_handshake`.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Header {
+    pub kind: PacketType,
+    pub epoch: u8,
+    pub session: SessionId,
+    pub counter: u64
+}
+
+impl Header {
+    pub fn parse(buf: &[u8]) -> Result<Header,Error> {
+        if buf.len() < HEADER_LEN {
+            return Err(Error::Invaildmessage("packet too short"))
+        }
+        if buf[0] != VERSION {
+            return Err(Error::Invaildmessage("unsupported protocol version"))
+        }
+        let kind = match buf[1] {
+            0 => PacketType::Handshake,
+            1 => PacketType::Control,
+            2 => PacketType::Data,
+            3 => PacketType::Noise,
+            4 => PacketType::Cookie,
+            _ => return Err(Error::Invaildmessage("unknown packet type"))
+        };
+        Ok(Header {
+            kind: kind,
+            epoch: buf[2],
+            session: buf[4..8].iter().fold(0, |session, &b| session << 8 | b as SessionId),
+            counter: buf[8..HEADER_LEN].iter().fold(0, |counter, &b| counter << 8 | b as u64)
+        })
+    }
+
+    pub fn write(&self,buf: &mut [u8]) {
+        buf[0] = VERSION;
+        buf[1] = match self.kind {
+            PacketType::Handshake => 0,
+            PacketType::Control => 1,
+            PacketType::Data => 2,
+            PacketType::Noise => 3,
+            PacketType::Cookie => 4
+        };
+        buf[2] = self.epoch;
+        buf[3] = 0;
+        buf[4..8].copy_from_slice(&self.session.to_be_bytes());
+        buf[8..HEADER_LEN].copy_from_slice(&self.counter.to_be_bytes());
+    }
+}
+
+/// Seals the `len` payload bytes at `buf[HEADER_LEN..]` in place and writes the
+/// header in front of them. `buf` needs `TAG_LEN` spare bytes behind the
+/// payload. Returns the length of the packet.
+pub fn seal_packet(buf: &mut [u8],len: usize,kind: PacketType,epoch: u8,session: SessionId,sender: &mut Crypto) -> usize {
+    let header = Header { kind: kind, epoch: epoch, session: session, counter: sender.next_counter() };
+    let (head, payload) = buf.split_at_mut(HEADER_LEN);
+    header.write(head);
+    HEADER_LEN + sender.seal(header.counter, payload, len, head)
+}
+
+/// Opens a packet in place, leaving the payload at `buf[HEADER_LEN..HEADER_LEN + len]`.
+/// With a replay window, duplicated or too old counters are rejected and
+/// the window is advanced once the packet authenticated.
+pub fn open_packet(buf: &mut [u8],receiver: &Crypto,replay: Option<&mut ReplayWindow>) -> Result<(Header,usize),Error> {
+    let header = Header::parse(buf)?;
+    if let Some(ref window) = replay {
+        if !window.check(header.counter) {
+            return Err(Error::Crypto("Replayed packet"))
+        }
+    }
+    let nonce = receiver.nonce_from_counter(header.counter);
+    let (head, payload) = buf.split_at_mut(HEADER_LEN);
+    let len = receiver.decrypt(payload, &nonce, head)?;
+    if let Some(window) = replay {
+        window.update(header.counter);
+    }
+    Ok((header,len))
 }
 
+pub fn decode(payload: &[u8]) -> Result<Message,Error> {
+    deserialize(payload).map_err(|_| Error::Invaildmessage("failed to deserialize message"))
+}
+
+/// Serializes and seals a control message, returning the packet to put on the wire.
+pub fn seal(msg: &Message,kind: PacketType,epoch: u8,session: SessionId,sender: &mut Crypto) -> Vec<u8> {
+    let encoded: Vec<u8> = serialize(msg).unwrap();
+    let len = encoded.len();
+    let mut buf = vec![0u8; HEADER_LEN + len + sender.additional_bytes()];
+    buf[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&encoded);
+    let size = seal_packet(&mut buf, len, kind, epoch, session, sender);
+    buf.truncate(size);
+    buf
+}
+
+/// Seals a handshake message. A hello from a client with its own key carries
+/// the client id in cleartext behind the header, so the server knows which
+/// key opens it. The id is authenticated along with the header.
+pub fn seal_handshake(msg: &Message,id: Option<&str>,psk: &mut Crypto) -> Vec<u8> {
+    let id = id.unwrap_or("").as_bytes();
+    assert!(id.len() <= MAX_ID_LEN);
+    let encoded: Vec<u8> = serialize(msg).unwrap();
+    let (start, len) = (HEADER_LEN + id.len(), encoded.len());
+    let mut buf = vec![0u8; start + len + psk.additional_bytes()];
+    let header = Header { kind: PacketType::Handshake, epoch: 0, session: 0, counter: psk.next_counter() };
+    header.write(&mut buf);
+    buf[3] = id.len() as u8;
+    buf[HEADER_LEN..start].copy_from_slice(id);
+    buf[start..start + len].copy_from_slice(&encoded);
+    let (head, payload) = buf.split_at_mut(start);
+    let size = start + psk.seal(header.counter, payload, len, head);
+    buf.truncate(size);
+    buf
+}
+
+/// The client id a handshake packet was sealed for, if it carries one.
+pub fn handshake_id(buf: &[u8]) -> Result<Option<String>,Error> {
+    if Header::parse(buf)?.kind != PacketType::Handshake {
+        return Err(Error::Invaildmessage("not a handshake packet"))
+    }
+    let end = HEADER_LEN + buf[3] as usize;
+    match buf[3] {
+        0 => Ok(None),
+        _ if buf.len() < end => Err(Error::Invaildmessage("packet too short")),
+        _ => String::from_utf8(buf[HEADER_LEN..end].to_vec()).map(Some).map_err(|_| Error::Invaildmessage("client id is not UTF-8"))
+    }
+}
+
+pub fn open_handshake(buf: &mut [u8],psk: &Crypto) -> Result<Message,Error> {
+    let start = HEADER_LEN + handshake_id(buf)?.map_or(0, |id| id.len());
+    let nonce = psk.nonce_from_counter(Header::parse(buf)?.counter);
+    let (head, payload) = buf.split_at_mut(start);
+    let len = psk.decrypt(payload, &nonce, head)?;
+    decode(&payload[..len])
+}
+
+/// Writes our next message of a static key handshake, carrying `msg`. The
+/// header only tells the packet type, all fields behind it stay zero.
+pub fn seal_noise(msg: &Message,noise: &mut NoiseHandshake) -> Result<Vec<u8>,Error> {
+    let mut buf = vec![0u8; HEADER_LEN];
+    Header { kind: PacketType::Noise, epoch: 0, session: 0, counter: 0 }.write(&mut buf);
+    buf.extend(noise.write_message(&serialize(msg).unwrap())?);
+    Ok(buf)
+}
+
+pub fn open_noise(buf: &[u8],noise: &mut NoiseHandshake) -> Result<Message,Error> {
+    if Header::parse(buf)?.kind != PacketType::Noise {
+        return Err(Error::Invaildmessage("not a static key handshake packet"))
+    }
+    decode(&noise.read_message(&buf[HEADER_LEN..])?)
+}
+
+/// Appends the MAC a hello carries under the last cookie the server sent us,
+/// zeros while we have none. A server under load only answers hellos whose
+/// MAC proves that the client received a cookie for its source address.
+pub fn append_cookie_mac(hello: &[u8],cookie: Option<&[u8; COOKIE_LEN]>) -> Vec<u8> {
+    let mut packet = hello.to_vec();
+    match cookie {
+        Some(cookie) => packet.extend_from_slice(&crypto::cookie_mac(cookie, &[hello])),
+        None => packet.extend_from_slice(&[0u8; COOKIE_LEN])
+    }
+    packet
+}
+
+/// Splits a hello received from a client into the sealed hello and its cookie MAC.
+pub fn strip_cookie_mac(packet: &mut [u8]) -> Result<(&mut [u8],&[u8]),Error> {
+    if packet.len() < HEADER_LEN + COOKIE_LEN {
+        return Err(Error::Invaildmessage("packet too short"))
+    }
+    let end = packet.len() - COOKIE_LEN;
+    let (hello, mac) = packet.split_at_mut(end);
+    Ok((hello,mac))
+}
+
+/// The cookie the client has to answer with a MAC of its hello. It goes out
+/// in cleartext, only the holder of the address it was sent to gets to see it.
+pub fn seal_cookie(cookie: &[u8; COOKIE_LEN]) -> Vec<u8> {
+    let mut buf = vec![0u8; HEADER_LEN];
+    Header { kind: PacketType::Cookie, epoch: 0, session: 0, counter: 0 }.write(&mut buf);
+    buf.extend_from_slice(cookie);
+    buf
+}
+
+pub fn open_cookie(buf: &[u8]) -> Result<[u8; COOKIE_LEN],Error> {
+    if Header::parse(buf)?.kind != PacketType::Cookie {
+        return Err(Error::Invaildmessage("not a cookie packet"))
+    }
+    if buf.len() != HEADER_LEN + COOKIE_LEN {
+        return Err(Error::Invaildmessage("invalid cookie packet"))
+    }
+    let mut cookie = [0u8; COOKIE_LEN];
+    cookie.copy_from_slice(&buf[HEADER_LEN..]);
+    Ok(cookie)
+}
+
+/// Tracks when a tunnel last carried traffic in each direction.
+///
+/// A `Ping` is due once a keepalive interval passed without sending anything,
+/// to keep NAT bindings open, or without receiving anything, to make a quiet
+/// peer prove it is still there. A peer we did not hear from for the dead-peer
+/// timeout is gone.
+#[derive(Debug,Clone)]
+pub struct Liveness {
+    interval: Duration,
+    timeout: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+    last_ping: Instant
+}
+
+impl Liveness {
+    pub fn new(interval: Duration,timeout: Duration) -> Self {
+        let now = Instant::now();
+        Liveness {
+            interval: interval,
+            timeout: timeout,
+            last_sent: now,
+            last_received: now,
+            last_ping: now
+        }
+    }
+
+    pub fn sent(&mut self) {
+        self.last_sent = Instant::now();
+    }
+
+    pub fn received(&mut self) {
+        self.last_received = Instant::now();
+    }
+
+    fn ping_at(&self) -> Instant {
+        std::cmp::max(self.last_ping, std::cmp::min(self.last_sent, self.last_received)) + self.interval
+    }
+
+    pub fn ping_due(&self,now: Instant) -> bool {
+        now >= self.ping_at()
+    }
+
+    pub fn is_dead(&self,now: Instant) -> bool {
+        now >= self.last_received + self.timeout
+    }
+
+    /// Time until a ping or the dead-peer check is due next, to be used as poll timeout.
+    pub fn next_check(&self,now: Instant) -> Duration {
+        let at = std::cmp::min(self.ping_at(), self.last_received + self.timeout);
+        if at > now { at - now } else { Duration::from_secs(0) }
+    }
+}
+
+/// Session keys of one tunnel together with their rotation.
+///
+/// Either side starts a rekey by sending `Rekey` with a fresh ephemeral key
+/// once the current key sealed `REKEY_AFTER_PACKETS` packets or is older than
+/// `REKEY_AFTER`. The peer answers with `RekeyAck`, still sealed with the old
+/// key, and switches right away; the initiator switches when the ack arrives.
+/// Both keep the previous receiving key for `REKEY_OVERLAP` so packets that
+/// were in flight during the switch are not dropped. An unanswered `Rekey`
+/// is sent again with the same key, which the peer answers with the ack it
+/// sent before, in case that one got lost.
+pub struct SessionKeys {
+    method: CryptoMethod,
+    client: bool,
+    psk: Vec<u8>,
+    session: SessionId,
+    epoch: u8,
+    sender: Crypto,
+    receiver: Crypto,
+    replay: ReplayWindow,
+    previous: Option<(Crypto,ReplayWindow,u8,Instant)>,
+    /// Our ephemeral key of the rekey we started, when we last sent it and
+    /// how often.
+    pending: Option<(KeyExchange,Instant,u32)>,
+    /// The peer's key of the last rekey we answered, and our sealed ack.
+    answered: Option<(Vec<u8>,Vec<u8>)>,
+    established: Instant,
+    rekey_after_packets: u64,
+    rekey_after: Duration,
+    liveness: Liveness
+}
+
+impl SessionKeys {
+    /// `client` tells which end of the tunnel we are, `keys` is the
+    /// `(sender, receiver)` pair from the handshake.
+    pub fn new(method: CryptoMethod,client: bool,psk: &[u8],session: SessionId,keys: (Crypto,Crypto)) -> Self {
+        SessionKeys {
+            method: method,
+            client: client,
+            psk: psk.to_vec(),
+            session: session,
+            epoch: 0,
+            sender: keys.0,
+            receiver: keys.1,
+            replay: ReplayWindow::new(),
+            previous: None,
+            pending: None,
+            answered: None,
+            established: Instant::now(),
+            rekey_after_packets: REKEY_AFTER_PACKETS,
+            rekey_after: REKEY_AFTER,
+            liveness: Liveness::new(KEEPALIVE, DEAD_PEER_TIMEOUT)
+        }
+    }
+
+    pub fn session(&self) -> SessionId {
+        self.session
+    }
+
+    pub fn set_keepalive(&mut self,interval: Duration,timeout: Duration) {
+        self.liveness = Liveness::new(interval, timeout);
+    }
+
+    /// Seals a control message for the peer.
+    pub fn seal(&mut self,msg: &Message) -> Vec<u8> {
+        self.liveness.sent();
+        seal(msg, PacketType::Control, self.epoch, self.session, &mut self.sender)
+    }
+
+    /// Seals the `len` bytes of an IP packet at `buf[HEADER_LEN..]` in place,
+    /// see `seal_packet`.
+    pub fn seal_data(&mut self,buf: &mut [u8],len: usize) -> usize {
+        self.liveness.sent();
+        seal_packet(buf, len, PacketType::Data, self.epoch, self.session, &mut self.sender)
+    }
+
+    /// Opens a packet of this session in place with the key of its epoch,
+    /// which may be the previous one while the rekey overlap lasts.
+    pub fn open(&mut self,buf: &mut [u8]) -> Result<(Header,usize),Error> {
+        let header = Header::parse(buf)?;
+        if header.kind == PacketType::Handshake || header.kind == PacketType::Noise || header.session != self.session {
+            return Err(Error::Invaildmessage("packet does not belong to this session"))
+        }
+        if let Some((_, _, _, until)) = self.previous {
+            if Instant::now() >= until {
+                self.previous = None;
+            }
+        }
+        let opened = match self.previous {
+            _ if header.epoch == self.epoch => open_packet(buf, &self.receiver, Some(&mut self.replay)),
+            Some((ref receiver, ref mut replay, epoch, _)) if epoch == header.epoch => open_packet(buf, receiver, Some(replay)),
+            _ => Err(Error::Crypto("Packet sealed with an unknown key"))
+        };
+        if opened.is_ok() {
+            self.liveness.received();
+        }
+        opened
+    }
+
+    /// Returns a sealed `Ping` if the tunnel was quiet for a keepalive interval.
+    pub fn keepalive(&mut self) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        if !self.liveness.ping_due(now) {
+            return None
+        }
+        self.liveness.last_ping = now;
+        Some(self.seal(&Message::Ping))
+    }
+
+    /// Whether the peer has not been heard from for the dead-peer timeout.
+    pub fn is_dead(&self) -> bool {
+        self.liveness.is_dead(Instant::now())
+    }
+
+    pub fn next_check(&self) -> Duration {
+        self.liveness.next_check(Instant::now())
+    }
+
+    /// Handles a control message received on the session, returning the sealed
+    /// reply to send, if any.
+    pub fn handle_control(&mut self,msg: Message) -> Result<Option<Vec<u8>>,Error> {
+        match msg {
+            Message::Rekey{..} | Message::RekeyAck{..} => self.handle_rekey(msg),
+            Message::Ping => Ok(Some(self.seal(&Message::Pong))),
+            Message::Pong => Ok(None),
+            Message::Reject { reason, .. } => Err(Error::Rejected(reason)),
+            Message::Disconnect => Err(Error::Disconnected),
+            _ => Err(Error::Invaildmessage("unexpected message on an established session"))
+        }
+    }
+
+    /// Returns the sealed `Rekey` message to send if the current key is due
+    /// for rotation and no rekey is in progress.
+    pub fn start_rekey(&mut self) -> Result<Option<Vec<u8>>,Error> {
+        let now = Instant::now();
+        if let Some((ref kx, ref mut sent, ref mut attempts)) = self.pending {
+            if now.duration_since(*sent) < REKEY_TIMEOUT {
+                return Ok(None)
+            }
+            if *attempts < REKEY_ATTEMPTS {
+                log::warn!("Rekey was not answered, sending it again");
+                *sent = now;
+                *attempts += 1;
+                let msg = Message::Rekey { public_key: kx.public_key().to_vec() };
+                return Ok(Some(self.seal(&msg)))
+            }
+            log::warn!("Rekey was not answered, starting over");
+        }
+        if self.sender.counter() < self.rekey_after_packets && now.duration_since(self.established) < self.rekey_after {
+            return Ok(None)
+        }
+        let kx = KeyExchange::new()?;
+        let msg = Message::Rekey { public_key: kx.public_key().to_vec() };
+        self.pending = Some((kx, now, 1));
+        Ok(Some(self.seal(&msg)))
+    }
 
+    /// Handles `Rekey` and `RekeyAck`, returning the sealed reply to send, if any.
+    pub fn handle_rekey(&mut self,msg: Message) -> Result<Option<Vec<u8>>,Error> {
+        match msg {
+            Message::Rekey { public_key } => {
+                // The peer missed our ack and still uses the previous key.
+                if let Some((ref answered, ref ack)) = self.answered {
+                    if *answered == public_key {
+                        return Ok(Some(ack.clone()))
+                    }
+                }
+                if self.pending.is_some() {
+                    // Both ends started at once: the client's rekey wins.
+                    if self.client {
+                        return Ok(None)
+                    }
+                    self.pending = None;
+                }
+                let kx = KeyExchange::new()?;
+                let reply = self.seal(&Message::RekeyAck { public_key: kx.public_key().to_vec() });
+                let keys = kx.derive(self.method, &self.psk, &public_key, self.client)?;
+                self.install(keys);
+                self.answered = Some((public_key, reply.clone()));
+                Ok(Some(reply))
+            },
+            Message::RekeyAck { public_key } => {
+                match self.pending.take() {
+                    Some((kx, _, _)) => {
+                        let keys = kx.derive(self.method, &self.psk, &public_key, self.client)?;
+                        self.install(keys);
+                    },
+                    None => log::warn!("Unexpected rekey ack")
+                }
+                Ok(None)
+            },
+            _ => Err(Error::Invaildmessage("not a rekey message"))
+        }
+    }
+
+    fn install(&mut self,keys: (Crypto,Crypto)) {
+        let now = Instant::now();
+        let receiver = std::mem::replace(&mut self.receiver, keys.1);
+        let replay = std::mem::replace(&mut self.replay, ReplayWindow::new());
+        self.previous = Some((receiver, replay, self.epoch, now + REKEY_OVERLAP));
+        self.epoch = self.epoch.wrapping_add(1);
+        self.sender = keys.0;
+        self.established = now;
+        log::info!("Session keys rotated");
+    }
+}
+
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() <= u16::max_value() as usize);
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.push((payload.len() >> 8) as u8);
+    frame.push(payload.len() as u8);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Writes one frame to a blocking stream.
+pub fn write_frame<W: Write>(stream: &mut W,payload: &[u8]) -> io::Result<()> {
+    stream.write_all(&encode_frame(payload))
+}
+
+/// Reads one frame from a blocking stream.
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    let len = (header[0] as usize) << 8 | header[1] as usize;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Reassembles frames from the arbitrary chunks a stream hands us.
+pub struct FrameDecoder {
+    buf: Vec<u8>
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder { buf: Vec::new() }
+    }
+
+    pub fn feed(&mut self,data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.buf.len() < FRAME_HEADER_LEN {
+            return None
+        }
+        let len = (self.buf[0] as usize) << 8 | self.buf[1] as usize;
+        if self.buf.len() < FRAME_HEADER_LEN + len {
+            return None
+        }
+        let frame = self.buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+        self.buf.drain(..FRAME_HEADER_LEN + len);
+        Some(frame)
+    }
+}
+
+/// Most bytes a `Framed` stream queues for a peer that does not read them.
+const MAX_OUTBUF: usize = 256 * 1024;
+/// Most bytes `Framed::read_frames` takes off the socket per call.
+const MAX_READ: usize = 64 * 1024;
+
+/// A non-blocking TCP stream carrying length-prefixed frames.
+///
+/// Outgoing frames that the socket cannot take right away are kept in `outbuf`
+/// and flushed once the stream becomes writable again. Once `MAX_OUTBUF` bytes
+/// wait there, further frames are dropped as a full socket buffer would, so a
+/// peer that stops reading does not grow it without bound.
+pub struct Framed {
+    stream: mio::net::TcpStream,
+    decoder: FrameDecoder,
+    outbuf: Vec<u8>
+}
+
+impl Framed {
+    pub fn new(stream: mio::net::TcpStream) -> Self {
+        Framed {
+            stream: stream,
+            decoder: FrameDecoder::new(),
+            outbuf: Vec::new()
+        }
+    }
+
+    pub fn stream(&self) -> &mio::net::TcpStream {
+        &self.stream
+    }
+
+    /// Ready set the stream should be registered with.
+    pub fn interest(&self) -> mio::Ready {
+        if self.outbuf.is_empty() {
+            mio::Ready::readable()
+        } else {
+            mio::Ready::readable() | mio::Ready::writable()
+        }
+    }
+
+    /// Reads up to `MAX_READ` bytes off the socket and returns every complete
+    /// frame received so far. What is left stays readable for the next call,
+    /// so a peer sending faster than we handle its frames does not grow the
+    /// decoder without bound. The peer closing the connection is reported as
+    /// `UnexpectedEof`, once the frames it sent before are returned.
+    pub fn read_frames(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        let mut buf = [0u8; 4096];
+        let mut closed = false;
+        let mut read = 0;
+        while read < MAX_READ {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    closed = true;
+                    break
+                },
+                Ok(len) => {
+                    self.decoder.feed(&buf[..len]);
+                    read += len;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        }
+        let mut frames = Vec::new();
+        while let Some(frame) = self.decoder.next_frame() {
+            frames.push(frame);
+        }
+        // A closed stream stays readable, the next call reports it.
+        if closed && frames.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by peer"))
+        }
+        Ok(frames)
+    }
+
+    pub fn send(&mut self,payload: &[u8]) -> io::Result<()> {
+        if self.outbuf.len() + FRAME_HEADER_LEN + payload.len() <= MAX_OUTBUF {
+            self.outbuf.extend_from_slice(&encode_frame(payload));
+        }
+        self.flush()
+    }
+
+    /// Writes as much of the pending output as the socket accepts.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.outbuf.is_empty() {
+            match self.stream.write(&self.outbuf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write frame")),
+                Ok(len) => { self.outbuf.drain(..len); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::boring::*;
+    use crate::crypto::{CryptoMethod,PrivateKey};
+
+    fn request() -> Message {
+        Message::Request { msg: "hello".to_string(), public_key: vec![0; 32], ciphers: vec![CryptoMethod::AES256], ip: None, ip6: None, id: None, mode: device::Type::Tun, time: 0 }
+    }
+
+    #[test]
+    fn frame_decoder_test() {
+        let mut stream = encode_frame(b"hello");
+        stream.extend(encode_frame(b""));
+        stream.extend(encode_frame(&[7u8; 300]));
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(3) {
+            decoder.feed(chunk);
+            while let Some(frame) = decoder.next_frame() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames, vec![b"hello".to_vec(), Vec::new(), vec![7u8; 300]]);
+    }
+
+    #[test]
+    fn read_write_frame_test() {
+        let mut sender = Crypto::from_shared_key(CryptoMethod::AES256, "test");
+        let receiver = Crypto::from_shared_key(CryptoMethod::AES256, "test");
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &seal_handshake(&request(), None, &mut sender)).unwrap();
+        let mut frame = read_frame(&mut &stream[..]).unwrap();
+        assert_eq!(handshake_id(&frame).unwrap(), None);
+        assert_eq!(open_handshake(&mut frame, &receiver).unwrap(), request());
+    }
+
+    #[test]
+    fn framed_outbuf_test() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_peer, _) = listener.accept().unwrap();
+        let mut conn = Framed::new(mio::net::TcpStream::from_stream(stream).unwrap());
+        // The peer never reads, far more than any socket buffer takes.
+        for _ in 0..20000 {
+            conn.send(&[0u8; 1400]).unwrap();
+        }
+        assert!(!conn.outbuf.is_empty());
+        assert!(conn.outbuf.len() <= MAX_OUTBUF);
+    }
+
+    #[test]
+    fn framed_read_test() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut conn = Framed::new(mio::net::TcpStream::from_stream(stream).unwrap());
+        let frames = 4 * MAX_READ / 1000;
+        for _ in 0..frames {
+            peer.write_all(&encode_frame(&[0u8; 998])).unwrap();
+        }
+        let mut received = 0;
+        // Each call takes no more than its share off the socket.
+        while received < frames {
+            let read = conn.read_frames().unwrap().len();
+            assert!(read <= MAX_READ / 1000 + 1);
+            received += read;
+        }
+        assert_eq!(received, frames);
+    }
+
+    #[test]
+    fn handshake_id_test() {
+        let mut sender = Crypto::from_shared_key(CryptoMethod::AES256, "test");
+        let receiver = Crypto::from_shared_key(CryptoMethod::AES256, "test");
+        let packet = seal_handshake(&request(), Some("laptop"), &mut sender);
+        assert_eq!(packet[3], 6);
+        assert_eq!(handshake_id(&packet).unwrap(), Some("laptop".to_string()));
+        assert_eq!(open_handshake(&mut packet.clone(), &receiver).unwrap(), request());
+        // The id is authenticated, another one does not open.
+        let mut tampered = packet.clone();
+        tampered[HEADER_LEN] = b'L';
+        assert_eq!(handshake_id(&tampered).unwrap(), Some("Laptop".to_string()));
+        assert!(open_handshake(&mut tampered, &receiver).is_err());
+        assert!(handshake_id(&packet[..HEADER_LEN + 3]).is_err());
+    }
+
+    #[test]
+    fn noise_packet_test() {
+        let client_key = PrivateKey::generate().unwrap();
+        let server_key = PrivateKey::generate().unwrap();
+        let mut client = NoiseHandshake::initiator(&client_key, &server_key.public_key());
+        let mut server = NoiseHandshake::responder(&server_key);
+        let hello = seal_noise(&request(), &mut client).unwrap();
+        assert_eq!(Header::parse(&hello).unwrap().kind, PacketType::Noise);
+        assert!(open_handshake(&mut hello.clone(), &Crypto::from_shared_key(CryptoMethod::AES256, "test")).is_err());
+        assert_eq!(open_noise(&hello, &mut server).unwrap(), request());
+        let reject = Message::Reject { reason: "no".to_string(), retry: false };
+        let reply = seal_noise(&reject, &mut server).unwrap();
+        assert_eq!(open_noise(&reply, &mut client).unwrap(), reject);
+    }
+
+    #[test]
+    fn cookie_test() {
+        let mut psk = Crypto::from_shared_key(CryptoMethod::AES256, "test");
+        let hello = seal_handshake(&request(), None, &mut psk);
+        let mut packet = append_cookie_mac(&hello, None);
+        {
+            let (stripped, mac) = strip_cookie_mac(&mut packet).unwrap();
+            assert_eq!(stripped, &hello[..]);
+            assert_eq!(mac, &[0u8; COOKIE_LEN][..]);
+        }
+
+        let cookie = [7u8; COOKIE_LEN];
+        let reply = seal_cookie(&cookie);
+        assert_eq!(Header::parse(&reply).unwrap().kind, PacketType::Cookie);
+        assert_eq!(open_cookie(&reply).unwrap(), cookie);
+        assert!(open_cookie(&reply[..reply.len() - 1]).is_err());
+        assert!(open_cookie(&hello).is_err());
+
+        let mut packet = append_cookie_mac(&hello, Some(&cookie));
+        let (stripped, mac) = strip_cookie_mac(&mut packet).unwrap();
+        assert!(crypto::verify_cookie_mac(&cookie, stripped, mac));
+        assert!(!crypto::verify_cookie_mac(&[8u8; COOKIE_LEN], stripped, mac));
+        assert_eq!(open_handshake(stripped, &psk).unwrap(), request());
+        assert!(strip_cookie_mac(&mut [0u8; HEADER_LEN + COOKIE_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn header_test() {
+        let header = Header { kind: PacketType::Data, epoch: 3, session: 0xdeadbeef, counter: 0x0102030405060708 };
+        let mut buf = [0u8; HEADER_LEN];
+        header.write(&mut buf);
+        assert_eq!(buf[..4], [VERSION, 2, 3, 0]);
+        assert_eq!(Header::parse(&buf).unwrap(), header);
+        assert!(Header::parse(&buf[..HEADER_LEN - 1]).is_err());
+        buf[0] = VERSION + 1;
+        assert!(Header::parse(&buf).is_err());
+        buf[0] = VERSION;
+        buf[1] = 9;
+        assert!(Header::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn data_packet_test() {
+        let mut sender = Crypto::from_shared_key(CryptoMethod::ChaCha20, "test");
+        let receiver = Crypto::from_shared_key(CryptoMethod::ChaCha20, "test");
+        let payload = [0x45u8; 100];
+        let mut buf = [0u8; 256];
+        buf[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(&payload);
+        let size = seal_packet(&mut buf, payload.len(), PacketType::Data, 0, 7, &mut sender);
+        assert_eq!(size, HEADER_LEN + payload.len() + TAG_LEN);
+        assert!(buf[HEADER_LEN..HEADER_LEN + payload.len()] != payload[..]);
+
+        let mut tampered = buf;
+        tampered[4] ^= 1;
+        assert!(open_packet(&mut tampered[..size], &receiver, None).is_err());
+
+        let (header, len) = open_packet(&mut buf[..size], &receiver, None).unwrap();
+        assert_eq!(header.kind, PacketType::Data);
+        assert_eq!(header.session, 7);
+        assert_eq!(buf[HEADER_LEN..HEADER_LEN + len], payload[..]);
+    }
+
+    #[test]
+    fn replayed_packet_test() {
+        let mut sender = Crypto::from_shared_key(CryptoMethod::AES256, "test");
+        let receiver = Crypto::from_shared_key(CryptoMethod::AES256, "test");
+        let mut window = ReplayWindow::new();
+        let first = seal(&request(), PacketType::Control, 0, 1, &mut sender);
+        let second = seal(&request(), PacketType::Control, 0, 1, &mut sender);
+        assert!(Header::parse(&first).unwrap().counter != Header::parse(&second).unwrap().counter);
+        assert!(open_packet(&mut second.clone(), &receiver, Some(&mut window)).is_ok());
+        assert!(open_packet(&mut first.clone(), &receiver, Some(&mut window)).is_ok());
+        assert!(open_packet(&mut second.clone(), &receiver, Some(&mut window)).is_err());
+        let mut tampered = first.clone();
+        tampered[HEADER_LEN - 1] ^= 1;
+        assert!(open_packet(&mut tampered, &receiver, None).is_err());
+    }
+
+    fn session_pair() -> (SessionKeys, SessionKeys) {
+        let psk = Crypto::from_shared_key(CryptoMethod::AES256, "test");
+        let client = KeyExchange::new().unwrap();
+        let server = KeyExchange::new().unwrap();
+        let client_public_key = client.public_key().to_vec();
+        let server_public_key = server.public_key().to_vec();
+        let client_keys = client.derive(CryptoMethod::AES256, psk.get_key(), &server_public_key, true).unwrap();
+        let server_keys = server.derive(CryptoMethod::AES256, psk.get_key(), &client_public_key, false).unwrap();
+        (SessionKeys::new(CryptoMethod::AES256, true, psk.get_key(), 1, client_keys),
+         SessionKeys::new(CryptoMethod::AES256, false, psk.get_key(), 1, server_keys))
+    }
+
+    fn data(keys: &mut SessionKeys,n: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; HEADER_LEN + 1 + TAG_LEN];
+        buf[HEADER_LEN] = n;
+        let size = keys.seal_data(&mut buf, 1);
+        buf.truncate(size);
+        buf
+    }
+
+    fn open_data(keys: &mut SessionKeys,mut packet: Vec<u8>) -> Result<u8,Error> {
+        let (header, len) = keys.open(&mut packet)?;
+        assert_eq!((header.kind, len), (PacketType::Data, 1));
+        Ok(packet[HEADER_LEN])
+    }
+
+    fn open_control(keys: &mut SessionKeys,mut packet: Vec<u8>) -> Message {
+        let (header, len) = keys.open(&mut packet).unwrap();
+        assert_eq!(header.kind, PacketType::Control);
+        decode(&packet[HEADER_LEN..HEADER_LEN + len]).unwrap()
+    }
+
+    #[test]
+    fn rekey_test() {
+        let (mut client, mut server) = session_pair();
+        client.rekey_after_packets = 2;
+        assert!(client.start_rekey().unwrap().is_none());
+        let in_flight = data(&mut client, 1);
+        let packet = data(&mut client, 2);
+        assert_eq!(open_data(&mut server, packet).unwrap(), 2);
+
+        let rekey = client.start_rekey().unwrap().unwrap();
+        assert!(client.start_rekey().unwrap().is_none());
+        let msg = open_control(&mut server, rekey);
+        let ack = server.handle_rekey(msg).unwrap().unwrap();
+        let from_server = data(&mut server, 3);
+        let msg = open_control(&mut client, ack);
+        assert!(client.handle_rekey(msg).unwrap().is_none());
+        assert!(client.start_rekey().unwrap().is_none());
+
+        assert_eq!(open_data(&mut client, from_server).unwrap(), 3);
+        let packet = data(&mut client, 4);
+        assert_eq!(Header::parse(&packet).unwrap().epoch, 1);
+        assert_eq!(open_data(&mut server, packet).unwrap(), 4);
+        // Sealed before the switch and delivered late.
+        assert_eq!(open_data(&mut server, in_flight.clone()).unwrap(), 1);
+        assert!(open_data(&mut server, in_flight.clone()).is_err());
+
+        server.previous.as_mut().unwrap().3 = Instant::now();
+        let old = data(&mut session_pair().0, 5);
+        assert!(open_data(&mut server, old).is_err());
+        assert!(server.previous.is_none());
+    }
+
+    #[test]
+    fn rekey_lost_ack_test() {
+        let (mut client, mut server) = session_pair();
+        client.rekey_after_packets = 1;
+        assert_eq!(open_data(&mut server, data(&mut client, 1)).unwrap(), 1);
+        let rekey = client.start_rekey().unwrap().unwrap();
+        let msg = open_control(&mut server, rekey);
+        let lost = server.handle_rekey(msg).unwrap().unwrap();
+        let from_server = data(&mut server, 2);
+        assert!(open_data(&mut client, from_server.clone()).is_err());
+
+        // The retry still uses the old key and gets the same ack.
+        client.pending.as_mut().unwrap().1 = Instant::now() - REKEY_TIMEOUT;
+        let retry = client.start_rekey().unwrap().unwrap();
+        assert_eq!(Header::parse(&retry).unwrap().epoch, 0);
+        let msg = open_control(&mut server, retry);
+        let ack = server.handle_rekey(msg).unwrap().unwrap();
+        assert_eq!(ack, lost);
+        let msg = open_control(&mut client, ack);
+        assert!(client.handle_rekey(msg).unwrap().is_none());
+
+        assert_eq!(open_data(&mut client, from_server).unwrap(), 2);
+        assert_eq!(open_data(&mut server, data(&mut client, 3)).unwrap(), 3);
+        assert!(REKEY_OVERLAP >= REKEY_TIMEOUT * REKEY_ATTEMPTS);
+    }
+
+    #[test]
+    fn liveness_test() {
+        let interval = Duration::from_secs(10);
+        let mut liveness = Liveness::new(interval, Duration::from_secs(30));
+        let start = liveness.last_received;
+        assert!(!liveness.ping_due(start));
+        assert_eq!(liveness.next_check(start), interval);
+        assert!(liveness.ping_due(start + interval));
+
+        // Sending alone does not keep a quiet peer from being pinged.
+        liveness.last_sent = start + Duration::from_secs(9);
+        assert!(liveness.ping_due(start + interval));
+        liveness.last_received = start + Duration::from_secs(5);
+        assert!(!liveness.ping_due(start + interval));
+        assert_eq!(liveness.next_check(start + interval), Duration::from_secs(5));
+
+        liveness.last_ping = start + Duration::from_secs(20);
+        assert!(!liveness.ping_due(start + Duration::from_secs(25)));
+        assert!(!liveness.is_dead(start + Duration::from_secs(34)));
+        assert!(liveness.is_dead(start + Duration::from_secs(35)));
+        assert_eq!(liveness.next_check(start + Duration::from_secs(40)), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn keepalive_test() {
+        let (mut client, mut server) = session_pair();
+        assert!(client.keepalive().is_none());
+        client.set_keepalive(Duration::from_secs(0), DEAD_PEER_TIMEOUT);
+        let ping = client.keepalive().unwrap();
+        let msg = open_control(&mut server, ping);
+        assert_eq!(msg, Message::Ping);
+        let pong = server.handle_control(msg).unwrap().unwrap();
+        let msg = open_control(&mut client, pong);
+        assert_eq!(msg, Message::Pong);
+        assert!(client.handle_control(msg).unwrap().is_none());
+        assert!(!client.is_dead());
+
+        client.set_keepalive(KEEPALIVE, Duration::from_secs(0));
+        assert!(client.is_dead());
+        assert!(client.handle_control(request()).is_err());
+        match client.handle_control(Message::Reject { reason: "revoked".to_string(), retry: false }) {
+            Err(Error::Rejected(reason)) => assert_eq!(reason, "revoked"),
+            _ => panic!("reject on a session was not fatal")
+        }
+        let disconnect = open_control(&mut server, client.seal(&Message::Disconnect));
+        match server.handle_control(disconnect) {
+            Err(Error::Disconnected) => {},
+            _ => panic!("disconnect did not end the session")
+        }
+    }
+
+    #[test]
+    fn simultaneous_rekey_test() {
+        let (mut client, mut server) = session_pair();
+        client.rekey_after_packets = 0;
+        server.rekey_after_packets = 0;
+        let client_rekey = client.start_rekey().unwrap().unwrap();
+        let server_rekey = server.start_rekey().unwrap().unwrap();
+        let msg = open_control(&mut client, server_rekey);
+        assert!(client.handle_rekey(msg).unwrap().is_none());
+        let msg = open_control(&mut server, client_rekey);
+        let ack = server.handle_rekey(msg).unwrap().unwrap();
+        let msg = open_control(&mut client, ack);
+        client.handle_rekey(msg).unwrap();
+        let packet = data(&mut client, 1);
+        assert_eq!(open_data(&mut server, packet).unwrap(), 1);
+        let packet = data(&mut server, 2);
+        assert_eq!(open_data(&mut client, packet).unwrap(), 2);
+    }
+}
@@ -1,30 +1,161 @@
-use clap::{App, Arg, Error,SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use clap;
-use std::{ffi::OsString,path::PathBuf};
-use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
+use std::env;
+use std::io::{self,Read};
+use std::path::Path;
 
 use crate::client::Client;
 use crate::server::Server;
+use crate::config::{self,ClientConfig,ServerConfig};
+use crate::crypto::{PrivateKey,SharedKey};
+use crate::types::Error;
 
 
 #[derive(Debug,Clone)]
 pub enum Args {
     Client(Client),
-    Server(Server)
+    Server(Server),
+    GenKey,
+    PubKey
 }
 
-pub fn get_args() -> Result<Args,String> {
+/// The value of a flag given on the command line, else the one from the
+/// config file, else the flag's default.
+fn setting(matches: &ArgMatches,name: &str,file: Option<String>) -> Option<String> {
+    match (matches.occurrences_of(name), file) {
+        (0, Some(value)) => Some(value),
+        _ => matches.value_of(name).map(String::from)
+    }
+}
+
+fn required(matches: &ArgMatches,name: &str,file: Option<String>) -> Result<String,Error> {
+    setting(matches, name, file).ok_or_else(|| Error::Config(format!("missing {0}, set it with --{0} or in the config file", name)))
+}
+
+fn number<T: std::str::FromStr>(name: &str,value: &str) -> Result<T,Error> {
+    value.parse().map_err(|_| Error::Config(format!("invalid {} '{}'", name, value)))
+}
+
+/// Environment variable the pre-shared key is taken from when no flag sets it.
+const KEY_ENV: &str = "BORINGVPN_KEY";
+
+/// The pre-shared key from `--key`, `--key-file`, the environment or the
+/// config file, in that order.
+fn key(matches: &ArgMatches,key: Option<String>,key_file: Option<String>) -> Result<Option<SharedKey>,Error> {
+    if let Some(key) = matches.value_of("key") {
+        return SharedKey::parse(key).map(Some)
+    }
+    if let Some(path) = matches.value_of("key-file") {
+        return config::read_key_file(Path::new(path)).map(Some)
+    }
+    if let Some(key) = env::var_os(KEY_ENV) {
+        return SharedKey::parse(&key.to_string_lossy()).map(Some)
+    }
+    match (key, key_file) {
+        (Some(key), _) => SharedKey::parse(&key).map(Some),
+        (None, Some(path)) => config::read_key_file(Path::new(&path)).map(Some),
+        (None, None) => Ok(None)
+    }
+}
+
+fn missing_key() -> Error {
+    Error::Config(format!("missing key, set it with --key, --key-file, {} or in the config file", KEY_ENV))
+}
+
+fn key_args<'a,'b>() -> Vec<Arg<'a,'b>> {
+    vec![Arg::with_name("key")
+            .short("k")
+            .long("key")
+            .conflicts_with("key-file")
+            .help("set the key for encryption communication, visible to other users in the process list; base64:<32 bytes> is used as a raw key, anything else as a passphrase")
+            .takes_value(true),
+        Arg::with_name("key-file")
+            .long("key-file")
+            .help("read the key from a file not readable by others, holding the key as text like --key")
+            .takes_value(true)]
+}
+
+fn private_key_arg<'a,'b>() -> Arg<'a,'b> {
+    Arg::with_name("private-key-file")
+        .long("private-key-file")
+        .help("authenticate by public key with the private key from this file, made with boringvpn genkey")
+        .takes_value(true)
+}
+
+/// Prints a new private key for `--private-key-file`.
+pub fn genkey() -> Result<(),Error> {
+    println!("{}", PrivateKey::generate()?.to_base64());
+    Ok(())
+}
+
+/// Reads a private key from stdin and prints its public key.
+pub fn pubkey() -> Result<(),Error> {
+    let mut key = String::new();
+    io::stdin().read_to_string(&mut key).map_err(|e| Error::File("failed to read private key from stdin",e))?;
+    println!("{}", PrivateKey::parse(&key)?.public_key());
+    Ok(())
+}
+
+fn mode_arg<'a,'b>() -> Arg<'a,'b> {
+    Arg::with_name("mode")
+        .long("mode")
+        .default_value("tun")
+        .possible_values(&["tun", "tap"])
+        .help("set what the tunnel carries, ip packets (tun) or ethernet frames (tap)")
+        .takes_value(true)
+}
+
+fn batch_arg<'a,'b>() -> Arg<'a,'b> {
+    Arg::with_name("batch")
+        .long("batch")
+        .default_value("32")
+        .help("set the datagrams sent or received per syscall over udp, 1 moves them one at a time")
+        .takes_value(true)
+}
+
+fn device_args<'a,'b>() -> Vec<Arg<'a,'b>> {
+    vec![Arg::with_name("dev")
+            .long("dev")
+            .help("set the name of the tun or tap device, default tun0, tun1, ... or tap0, ... whichever is free")
+            .takes_value(true),
+        Arg::with_name("mtu")
+            .long("mtu")
+            .help("set the mtu of the device, default 1420 for tun and 1406 for tap; clients take the server's unless set")
+            .takes_value(true),
+        Arg::with_name("persist")
+            .long("persist")
+            .help("keep the device after exiting, it is reused on the next start"),
+        Arg::with_name("owner")
+            .long("owner")
+            .help("let this user attach to the persistent device")
+            .takes_value(true),
+        Arg::with_name("group")
+            .long("group")
+            .help("let this group attach to the persistent device")
+            .takes_value(true)]
+}
+
+fn config_arg<'a,'b>() -> Arg<'a,'b> {
+    Arg::with_name("config")
+        .long("config")
+        .help("read settings from a TOML file, flags given as well override them")
+        .takes_value(true)
+}
+
+pub fn get_args() -> Result<Args,Error> {
     let matches = App::new("boringvpn: so boring vpn power by rust")
                             .version("0.1")
+                            .setting(AppSettings::SubcommandRequiredElseHelp)
                             .subcommand(SubCommand::with_name("server")
                                         .about("server mode")
                                         .version("0.1")
                                         .author("Attenuation <ouyangjun1999@gmail.com>")
+                                        .arg(config_arg())
                                         .arg(Arg::with_name("bind")
                                             .short("l")
                                             .long("listen")
                                             .default_value("0.0.0.0")
-                                            .help("set the listen address")
+                                            .help("set the listen address, [::] listens on IPv6 and IPv4")
                                             .takes_value(true))
                                         .arg(Arg::with_name("port")
                                             .short("p")
@@ -32,11 +163,12 @@ pub fn get_args() -> Result<Args,String> {
                                             .default_value("9527")
                                             .help("set the listen port")
                                             .takes_value(true))
-                                        .arg(Arg::with_name("key")
-                                            .short("k")
-                                            .long("key")
-                                            .help("set the key for encryption communication")
+                                        .args(&key_args())
+                                        .arg(Arg::with_name("peers")
+                                            .long("peers")
+                                            .help("accept clients with their own key from this TOML file, which is reloaded when it changes; --key becomes optional")
                                             .takes_value(true))
+                                        .arg(private_key_arg())
                                         .arg(Arg::with_name("dns")
                                             .short("d")
                                             .long("dns")
@@ -46,74 +178,254 @@ pub fn get_args() -> Result<Args,String> {
                                         .arg(Arg::with_name("ip")
                                             .short("i")
                                             .long("ip")
-                                            .default_value("10.10.10.1")
-                                            .help("set tun ip address")
+                                            .help("set tun ip address, default 10.10.10.1 or the first address of the pool")
                                             .takes_value(true))
                                         .arg(Arg::with_name("netmask")
                                             .short("n")
                                             .long("netmask")
-                                            .default_value("255.255.255.0")
+                                            .conflicts_with("pool")
                                             .takes_value(true)
-                                            .help("set tun netmask"))
+                                            .help("set tun netmask, default 255.255.255.0"))
+                                        .arg(Arg::with_name("pool")
+                                            .long("pool")
+                                            .takes_value(true)
+                                            .help("set the network to lease client addresses from, like 10.8.0.0/16, default the network of ip and netmask"))
+                                        .arg(Arg::with_name("pool6")
+                                            .long("pool6")
+                                            .takes_value(true)
+                                            .help("also lease IPv6 client addresses from this network, like fd00:8::/64"))
+                                        .arg(Arg::with_name("ip6")
+                                            .long("ip6")
+                                            .takes_value(true)
+                                            .help("set tun ipv6 address, default the first address of pool6"))
+                                        .arg(Arg::with_name("proto")
+                                            .long("proto")
+                                            .default_value("udp")
+                                            .possible_values(&["udp", "tcp"])
+                                            .help("set the transport protocol")
+                                            .takes_value(true))
+                                        .arg(mode_arg())
+                                        .args(&device_args())
+                                        .arg(Arg::with_name("bridge")
+                                            .long("bridge")
+                                            .help("attach the tap device to this linux bridge, which holds the server's ip instead")
+                                            .takes_value(true))
+                                        .arg(Arg::with_name("cipher")
+                                            .short("c")
+                                            .long("cipher")
+                                            .default_value("aes256,chacha20")
+                                            .help("set the ciphers to accept, in order of preference (aes256, chacha20)")
+                                            .takes_value(true))
+                                        .arg(Arg::with_name("cookie-threshold")
+                                            .long("cookie-threshold")
+                                            .default_value("64")
+                                            .help("set the handshakes per second above which UDP clients have to answer a cookie first, 0 always asks")
+                                            .takes_value(true))
+                                        .arg(Arg::with_name("threads")
+                                            .long("threads")
+                                            .default_value("1")
+                                            .help("set the threads serving UDP clients, each with a queue of the tun device")
+                                            .takes_value(true))
+                                        .arg(batch_arg())
+                                        .arg(Arg::with_name("keepalive")
+                                            .long("keepalive")
+                                            .default_value("10")
+                                            .help("set the interval in seconds between keepalives on a quiet tunnel")
+                                            .takes_value(true))
+                                        .arg(Arg::with_name("dead-peer-timeout")
+                                            .long("dead-peer-timeout")
+                                            .default_value("60")
+                                            .help("set the seconds without reply after which the peer is considered dead")
+                                            .takes_value(true))
                             )
                             .subcommand(SubCommand::with_name("client")
                                         .about("client mode")
                                         .version("0.1")
                                         .author("Attenuation <ouyangjun1999@gmail.com>")
+                                        .arg(config_arg())
                                         .arg(Arg::with_name("server")
                                             .short("s")
                                             .long("server")
-                                            .help("set the remote server address")
+                                            .help("set the remote server address or hostname")
                                             .takes_value(true))
                                         .arg(Arg::with_name("port")
                                             .short("p")
                                             .long("port")
                                             .help("set the remote port")
                                             .takes_value(true))
-                                        .arg(Arg::with_name("key")
-                                            .short("k")
-                                            .long("key")
-                                            .help("set the key for encryption communication")
+                                        .args(&key_args())
+                                        .arg(Arg::with_name("id")
+                                            .long("id")
+                                            .conflicts_with("private-key-file")
+                                            .help("set our client id in the server's peer database, the key is then the one the server has for it")
+                                            .takes_value(true))
+                                        .arg(private_key_arg())
+                                        .arg(Arg::with_name("server-key")
+                                            .long("server-key")
+                                            .help("set the server's public key, needed with --private-key-file")
                                             .takes_value(true))
                                         .arg(Arg::with_name("no-default-route")
                                             .short("n")
                                             .long("no-default-route")
                                             .help("do not set default route"))
-                            ).get_matches();
-    if let Some(matches) = matches.subcommand_matches("client"){ 
-        let ip_str = matches.value_of("server").ok_or_else(|| "can not find client host value").unwrap();
-        let port_str = matches.value_of("port").ok_or_else(|| "can not find client port value").unwrap();
-        let key_str = matches.value_of("key").ok_or_else(|| "can not find client key value").unwrap();
-        // let remote_addr = IpAddr::V4(Ipv4Addr::from_str(ip_str).map_err(|e| e.to_string())?);
-        let port = port_str.parse::<u16>().map_err(|e| e.to_string())?;
-        let default_route = match matches.is_present("no-default-route"){
-            false => true,
-            true => false
+                                        .arg(Arg::with_name("proto")
+                                            .long("proto")
+                                            .default_value("udp")
+                                            .possible_values(&["udp", "tcp"])
+                                            .help("set the transport protocol")
+                                            .takes_value(true))
+                                        .arg(mode_arg())
+                                        .args(&device_args())
+                                        .arg(Arg::with_name("cipher")
+                                            .short("c")
+                                            .long("cipher")
+                                            .default_value("aes256,chacha20")
+                                            .help("set the ciphers to offer the server (aes256, chacha20)")
+                                            .takes_value(true))
+                                        .arg(batch_arg())
+                                        .arg(Arg::with_name("keepalive")
+                                            .long("keepalive")
+                                            .default_value("10")
+                                            .help("set the interval in seconds between keepalives on a quiet tunnel")
+                                            .takes_value(true))
+                                        .arg(Arg::with_name("dead-peer-timeout")
+                                            .long("dead-peer-timeout")
+                                            .default_value("60")
+                                            .help("set the seconds without reply after which the peer is considered dead")
+                                            .takes_value(true))
+                            )
+                            .subcommand(SubCommand::with_name("genkey")
+                                        .about("print a new private key"))
+                            .subcommand(SubCommand::with_name("pubkey")
+                                        .about("read a private key from stdin and print its public key"))
+                            .get_matches();
+    if let Some(matches) = matches.subcommand_matches("client"){
+        let file = match matches.value_of("config") {
+            Some(path) => ClientConfig::load(Path::new(path))?,
+            None => ClientConfig::default()
+        };
+        let port = required(matches, "port", file.port.map(|port| port.to_string()))?;
+        let default_route = match matches.is_present("no-default-route") {
+            true => false,
+            false => file.tunnel.default_route.unwrap_or(true)
         };
         let mut client = Client::new();
-        client.parse_host(ip_str).unwrap();
-        client.parse_port(port);
-        client.parse_key(key_str);
+        client.parse_host(&required(matches, "server", file.server)?)?;
+        client.parse_port(number("port", &port)?);
+        let private_key = setting(matches, "private-key-file", file.private_key_file);
+        match key(matches, file.key, file.key_file)? {
+            Some(key) => client.parse_key(key),
+            None if private_key.is_none() => return Err(missing_key()),
+            None => {}
+        }
+        if let Some(ref path) = private_key {
+            let server_key = required(matches, "server-key", file.server_key)?;
+            client.parse_private_key(config::read_private_key(Path::new(path))?, &server_key)?;
+        }
+        if let Some(id) = setting(matches, "id", file.id) {
+            if private_key.is_some() {
+                return Err(Error::Config("id and private key can not be set both, the server knows us by our public key".to_string()))
+            }
+            client.parse_id(&id)?;
+        }
         client.parse_default_route(default_route);
+        client.parse_proto(&required(matches, "proto", file.proto)?)?;
+        let batch = required(matches, "batch", file.batch.map(|batch| batch.to_string()))?;
+        client.parse_batch(number("batch", &batch)?)?;
+        client.parse_mode(&required(matches, "mode", file.tunnel.mode)?)?;
+        if let Some(dev) = setting(matches, "dev", file.tunnel.dev) {
+            client.parse_dev(&dev)?;
+        }
+        if let Some(mtu) = setting(matches, "mtu", file.tunnel.mtu.map(|mtu| mtu.to_string())) {
+            client.parse_mtu(number("mtu", &mtu)?)?;
+        }
+        let owner = setting(matches, "owner", file.tunnel.owner);
+        let group = setting(matches, "group", file.tunnel.group);
+        match matches.is_present("persist") || file.tunnel.persist.unwrap_or(false) {
+            true => client.parse_persist(owner.as_ref().map(String::as_str), group.as_ref().map(String::as_str))?,
+            false if owner.is_some() || group.is_some() => return Err(Error::Config("owner and group need persist to be set".to_string())),
+            false => {}
+        }
+        client.parse_ciphers(&required(matches, "cipher", file.ciphers)?)?;
+        let keepalive = required(matches, "keepalive", file.keepalive.interval.map(|interval| interval.to_string()))?;
+        let dead_peer_timeout = required(matches, "dead-peer-timeout", file.keepalive.dead_peer_timeout.map(|timeout| timeout.to_string()))?;
+        client.parse_keepalive(number("keepalive", &keepalive)?, number("dead-peer-timeout", &dead_peer_timeout)?)?;
         Ok(Args::Client(client))
     } else if let Some(matches) = matches.subcommand_matches("server") {
-        let ip_str = matches.value_of("bind").ok_or_else(|| "can not find server host value").unwrap();
-        let port_str = matches.value_of("port").ok_or_else(|| "can not find server port value").unwrap();
-        let port = port_str.parse::<u16>().map_err(|e| e.to_string())?;
-        let key_str = matches.value_of("key").ok_or_else(|| "can not find server key value").unwrap();
-        let dns = matches.value_of("dns").ok_or_else(|| "can not find dns value")?;
-        let ip = matches.value_of("ip").ok_or_else(|| "can not find ip value")?;
-        let netmask = matches.value_of("netmask").ok_or_else(|| "can not find netmask value")?;
+        let file = match matches.value_of("config") {
+            Some(path) => ServerConfig::load(Path::new(path))?,
+            None => ServerConfig::default()
+        };
+        let port = required(matches, "port", file.port.map(|port| port.to_string()))?;
         let mut server = Server::new();
-        server.parse_host(ip_str).unwrap();
-        server.parse_port(port);
-        server.parse_dns(dns).unwrap();
-        server.parse_key(key_str);
-        server.parse_ip(ip).unwrap();
-        server.parse_netmask(netmask).unwrap();
-        // let bind_addr = IpAddr::V4(Ipv4Addr::from_str(ip_str).map_err(|e| e.to_string())?);
+        server.parse_host(&required(matches, "bind", file.listen)?)?;
+        server.parse_port(number("port", &port)?);
+        server.parse_dns(&required(matches, "dns", file.tunnel.dns)?)?;
+        let peers = setting(matches, "peers", file.peers);
+        match key(matches, file.key, file.key_file)? {
+            Some(key) => server.parse_key(key),
+            None if peers.is_none() => return Err(missing_key()),
+            None => {}
+        }
+        // The public keys of the clients are in the peer database.
+        match setting(matches, "private-key-file", file.private_key_file) {
+            Some(_) if peers.is_none() => return Err(Error::Config("private key needs peers to be set".to_string())),
+            Some(path) => server.parse_private_key(config::read_private_key(Path::new(&path))?),
+            None => {}
+        }
+        if let Some(peers) = peers {
+            server.parse_peers(&peers);
+        }
+        let ip = setting(matches, "ip", file.tunnel.ip);
+        let netmask = setting(matches, "netmask", file.tunnel.netmask);
+        match setting(matches, "pool", file.tunnel.pool) {
+            Some(_) if netmask.is_some() => return Err(Error::Config("netmask and pool can not be set both".to_string())),
+            Some(pool) => server.parse_pool(&pool, ip.as_ref().map(String::as_str))?,
+            None => {
+                server.parse_ip(ip.as_ref().map(String::as_str).unwrap_or("10.10.10.1"))?;
+                server.parse_netmask(netmask.as_ref().map(String::as_str).unwrap_or("255.255.255.0"))?;
+            }
+        }
+        let ip6 = setting(matches, "ip6", file.tunnel.ip6);
+        match setting(matches, "pool6", file.tunnel.pool6) {
+            Some(pool6) => server.parse_pool6(&pool6, ip6.as_ref().map(String::as_str))?,
+            None if ip6.is_some() => return Err(Error::Config("ip6 needs pool6 to be set".to_string())),
+            None => {}
+        }
+        server.parse_proto(&required(matches, "proto", file.proto)?)?;
+        let threads = required(matches, "threads", file.threads.map(|threads| threads.to_string()))?;
+        server.parse_threads(number("threads", &threads)?)?;
+        let batch = required(matches, "batch", file.batch.map(|batch| batch.to_string()))?;
+        server.parse_batch(number("batch", &batch)?)?;
+        server.parse_mode(&required(matches, "mode", file.tunnel.mode)?)?;
+        if let Some(dev) = setting(matches, "dev", file.tunnel.dev) {
+            server.parse_dev(&dev)?;
+        }
+        if let Some(mtu) = setting(matches, "mtu", file.tunnel.mtu.map(|mtu| mtu.to_string())) {
+            server.parse_mtu(number("mtu", &mtu)?)?;
+        }
+        let owner = setting(matches, "owner", file.tunnel.owner);
+        let group = setting(matches, "group", file.tunnel.group);
+        match matches.is_present("persist") || file.tunnel.persist.unwrap_or(false) {
+            true => server.parse_persist(owner.as_ref().map(String::as_str), group.as_ref().map(String::as_str))?,
+            false if owner.is_some() || group.is_some() => return Err(Error::Config("owner and group need persist to be set".to_string())),
+            false => {}
+        }
+        if let Some(bridge) = setting(matches, "bridge", file.tunnel.bridge) {
+            server.parse_bridge(&bridge)?;
+        }
+        server.parse_ciphers(&required(matches, "cipher", file.ciphers)?)?;
+        let cookie_threshold = required(matches, "cookie-threshold", file.cookie_threshold.map(|threshold| threshold.to_string()))?;
+        server.parse_cookie_threshold(number("cookie-threshold", &cookie_threshold)?);
+        let keepalive = required(matches, "keepalive", file.keepalive.interval.map(|interval| interval.to_string()))?;
+        let dead_peer_timeout = required(matches, "dead-peer-timeout", file.keepalive.dead_peer_timeout.map(|timeout| timeout.to_string()))?;
+        server.parse_keepalive(number("keepalive", &keepalive)?, number("dead-peer-timeout", &dead_peer_timeout)?)?;
         Ok(Args::Server(server))
+    } else if matches.subcommand_matches("genkey").is_some() {
+        Ok(Args::GenKey)
+    } else if matches.subcommand_matches("pubkey").is_some() {
+        Ok(Args::PubKey)
     } else {
-        unimplemented!()
+        Err(Error::Config("missing subcommand, see --help".to_string()))
     }
 }
\ No newline at end of file
@@ -1,37 +1,173 @@
-use bincode;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket, TcpStream};
 use std::io::{self,Write,Read};
 use dns_lookup;
 use log::*;
-use bincode::{serialize, deserialize};
 use std::os::unix::io::AsRawFd;
+use std::time::Duration;
 use mio;
+use signal_hook::iterator::Signals;
 
 
+use crate::batch;
 use crate::device;
 use crate::utils;
 use crate::boring;
-use crate::crypto::{Crypto,CryptoData,CryptoMethod};
-use crate::types::Error;
+use crate::crypto::{Crypto,CryptoMethod,KeyExchange,NoiseHandshake,PrivateKey,PublicKey,SharedKey,HANDSHAKE_METHOD};
+use crate::types::{Error,Proto};
 
-type Token = u64;
+/// How long to wait for the server to answer a handshake request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often a handshake request is sent before the attempt is given up.
+const HANDSHAKE_ATTEMPTS: u32 = 5;
+/// Wait before the first reconnect, doubled after every failed attempt.
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+/// The rekey request due before a packet sealed in place, if any, and the
+/// length of the sealed packet.
+type Sealed = (Option<Vec<u8>>,usize);
+
+/// What the client keeps across reconnects.
+struct Reconnect {
+    /// Routes through the tunnel, with the server address they keep
+    /// reachable outside of it. Declared first to be restored before the
+    /// device, whose removal takes the routes through it along.
+    routes: Option<(IpAddr,utils::DefaultGateWay,Option<utils::DefaultGateWay6>)>,
+    /// TUN device, left in place while the tunnel is down so applications
+    /// do not see the interface disappear.
+    tunnel: Option<device::Tuntap>,
+    /// Whether the last attempt got as far as bringing up the tunnel.
+    connected: bool,
+    delay: Duration,
+    /// The resolver configuration to restore once we are done.
+    resolv_conf: Option<utils::ResolvConf>
+}
+
+impl Reconnect {
+    fn new() -> Self {
+        Reconnect {
+            tunnel: None,
+            routes: None,
+            connected: false,
+            delay: RECONNECT_MIN,
+            resolv_conf: None
+        }
+    }
+
+    /// Drops the routes unless they were set up for `server`, which could
+    /// not be reached through the tunnel otherwise.
+    fn route_to(&mut self,server: IpAddr) {
+        if self.routes.as_ref().map(|&(host, _, _)| host) != Some(server) {
+            self.routes = None;
+        }
+    }
+
+    /// After a reconnect we ask for the address the tunnel already has.
+    fn requested(&self,ip: IpAddr) -> Option<IpAddr> {
+        self.tunnel.as_ref().map(|_| ip)
+    }
+
+    /// Returns how long to wait before the next attempt and backs off further.
+    fn backoff(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = std::cmp::min(delay * 2, RECONNECT_MAX);
+        delay
+    }
+}
+
+/// Our side of a handshake in progress: the pre-shared key and our
+/// ephemeral key, or the static key handshake.
+enum Handshake {
+    Psk(Box<Crypto>,KeyExchange),
+    Noise(Box<NoiseHandshake>)
+}
+
+impl Handshake {
+    fn open(&mut self,buf: &mut [u8]) -> Result<boring::Message,Error> {
+        match *self {
+            Handshake::Psk(ref psk, _) => boring::open_handshake(buf, psk),
+            Handshake::Noise(ref mut noise) => boring::open_noise(buf, noise)
+        }
+    }
+
+    /// The session keys once the server's response arrived, with the secret
+    /// later rekeys are salted with. `public_key` is the server's ephemeral
+    /// key from the response in the pre-shared key mode.
+    fn finish(self,cipher: CryptoMethod,public_key: &[u8]) -> Result<((Crypto,Crypto),Vec<u8>),Error> {
+        match self {
+            Handshake::Psk(psk, kx) => Ok((kx.derive(cipher, psk.get_key(), public_key, true)?, psk.get_key().to_vec())),
+            Handshake::Noise(noise) => noise.split(cipher)
+        }
+    }
+}
+
+/// Everything the server's response configures on the tunnel: address,
+/// netmask, gateway, dns, the IPv6 address, prefix length and gateway and
+/// the MTU.
+type Lease = (IpAddr,IpAddr,IpAddr,IpAddr,Option<(IpAddr,u8,IpAddr)>,u32);
 
 #[derive(Debug,Clone)]
 pub struct Client {
     ip: IpAddr,
     netmask: IpAddr,
-    token: Token,
+    gateway: IpAddr,
+    /// IPv6 address, prefix length and gateway, if the server leased us one.
+    ip6: Option<(IpAddr,u8,IpAddr)>,
+    session: boring::SessionId,
     dns: IpAddr,
-    secret: String,
+    secret: SharedKey,
+    /// Our id in the server's peer database, if we have a key of our own.
+    id: Option<String>,
+    /// Our static key and the server's public key, when authenticating by
+    /// public key instead of a pre-shared key.
+    private_key: Option<PrivateKey>,
+    server_key: Option<PublicKey>,
+    /// The server as given, a hostname or an address.
+    server: String,
+    /// The server address we are connected to.
     host: IpAddr,
     port: u16,
-    default_route: bool
+    default_route: bool,
+    proto: Proto,
+    /// Whether the tunnel carries IP packets or Ethernet frames.
+    mode: device::Type,
+    /// Name of the device, if not the default one.
+    dev: Option<String>,
+    /// Our MTU, which overrides the one the server pushed.
+    mtu: Option<u32>,
+    server_mtu: u32,
+    /// Whether the device outlives us, and who may attach to it then.
+    persist: bool,
+    owner: Option<u32>,
+    group: Option<u32>,
+    ciphers: Vec<CryptoMethod>,
+    keepalive: Duration,
+    dead_peer_timeout: Duration,
+    /// Datagrams moved per syscall over UDP.
+    batch: usize
 }
 
 
-fn resolve(host: &str) -> Result<IpAddr, String> {
-    let ip_list = dns_lookup::lookup_host(host).map_err(|_| "dns_lookup::lookup_host")?;
-    Ok(ip_list.first().unwrap().clone())
+/// `host` if it is an address rather than a hostname. IPv6 addresses may
+/// be in brackets like `[2001:db8::1]`.
+fn literal(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+/// Addresses of `host`, in the order the resolver prefers them.
+fn resolve(host: &str) -> Result<Vec<IpAddr>,Error> {
+    if let Some(ip) = literal(host) {
+        return Ok(vec![ip])
+    }
+    let ip_list = dns_lookup::lookup_host(host).map_err(|_| Error::Name(host.to_string()))?;
+    // getaddrinfo returns an address once for every socket type.
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    for ip in ip_list {
+        if !addresses.contains(&ip) {
+            addresses.push(ip);
+        }
+    }
+    Ok(addresses)
 }
 
 impl Client {
@@ -39,32 +175,61 @@ impl Client {
         Client {
             ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
             netmask: IpAddr::V4(Ipv4Addr::new(255, 255, 255, 0)),
-            token: 0,
+            gateway: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            ip6: None,
+            session: 0,
             dns: IpAddr::V4(Ipv4Addr::new(114, 114, 114, 114)),
-            secret: String::new(),
+            secret: SharedKey::Passphrase(String::new()),
+            id: None,
+            private_key: None,
+            server_key: None,
+            server: String::new(),
             host: IpAddr::V4(Ipv4Addr::new(114, 114, 114, 114)),
             port: 0 as u16,
-            default_route: false
+            default_route: false,
+            proto: Proto::Udp,
+            mode: device::Type::Tun,
+            dev: None,
+            mtu: None,
+            server_mtu: device::MTU,
+            persist: false,
+            owner: None,
+            group: None,
+            ciphers: vec![CryptoMethod::AES256, CryptoMethod::ChaCha20],
+            keepalive: boring::KEEPALIVE,
+            dead_peer_timeout: boring::DEAD_PEER_TIMEOUT,
+            batch: batch::BATCH
         }
     }
 
-    fn parse_ip(&mut self,ipaddr: &str) -> Result<(),Error>{
-        self.ip = ipaddr.parse().map_err(|e| Error::Parse("failed to parse ipaddr from string",e))?;
+    /// The server's hostname or address. A hostname is looked up again on
+    /// every reconnect.
+    pub fn parse_host(&mut self,host: &str) -> Result<(),Error>{
+        if host.is_empty() {
+            return Err(Error::Config("server address is empty".to_string()))
+        }
+        self.server = host.to_string();
         Ok(())
     }
 
-    fn parse_netmask(&mut self,netmask: &str) -> Result<(),Error>{
-        self.netmask = netmask.parse().map_err(|e| Error::Parse("failed to parse netmask from string",e))?;
-        Ok(())
+    pub fn parse_key(&mut self,key: SharedKey) {
+        self.secret = key
     }
 
-    pub fn parse_host(&mut self,host: &str) -> Result<(),Error>{
-        self.host = host.parse().map_err(|e| Error::Parse("failed to parse host from string",e))?;
+    pub fn parse_id(&mut self,id: &str) -> Result<(),Error> {
+        if id.is_empty() || id.len() > boring::MAX_ID_LEN {
+            return Err(Error::Config(format!("client id must be 1 to {} bytes long", boring::MAX_ID_LEN)))
+        }
+        self.id = Some(id.to_string());
         Ok(())
     }
 
-    pub fn parse_key(&mut self,key: &str) {
-        self.secret = key.to_string()
+    /// Authenticates with our static key to the server holding `server_key`.
+    pub fn parse_private_key(&mut self,key: PrivateKey,server_key: &str) -> Result<(),Error> {
+        self.server_key = Some(PublicKey::parse(server_key)?);
+        info!("Public key: {}", key.public_key());
+        self.private_key = Some(key);
+        Ok(())
     }
 
     pub fn parse_default_route(&mut self,default: bool) {
@@ -75,164 +240,564 @@ impl Client {
         self.port = port;
     }
 
-    fn set_token(&mut self,token: Token) {
-        self.token = token
+    pub fn parse_proto(&mut self,proto: &str) -> Result<(),Error> {
+        self.proto = proto.parse()?;
+        Ok(())
+    }
+
+    pub fn parse_mode(&mut self,mode: &str) -> Result<(),Error> {
+        self.mode = mode.parse()?;
+        Ok(())
+    }
+
+    pub fn parse_dev(&mut self,dev: &str) -> Result<(),Error> {
+        device::check_name(dev)?;
+        self.dev = Some(dev.to_string());
+        Ok(())
+    }
+
+    pub fn parse_mtu(&mut self,mtu: u32) -> Result<(),Error> {
+        device::check_mtu(mtu)?;
+        self.mtu = Some(mtu);
+        Ok(())
+    }
+
+    fn mtu(&self) -> u32 {
+        self.mtu.unwrap_or(self.server_mtu)
     }
 
-    fn parse_dns(&mut self,dns: &str) -> Result<(),Error>{
-        self.dns = dns.parse().map_err(|e| Error::Parse("failed to parse dns from string",e))?;
+    /// Keeps the device when we exit, for `owner` and `group` to attach to.
+    pub fn parse_persist(&mut self,owner: Option<&str>,group: Option<&str>) -> Result<(),Error> {
+        if self.dev.is_none() {
+            return Err(Error::Config("persist needs dev to be set, to find the device again".to_string()))
+        }
+        self.owner = owner.map(utils::user_id).transpose()?;
+        self.group = group.map(utils::group_id).transpose()?;
+        self.persist = true;
+        Ok(())
+    }
+
+    pub fn parse_ciphers(&mut self,ciphers: &str) -> Result<(),Error> {
+        self.ciphers = CryptoMethod::parse_list(ciphers)?;
+        Ok(())
+    }
+
+    pub fn parse_keepalive(&mut self,keepalive: u64,dead_peer_timeout: u64) -> Result<(),Error> {
+        if keepalive == 0 || dead_peer_timeout <= keepalive {
+            return Err(Error::Config(format!("dead peer timeout of {}s must be longer than the keepalive interval of {}s", dead_peer_timeout, keepalive)))
+        }
+        self.keepalive = Duration::from_secs(keepalive);
+        self.dead_peer_timeout = Duration::from_secs(dead_peer_timeout);
+        Ok(())
+    }
+
+    pub fn parse_batch(&mut self,batch: usize) -> Result<(),Error> {
+        batch::check_batch(batch)?;
+        self.batch = batch;
         Ok(())
     }
 
-    pub fn create_tun(&mut self) -> Result<device::Tuntap,io::Error>{
-        let tun = device::Tuntap::create("tun1", device::Type::Tun, None).expect("failed to create tun");
-        // self.parse_ip(ipaddr).unwrap();
-        // self.parse_dns(dns).unwrap();
-        // self.parse_netmask(netmask).unwrap();
-        // self.parse_dns(dns).expect("parse dns failed");
-        tun.set_ip(&self.ip.to_string(),&self.netmask.to_string()).expect("failed to set ip to tun device");
-        utils::set_dns(&self.dns.to_string()).expect("set dns failed");
+    fn set_session(&mut self,session: boring::SessionId) {
+        self.session = session
+    }
+
+    pub fn create_tun(&mut self) -> Result<device::Tuntap,Error>{
+        let name = self.dev.as_ref().map(String::as_str).unwrap_or_else(|| self.mode.ifname());
+        let tun = device::Tuntap::create(name, self.mode, None).map_err(|e| Error::TunTapDev("failed to create tun device",e))?;
+        tun.set_ip(self.ip,self.netmask).map_err(|e| Error::TunTapDev("failed to set ip to tun device",e))?;
+        utils::set_dns(self.dns).map_err(|e| Error::File("failed to set dns", e))?;
+        Ok(tun)
+    }
+
+    fn setup_tunnel(&mut self) -> Result<device::Tuntap,Error> {
+        info!("start create tun device");
+        let tun = self.create_tun()?;
+        info!("tun device create successful,set ip: {} netmask: {}",self.ip.to_string(),self.netmask.to_string());
+        tun.set_persist(self.persist).map_err(|e| Error::TunTapDev("failed to set persistence of tun device",e))?;
+        if let Some(uid) = self.owner {
+            tun.set_owner(uid).map_err(|e| Error::TunTapDev("failed to set owner of tun device",e))?;
+        }
+        if let Some(gid) = self.group {
+            tun.set_group(gid).map_err(|e| Error::TunTapDev("failed to set group of tun device",e))?;
+        }
+        tun.set_mtu(self.mtu()).map_err(|e| Error::TunTapDev("failed to set mtu of tun device",e))?;
+        info!("set mtu: {}",self.mtu());
+        tun.up().map_err(|e| Error::TunTapDev("failed to bring up tun device",e))?;
+        if let Some((ip6, prefix, _)) = self.ip6 {
+            tun.set_ip6(ip6, prefix).map_err(|e| Error::TunTapDev("failed to set ipv6 address to tun device",e))?;
+            info!("set ipv6: {}/{}",ip6,prefix);
+        }
         Ok(tun)
     }
 
-    pub fn shakehand_udp(&mut self,socket: &UdpSocket, addr: &SocketAddr) -> Result<(Crypto,Crypto), Error> {
-        let request_msg = boring::Message::Request {msg: "hello".to_string() };
-        let mut sender =  Crypto::from_shared_key(CryptoMethod::AES256, &self.secret);
-        let receiver = Crypto::from_shared_key(CryptoMethod::AES256, &self.secret);
-        let mut nonce = [0u8; 12];
-        let encoded_req_msg: Vec<u8> = serialize(&request_msg).unwrap();
-        let mut encrypted_req_msg = encoded_req_msg.clone();
-        encrypted_req_msg.resize(encrypted_req_msg.len() + sender.additional_bytes(), 0);
-        let add = [0u8; 8];
-        let mut size = sender.encrypt(&mut encrypted_req_msg, encoded_req_msg.len(), &mut nonce, &add);
+    fn route(&self) -> Result<utils::DefaultGateWay,Error> {
+        utils::DefaultGateWay::create(self.gateway, self.host, self.default_route)
+    }
 
-        while size > 0 {
-            let sent_bytes = socket.send_to(&encrypted_req_msg, addr).map_err(|e| Error::Shakehand("failed send handshake",e))?;
-            size -= sent_bytes;
+    /// The IPv6 default route through the tunnel, if we route everything
+    /// through it and the server leased us an IPv6 address.
+    fn route6(&self) -> Result<Option<utils::DefaultGateWay6>,Error> {
+        match self.ip6 {
+            Some((_, _, gateway6)) if self.default_route => {
+                let gw6 = utils::DefaultGateWay6::create(gateway6)?;
+                Ok(Some(gw6))
+            },
+            _ => Ok(None)
         }
-        info!("Request sent to {}.", addr);
-        let mut buf = [0u8; 1600];
-        let (len, recv_addr) = socket.recv_from(&mut buf).map_err(|e| Error::Shakehand("failed recv_from shakehand",e))?;
-        assert_eq!(&recv_addr, addr);
-        info!("Response received from {}.", addr);
-
-        let decrypted_buf_len = receiver.decrypt(&mut buf[..len], &nonce, &add).map_err(|e| e)?;
-        let resp_msg: boring::Message = deserialize(&buf[..decrypted_buf_len]).unwrap();
-        match resp_msg {
-            boring::Message::Response { ip, netmask,token, dns } => {
+    }
+
+    fn lease(&self) -> Lease {
+        (self.ip, self.netmask, self.gateway, self.dns, self.ip6, self.mtu())
+    }
+
+    /// Brings up the tunnel after the first handshake and points the routes
+    /// at it. After a reconnect the existing device is kept and only updated
+    /// if the server leased a different configuration.
+    fn attach_tunnel<'a>(&mut self,state: &'a mut Reconnect,previous: Lease) -> Result<&'a mut device::Tuntap,Error> {
+        state.delay = RECONNECT_MIN;
+        state.connected = true;
+        let (_, _, gateway, _, ip6, mtu) = previous;
+        match state.tunnel.take() {
+            None => {
+                if state.resolv_conf.is_none() {
+                    state.resolv_conf = utils::ResolvConf::save();
+                }
+                state.tunnel = Some(self.setup_tunnel()?)
+            },
+            Some(tun) => {
+                if previous != self.lease() {
+                    info!("Server leased a new configuration, set ip: {} netmask: {}",self.ip,self.netmask);
+                    tun.set_ip(self.ip,self.netmask).map_err(|e| Error::TunTapDev("failed to set ip to tun device",e))?;
+                    utils::set_dns(self.dns).map_err(|e| Error::File("failed to set dns", e))?;
+                }
+                if mtu != self.mtu() {
+                    info!("set mtu: {}",self.mtu());
+                    tun.set_mtu(self.mtu()).map_err(|e| Error::TunTapDev("failed to set mtu of tun device",e))?;
+                }
+                // Unlike the IPv4 one, a new IPv6 address is added next to the old one.
+                if ip6 != self.ip6 {
+                    if let Some((old_ip6, prefix, _)) = ip6 {
+                        tun.del_ip6(old_ip6, prefix).map_err(|e| Error::TunTapDev("failed to remove ipv6 address from tun device",e))?;
+                    }
+                    if let Some((ip6, prefix, _)) = self.ip6 {
+                        info!("set ipv6: {}/{}",ip6,prefix);
+                        tun.set_ip6(ip6, prefix).map_err(|e| Error::TunTapDev("failed to set ipv6 address to tun device",e))?;
+                    }
+                }
+                state.tunnel = Some(tun);
+            }
+        }
+        // The old routes have to be restored before pointing them at a new gateway.
+        if gateway != self.gateway || ip6.map(|(_, _, gateway6)| gateway6) != self.ip6.map(|(_, _, gateway6)| gateway6) {
+            state.routes = None;
+        }
+        if state.routes.is_none() {
+            state.routes = Some((self.host, self.route()?, self.route6()?));
+        }
+        Ok(state.tunnel.as_mut().unwrap())
+    }
+
+    /// The addresses to try for the server. Looking up a hostname needs the
+    /// original routes back, the DNS server behind the tunnel is out of reach
+    /// while it is down.
+    fn addresses(&self,state: &mut Reconnect) -> Vec<IpAddr> {
+        if let Some(ip) = literal(&self.server) {
+            return vec![ip]
+        }
+        state.routes = None;
+        match resolve(&self.server) {
+            Ok(addresses) => {
+                info!("{} resolved to {:?}.", self.server, addresses);
+                addresses
+            },
+            Err(e) => {
+                warn!("Lookup failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Starts a handshake asking for the addresses we had, returning our side
+    /// of it with the sealed request.
+    fn handshake(&self,requested: Option<IpAddr>,requested6: Option<IpAddr>) -> Result<(Handshake,Vec<u8>),Error> {
+        let mut request_msg = boring::Message::Request {
+            msg: "hello".to_string(),
+            public_key: Vec::new(),
+            ciphers: self.ciphers.clone(),
+            ip: requested,
+            ip6: requested6,
+            id: self.id.clone(),
+            mode: self.mode,
+            time: utils::unix_time()
+        };
+        if let (Some(private_key), Some(server_key)) = (self.private_key.as_ref(), self.server_key.as_ref()) {
+            let mut noise = NoiseHandshake::initiator(private_key, server_key);
+            let encrypted_req_msg = boring::seal_noise(&request_msg, &mut noise)?;
+            return Ok((Handshake::Noise(Box::new(noise)), encrypted_req_msg))
+        }
+        let kx = KeyExchange::new()?;
+        if let boring::Message::Request { ref mut public_key, .. } = request_msg {
+            *public_key = kx.public_key().to_vec();
+        }
+        let mut psk = Crypto::from_psk(HANDSHAKE_METHOD, &self.secret);
+        let encrypted_req_msg = boring::seal_handshake(&request_msg, self.id.as_ref().map(String::as_str), &mut psk);
+        Ok((Handshake::Psk(Box::new(psk), kx), encrypted_req_msg))
+    }
+
+    /// Applies the server's handshake response and derives the session keys
+    /// from the handshake it completes.
+    fn handle_response(&mut self,msg: boring::Message,handshake: Handshake) -> Result<boring::SessionKeys,Error> {
+        match msg {
+            boring::Message::Response { ip, netmask, gateway, ip6, session, dns, public_key, cipher, mtu } => {
+                if !self.ciphers.contains(&cipher) {
+                    return Err(Error::Invaildmessage("server picked a cipher we did not offer"))
+                }
+                if device::check_mtu(mtu).is_err() {
+                    return Err(Error::Invaildmessage("server pushed an mtu we can not handle"))
+                }
+                info!("Server picked cipher {:?}.", cipher);
                 self.ip = ip;
                 self.netmask = netmask;
-                self.set_token(token);
+                self.gateway = gateway;
+                self.ip6 = ip6;
+                self.set_session(session);
                 self.dns = dns;
-                Ok((sender,receiver))
+                self.server_mtu = mtu;
+                let (keys, secret) = handshake.finish(cipher, &public_key)?;
+                let mut keys = boring::SessionKeys::new(cipher, true, &secret, session, keys);
+                keys.set_keepalive(self.keepalive, self.dead_peer_timeout);
+                Ok(keys)
             },
+            boring::Message::Reject { reason, retry: false } => Err(Error::Rejected(reason)),
+            boring::Message::Reject { reason, retry: true } => Err(Error::Unavailable(reason)),
             _ => Err(
                 Error::Invaildmessage("error shakehand message")
             ),
-        }   
+        }
+    }
+
+    /// Opens a packet received from the server in place and writes the IP packet
+    /// or Ethernet frame it carries to the device. Returns the reply to send back, if the
+    /// packet needs one.
+    fn handle_packet(&self,tun: &mut device::Tuntap,keys: &mut boring::SessionKeys,packet: &mut [u8],address: &SocketAddr) -> Result<Option<Vec<u8>>,Error> {
+        let (header, len) = match keys.open(packet) {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!("Invalid packet from {}: {}", address, e);
+                return Ok(None)
+            }
+        };
+        let payload = &packet[boring::HEADER_LEN..boring::HEADER_LEN + len];
+        if header.kind == boring::PacketType::Data {
+            if let Err(e) = tun.write(payload) {
+                warn!("Failed to write to {}: {}", tun.ifname(), e);
+            }
+            return Ok(None)
+        }
+        match keys.handle_control(boring::decode(payload)?) {
+            Ok(reply) => Ok(reply),
+            Err(e @ Error::Rejected(_)) | Err(e @ Error::Disconnected) => Err(e),
+            Err(e) => {
+                warn!("Invalid message from {}: {}", address, e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads a packet from the TUN device into `buf` and seals it in place,
+    /// returning the length of the sealed packet. A rekey request is returned
+    /// along with it when the session key is due for rotation. None once a
+    /// non-blocking device has no more packets.
+    fn read_tun(&self,tun: &mut device::Tuntap,keys: &mut boring::SessionKeys,buf: &mut [u8]) -> Result<Option<Sealed>,Error> {
+        let end = buf.len() - boring::TAG_LEN;
+        let len: usize = match tun.read(&mut buf[boring::HEADER_LEN..end]) {
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(Error::TunTapDev("failed to read from tun device",e))
+        };
+        let rekey = keys.start_rekey()?;
+        Ok(Some((rekey, keys.seal_data(buf, len))))
     }
 
-    pub fn shakehand_tcp() {
-        unimplemented!()
+    /// Connects to the server and runs the tunnel, trying the addresses of
+    /// the server in turn. Whenever the connection is lost or no address
+    /// answers, it looks the server up again and reconnects with exponential
+    /// backoff; only a server rejecting us is fatal. SIGINT and SIGTERM tell
+    /// the server we are leaving and restore the routes and resolver
+    /// configuration the tunnel changed.
+    pub fn run(&mut self) -> Result<(),Error> {
+        let signals = utils::shutdown_signals().map_err(|e| Error::Signal("failed to catch signals",e))?;
+        let mut state = Reconnect::new();
+        loop {
+            for address in self.addresses(&mut state) {
+                self.host = address;
+                state.route_to(address);
+                state.connected = false;
+                let result = match self.proto {
+                    Proto::Udp => self.connect_udp(&mut state, &signals),
+                    Proto::Tcp => self.connect_tcp(&mut state, &signals)
+                };
+                match result {
+                    Ok(()) | Err(Error::Rejected(_)) => return result,
+                    Err(Error::Interrupted) => {
+                        info!("Shutting down.");
+                        return Ok(())
+                    },
+                    Err(Error::DeadPeer) => warn!("No reply from server for {:?}.", self.dead_peer_timeout),
+                    Err(Error::Disconnected) => warn!("Server {} closed the session.", address),
+                    Err(e) => warn!("Connection to server {} failed: {}", address, e)
+                }
+                // A tunnel that was up and went down starts over with a fresh lookup.
+                if state.connected {
+                    break
+                }
+            }
+            let delay = state.backoff();
+            info!("Reconnecting in {:?}.", delay);
+            if utils::sleep_or_signal(&signals, delay).map_err(|e| Error::Signal("failed to wait for signals",e))? {
+                info!("Shutting down.");
+                return Ok(())
+            }
+        }
     }
 
-    pub fn connect_udp(&mut self) -> Result<(),Error> {
+    pub fn shakehand_udp(&mut self,socket: &UdpSocket, addr: &SocketAddr,requested: Option<IpAddr>,requested6: Option<IpAddr>,signals: &Signals) -> Result<boring::SessionKeys, Error> {
+        let (mut handshake, encrypted_req_msg) = self.handshake(requested, requested6)?;
+        // Set once a server under load asked us to prove our address.
+        let mut cookie = None;
+        socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).map_err(|e| Error::Socket("failed to set read timeout",e))?;
+
+        let mut buf = [0u8; 1600];
+        for _ in 0..HANDSHAKE_ATTEMPTS {
+            if utils::interrupted(signals) {
+                return Err(Error::Interrupted)
+            }
+            let hello = boring::append_cookie_mac(&encrypted_req_msg, cookie.as_ref());
+            socket.send_to(&hello, addr).map_err(|e| Error::Shakehand("failed send handshake",e))?;
+            info!("Request sent to {}.", addr);
+            loop {
+                let (len, recv_addr) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                    Err(e) => return Err(Error::Shakehand("failed recv_from shakehand",e))
+                };
+                if &recv_addr != addr {
+                    continue
+                }
+                // Sending the request again right away uses up an attempt.
+                if let Ok(received) = boring::open_cookie(&buf[..len]) {
+                    info!("Server {} is under load, answering its cookie.", addr);
+                    cookie = Some(received);
+                    break
+                }
+                match handshake.open(&mut buf[..len]) {
+                    Ok(resp_msg) => {
+                        info!("Response received from {}.", addr);
+                        return self.handle_response(resp_msg, handshake)
+                    },
+                    Err(e) => warn!("Invalid packet from {}: {}", addr, e)
+                }
+            }
+        }
+        Err(Error::Shakehand("no response from server", io::Error::from(io::ErrorKind::TimedOut)))
+    }
+
+    pub fn shakehand_tcp(&mut self,stream: &mut TcpStream,requested: Option<IpAddr>,requested6: Option<IpAddr>) -> Result<boring::SessionKeys, Error> {
+        let (mut handshake, encrypted_req_msg) = self.handshake(requested, requested6)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT * HANDSHAKE_ATTEMPTS)).map_err(|e| Error::Socket("failed to set read timeout",e))?;
+        boring::write_frame(stream, &boring::append_cookie_mac(&encrypted_req_msg, None)).map_err(|e| Error::Shakehand("failed send handshake",e))?;
+        info!("Request sent to {}.", self.host);
+
+        let mut frame = boring::read_frame(stream).map_err(|e| Error::Shakehand("failed read shakehand",e))?;
+        info!("Response received from {}.", self.host);
+        let resp_msg = handshake.open(&mut frame)?;
+        self.handle_response(resp_msg, handshake)
+    }
+
+    fn connect_udp(&mut self,state: &mut Reconnect,signals: &Signals) -> Result<(),Error> {
         info!("start connect server");
         let remote_ip = self.host;
         let remote_addr = SocketAddr::new(remote_ip, self.port);
-        info!("remote addr and port is {}:{}",remote_ip,self.port);
+        info!("remote addr and port is {}",remote_addr);
 
-        let local_addr: SocketAddr = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
-        let socket = UdpSocket::bind(&local_addr).unwrap();
-        let (mut sender,receiver) = self.shakehand_udp(&socket, &remote_addr).unwrap();
-        info!("shakehand sucess token: {}, ip address: {}",self.token,self.ip.to_string());
-        info!("start create tun device");
-        let mut tun = self.create_tun().unwrap();
-        info!("tun device create successful,set ip: {} netmask: {}",self.ip.to_string(),self.netmask.to_string());
-        tun.up().unwrap();
+        let local_ip = match remote_ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let local_addr = SocketAddr::new(local_ip, 0);
+        let socket = UdpSocket::bind(&local_addr).map_err(|e| Error::Socket("failed to bind socket",e))?;
+        let previous = self.lease();
+        let requested6 = self.ip6.and_then(|(ip6, _, _)| state.requested(ip6));
+        let mut keys = self.shakehand_udp(&socket, &remote_addr, state.requested(self.ip), requested6, signals)?;
+        info!("shakehand sucess session: {}, ip address: {}",self.session,self.ip.to_string());
+        let tun = self.attach_tunnel(state, previous)?;
+        // Drained in bursts of up to a batch once readable.
+        tun.set_nonblocking(true).map_err(|e| Error::TunTapDev("failed to set tun device non-blocking",e))?;
         let tun_rawfd = tun.as_raw_fd();
 
-        let mut buf = [0u8; 1600];
-        let mut nonce = [0u8; 12];
-        let add = [0u8; 8];
-
         let tunfd = mio::unix::EventedFd(&tun_rawfd);
         let sockfd = mio::net::UdpSocket::from_socket(socket).unwrap();
+        let mut receiver = batch::Receiver::new(&sockfd, self.batch);
+        let mut sender = batch::Sender::new(&sockfd, self.batch);
 
+        info!("start polling...");
+        const TUN_TOKEN: mio::Token = mio::Token(0);
+        const SOCK_TOKEN: mio::Token = mio::Token(1);
+        const SIGNAL_TOKEN: mio::Token = mio::Token(2);
+        let poll = mio::Poll::new().unwrap();
+        poll.register(&tunfd, TUN_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).expect("unable register TUN fd");
+        poll.register(&sockfd, SOCK_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).expect("unable register SOCK fd");
+        poll.register(signals, SIGNAL_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).map_err(|e| Error::Signal("failed to wait for signals",e))?;
 
-        let mut ipaddr_oct = [0u8;4];
-        match self.ip {
-            IpAddr::V4(ipv4) => {
-                ipaddr_oct = ipv4.octets();
-            },
-            IpAddr::V6(ipv6) => {},
+        let mut events = mio::Events::with_capacity(1024);
+        info!("ready transmission");
+        // While roaming between networks sends fail for a while, the packets
+        // are lost as the network would lose them. The dead-peer timeout
+        // decides when to give up on the server.
+        let send_to = |packet: &[u8]| {
+            if let Err(e) = sockfd.send_to(packet, &remote_addr) {
+                warn!("Failed to send to {}: {}", remote_addr, e);
+            }
         };
-        ipaddr_oct[3] = 1;
-        let client_ip = Ipv4Addr::new(ipaddr_oct[0], ipaddr_oct[1], ipaddr_oct[2], ipaddr_oct[3]);
-        let _gw  = utils::DefaultGateWay::create(&client_ip.to_string(), &remote_ip.to_string(),self.default_route).map_err(|e| Error::Route("failed to create route or exist"))?;
+
+        loop {
+            poll.poll(&mut events, Some(keys.next_check())).expect("poll failed");
+            for event in events.iter() {
+                match event.token() {
+                    SOCK_TOKEN => {
+                        match receiver.recv(&sockfd) {
+                            Ok(_) => {},
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                            Err(e) => return Err(Error::Socket("failed to receive from server",e))
+                        }
+                        for (packet, address) in receiver.datagrams() {
+                            if let Some(reply) = self.handle_packet(tun, &mut keys, packet, &address)? {
+                                send_to(&reply);
+                            }
+                        }
+                    },
+                    TUN_TOKEN => {
+                        for _ in 0..self.batch {
+                            let (rekey, size) = match self.read_tun(tun, &mut keys, sender.next())? {
+                                Some(sealed) => sealed,
+                                None => break
+                            };
+                            // Sent right away, ahead of the batch it was due in.
+                            if let Some(rekey) = rekey {
+                                send_to(&rekey);
+                            }
+                            sender.push(size, remote_addr);
+                        }
+                        if let Err(e) = sender.flush(&sockfd) {
+                            warn!("Failed to send to {}: {}", remote_addr, e);
+                        }
+                    },
+                    SIGNAL_TOKEN if utils::interrupted(signals) => {
+                        send_to(&keys.seal(&boring::Message::Disconnect));
+                        return Err(Error::Interrupted)
+                    },
+                    SIGNAL_TOKEN => {},
+                    _ => unreachable!()
+                }
+            }
+            if keys.is_dead() {
+                return Err(Error::DeadPeer)
+            }
+            if let Some(ping) = keys.keepalive() {
+                send_to(&ping);
+            }
+        }
+    }
+
+    fn connect_tcp(&mut self,state: &mut Reconnect,signals: &Signals) -> Result<(),Error> {
+        info!("start connect server");
+        let remote_addr = SocketAddr::new(self.host, self.port);
+        info!("remote addr and port is {}",remote_addr);
+
+        let mut stream = TcpStream::connect_timeout(&remote_addr, HANDSHAKE_TIMEOUT).map_err(|e| Error::Socket("failed to connect server",e))?;
+        stream.set_nodelay(true).map_err(|e| Error::Socket("failed to set TCP_NODELAY",e))?;
+        let previous = self.lease();
+        let requested6 = self.ip6.and_then(|(ip6, _, _)| state.requested(ip6));
+        let mut keys = self.shakehand_tcp(&mut stream, state.requested(self.ip), requested6)?;
+        if utils::interrupted(signals) {
+            return Err(Error::Interrupted)
+        }
+        info!("shakehand sucess session: {}, ip address: {}",self.session,self.ip.to_string());
+        let tun = self.attach_tunnel(state, previous)?;
+        let tun_rawfd = tun.as_raw_fd();
+
+        let mut buf = [0u8; 1600];
+
+        let tunfd = mio::unix::EventedFd(&tun_rawfd);
+        let mut conn = boring::Framed::new(mio::net::TcpStream::from_stream(stream).unwrap());
 
         info!("start polling...");
         const TUN_TOKEN: mio::Token = mio::Token(0);
         const SOCK_TOKEN: mio::Token = mio::Token(1);
+        const SIGNAL_TOKEN: mio::Token = mio::Token(2);
         let poll = mio::Poll::new().unwrap();
         poll.register(&tunfd, TUN_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).expect("unable register TUN fd");
-        poll.register(&sockfd, SOCK_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).expect("unable register SOCK fd");
+        poll.register(conn.stream(), SOCK_TOKEN, conn.interest(), mio::PollOpt::level()).expect("unable register SOCK fd");
+        poll.register(signals, SIGNAL_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).map_err(|e| Error::Signal("failed to wait for signals",e))?;
 
         let mut events = mio::Events::with_capacity(1024);
         info!("ready transmission");
 
         loop {
-            poll.poll(&mut events, None).expect("poll failed");
+            poll.poll(&mut events, Some(keys.next_check())).expect("poll failed");
             for event in events.iter() {
                 match event.token() {
                     SOCK_TOKEN => {
-                        let (len,address) = sockfd.recv_from(&mut buf).unwrap();
-                        let decrypted_buf_len = receiver.decrypt(&mut buf[..len], &nonce, &add).unwrap();
-                        let msg: boring::Message = deserialize(&buf[..decrypted_buf_len]).unwrap();
-                        match msg {
-                            boring::Message::Request{msg: _} | boring::Message::Response{ip: _,netmask: _,token: _,dns: _} => {
-                                warn!("Invalid message {:?} from {}", msg, address);
-                            },
-                            boring::Message::Data {ip: _,token: recv_token, data} => {
-                                if self.token == recv_token {
-                                    let data_len = data.len();
-                                    let mut sent_len = 0;
-                                    while sent_len < data_len {
-                                        sent_len += tun.write(&data[sent_len..data_len]).unwrap();
-                                    }
-                                } else {
-                                    warn!("Token mismatched. Received: {}. Expected: {}",recv_token,self.token);
+                        if event.readiness().is_writable() {
+                            conn.flush().map_err(|e| Error::Socket("failed to write to server",e))?;
+                        }
+                        if event.readiness().is_readable() {
+                            let frames = conn.read_frames().map_err(|e| Error::Socket("connection to server lost",e))?;
+                            for mut frame in frames {
+                                if let Some(reply) = self.handle_packet(tun, &mut keys, &mut frame, &remote_addr)? {
+                                    conn.send(&reply).map_err(|e| Error::Socket("failed to write to server",e))?;
                                 }
                             }
                         }
                     },
-                    TUN_TOKEN => {
-                        let len: usize = tun.read(&mut buf).unwrap();
-                        let data = &buf[..len];
-                        let msg = boring::Message::Data {
-                            ip: self.ip,
-                            token: self.token,
-                            data: data.to_vec()
-                        };
-                        let encoded_data = serialize(&msg).unwrap();
-                        let mut encrypted_msg = encoded_data.clone();
-                        encrypted_msg.resize(encrypted_msg.len() + sender.additional_bytes(), 0);
-                        let add = [0u8; 8];
-                        let data_len = sender.encrypt(&mut encrypted_msg, encoded_data.len(), &mut nonce, &add);
-                        let mut sent_len = 0;
-                        while sent_len < data_len {
-                            sent_len += sockfd.send_to(&encrypted_msg[sent_len..data_len], &remote_addr).unwrap();
+                    TUN_TOKEN => if let Some((rekey, size)) = self.read_tun(tun, &mut keys, &mut buf)? {
+                        if let Some(rekey) = rekey {
+                            conn.send(&rekey).map_err(|e| Error::Socket("failed to write to server",e))?;
                         }
-
+                        conn.send(&buf[..size]).map_err(|e| Error::Socket("failed to write to server",e))?;
+                    },
+                    SIGNAL_TOKEN if utils::interrupted(signals) => {
+                        conn.send(&keys.seal(&boring::Message::Disconnect)).map_err(|e| Error::Socket("failed to write to server",e))?;
+                        return Err(Error::Interrupted)
                     },
+                    SIGNAL_TOKEN => {},
                     _ => unreachable!()
                 }
             }
+            if keys.is_dead() {
+                return Err(Error::DeadPeer)
+            }
+            if let Some(ping) = keys.keepalive() {
+                conn.send(&ping).map_err(|e| Error::Socket("failed to write to server",e))?;
+            }
+            poll.reregister(conn.stream(), SOCK_TOKEN, conn.interest(), mio::PollOpt::level()).expect("unable reregister SOCK fd");
         }
-        Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::*;
+    use std::collections::HashSet;
 
-    pub fn connect_tcp() {
-        unimplemented!()
+    #[test]
+    fn resolve_test() {
+        assert_eq!(resolve("10.0.0.1").unwrap(), vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(resolve("[2001:db8::1]").unwrap(), vec!["2001:db8::1".parse::<IpAddr>().unwrap()]);
+        let addresses = resolve("localhost").unwrap();
+        assert!(addresses.contains(&"127.0.0.1".parse().unwrap()));
+        let unique: HashSet<&IpAddr> = addresses.iter().collect();
+        assert_eq!(unique.len(), addresses.len());
+        assert!(resolve("nonexistent.invalid").is_err());
     }
 }
@@ -0,0 +1,372 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use toml;
+
+use crate::crypto::{PrivateKey,PublicKey,SharedKey,PSK_LEN};
+use crate::types::Error;
+
+/// Settings of `boringvpn server --config`. Every one of them can also be
+/// given as a flag, which takes precedence over the file:
+///
+/// ```toml
+/// listen = "[::]"
+/// port = 9527
+/// key_file = "/etc/boringvpn/key"
+/// private_key_file = "/etc/boringvpn/private.key"
+/// peers = "/etc/boringvpn/peers.toml"
+/// proto = "udp"
+/// ciphers = "aes256,chacha20"
+/// cookie_threshold = 64
+/// threads = 4
+/// batch = 64
+///
+/// [tunnel]
+/// pool = "10.8.0.0/16"
+/// pool6 = "fd00:8::/64"
+/// dns = "8.8.8.8"
+/// mode = "tap"
+/// bridge = "br0"
+/// dev = "tap-vpn"
+/// mtu = 1406
+/// persist = true
+/// owner = "nobody"
+///
+/// [keepalive]
+/// interval = 10
+/// dead_peer_timeout = 60
+/// ```
+#[derive(Debug,Default,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Option<String>,
+    pub port: Option<u16>,
+    pub key: Option<String>,
+    pub key_file: Option<String>,
+    pub private_key_file: Option<String>,
+    pub peers: Option<String>,
+    pub proto: Option<String>,
+    pub ciphers: Option<String>,
+    pub cookie_threshold: Option<u32>,
+    pub threads: Option<usize>,
+    pub batch: Option<usize>,
+    #[serde(default)]
+    pub tunnel: ServerTunnel,
+    #[serde(default)]
+    pub keepalive: Keepalive
+}
+
+#[derive(Debug,Default,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTunnel {
+    pub ip: Option<String>,
+    pub netmask: Option<String>,
+    pub pool: Option<String>,
+    pub ip6: Option<String>,
+    pub pool6: Option<String>,
+    pub dns: Option<String>,
+    pub mode: Option<String>,
+    pub bridge: Option<String>,
+    pub dev: Option<String>,
+    pub mtu: Option<u32>,
+    pub persist: Option<bool>,
+    pub owner: Option<String>,
+    pub group: Option<String>
+}
+
+/// Settings of `boringvpn client --config`, laid out like the server's:
+///
+/// ```toml
+/// server = "vpn.example.com"
+/// port = 9527
+/// private_key_file = "/etc/boringvpn/private.key"
+/// server_key = "..."
+///
+/// [tunnel]
+/// default_route = false
+/// mode = "tap"
+/// dev = "tap-vpn"
+/// ```
+#[derive(Debug,Default,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    pub server: Option<String>,
+    pub port: Option<u16>,
+    pub id: Option<String>,
+    pub key: Option<String>,
+    pub key_file: Option<String>,
+    pub private_key_file: Option<String>,
+    pub server_key: Option<String>,
+    pub proto: Option<String>,
+    pub ciphers: Option<String>,
+    pub batch: Option<usize>,
+    #[serde(default)]
+    pub tunnel: ClientTunnel,
+    #[serde(default)]
+    pub keepalive: Keepalive
+}
+
+#[derive(Debug,Default,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientTunnel {
+    pub default_route: Option<bool>,
+    pub mode: Option<String>,
+    pub dev: Option<String>,
+    pub mtu: Option<u32>,
+    pub persist: Option<bool>,
+    pub owner: Option<String>,
+    pub group: Option<String>
+}
+
+#[derive(Debug,Default,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keepalive {
+    pub interval: Option<u64>,
+    pub dead_peer_timeout: Option<u64>
+}
+
+/// One client of the peer database given with `boringvpn server --peers`,
+/// which maps client ids to their pre-shared or public keys:
+///
+/// ```toml
+/// [laptop]
+/// public_key = "..."
+///
+/// [phone]
+/// key_file = "/etc/boringvpn/phone.key"
+/// revoked = true
+/// ```
+///
+/// A revoked client is told so when it connects, one missing from the
+/// database does not hear back at all.
+#[derive(Debug,Default,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    pub key: Option<String>,
+    pub key_file: Option<String>,
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub revoked: bool
+}
+
+/// How a client of the peer database authenticates.
+#[derive(Debug,Clone,PartialEq)]
+pub enum PeerKey {
+    Shared(SharedKey),
+    Public(PublicKey)
+}
+
+impl PeerConfig {
+    pub fn key(&self) -> Result<PeerKey,Error> {
+        match (&self.key, &self.key_file, &self.public_key) {
+            (Some(key), None, None) => SharedKey::parse(key).map(PeerKey::Shared),
+            (None, Some(path), None) => read_key_file(Path::new(path)).map(PeerKey::Shared),
+            (None, None, Some(key)) => PublicKey::parse(key).map(PeerKey::Public),
+            _ => Err(Error::Config("set one of key, key_file or public_key of a peer".to_string()))
+        }
+    }
+}
+
+/// Reads the peer database, refusing it if other users could read the keys in it.
+pub fn load_peers(path: &Path) -> Result<HashMap<String,PeerConfig>,Error> {
+    let content = read_private(path, "peer database", "failed to read peer database")?;
+    let content = String::from_utf8(content).map_err(|_| Error::Config(format!("peer database {} is not UTF-8", path.display())))?;
+    toml::from_str(&content).map_err(|e| Error::Config(format!("invalid peer database {}: {}", path.display(), e)))
+}
+
+fn load<T>(path: &Path) -> Result<T,Error> where for<'de> T: Deserialize<'de> {
+    let content = fs::read_to_string(path).map_err(|e| Error::File("failed to read config file",e))?;
+    toml::from_str(&content).map_err(|e| Error::Config(format!("invalid config file {}: {}", path.display(), e)))
+}
+
+/// Reads a pre-shared key from `path`. Text is read like `--key`, so a raw
+/// key is written as `base64:...`. A file of exactly `PSK_LEN` bytes that is
+/// not text, as random bytes almost never are, is taken as a raw key too.
+/// Files other users can read are refused.
+pub fn read_key_file(path: &Path) -> Result<SharedKey,Error> {
+    let content = read_private(path, "key file", "failed to read key file")?;
+    match String::from_utf8(content) {
+        Ok(text) => SharedKey::parse(text.trim()),
+        Err(e) if e.as_bytes().len() == PSK_LEN => SharedKey::from_bytes(e.into_bytes()),
+        Err(_) => Err(Error::Config(format!("key file {} is neither text nor a {} byte key", path.display(), PSK_LEN)))
+    }
+}
+
+/// Reads a private key as `boringvpn genkey` writes it.
+pub fn read_private_key(path: &Path) -> Result<PrivateKey,Error> {
+    let content = read_private(path, "private key file", "failed to read private key file")?;
+    let content = String::from_utf8(content).map_err(|_| Error::Config(format!("private key file {} is not base64", path.display())))?;
+    PrivateKey::parse(&content)
+}
+
+/// Reads a file holding keys unless other users can read it too. `what`
+/// names the file in errors.
+fn read_private(path: &Path,what: &str,failed: &'static str) -> Result<Vec<u8>,Error> {
+    check_private(path, what, failed)?;
+    fs::read(path).map_err(|e| Error::File(failed,e))
+}
+
+fn check_private(path: &Path,what: &str,failed: &'static str) -> Result<(),Error> {
+    let metadata = fs::metadata(path).map_err(|e| Error::File(failed,e))?;
+    if metadata.permissions().mode() & 0o004 != 0 {
+        return Err(Error::Config(format!("{} {} is readable by everyone, chmod o-r it", what, path.display())))
+    }
+    Ok(())
+}
+
+impl ServerConfig {
+    /// Reads the file, which must be private like a key file once it holds `key`.
+    pub fn load(path: &Path) -> Result<Self,Error> {
+        let config: Self = load(path)?;
+        if config.key.is_some() {
+            check_private(path, "config file", "failed to read config file")?;
+        }
+        Ok(config)
+    }
+}
+
+impl ClientConfig {
+    /// Reads the file, which must be private like a key file once it holds `key`.
+    pub fn load(path: &Path) -> Result<Self,Error> {
+        let config: Self = load(path)?;
+        if config.key.is_some() {
+            check_private(path, "config file", "failed to read config file")?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+
+    #[test]
+    fn server_config_test() {
+        let config: ServerConfig = toml::from_str(r#"
+            listen = "[::]"
+            port = 9527
+            key = "secret"
+            cookie_threshold = 0
+            threads = 8
+
+            [tunnel]
+            pool = "10.8.0.0/16"
+            mode = "tap"
+
+            [keepalive]
+            interval = 5
+        "#).unwrap();
+        assert_eq!(config.listen.as_ref().map(String::as_str), Some("[::]"));
+        assert_eq!(config.port, Some(9527));
+        assert_eq!(config.cookie_threshold, Some(0));
+        assert_eq!(config.threads, Some(8));
+        assert_eq!(config.tunnel.pool.as_ref().map(String::as_str), Some("10.8.0.0/16"));
+        assert_eq!(config.tunnel.netmask, None);
+        assert_eq!(config.tunnel.mode.as_ref().map(String::as_str), Some("tap"));
+        assert_eq!(config.tunnel.bridge, None);
+        assert_eq!(config.keepalive.interval, Some(5));
+        assert_eq!(config.keepalive.dead_peer_timeout, None);
+    }
+
+    #[test]
+    fn client_config_test() {
+        let config: ClientConfig = toml::from_str(r#"
+            server = "vpn.example.com"
+            batch = 1
+            [tunnel]
+            default_route = false
+            mtu = 1280
+        "#).unwrap();
+        assert_eq!(config.server.as_ref().map(String::as_str), Some("vpn.example.com"));
+        assert_eq!(config.batch, Some(1));
+        assert_eq!(config.tunnel.default_route, Some(false));
+        assert_eq!(config.tunnel.mtu, Some(1280));
+        assert_eq!(config.tunnel.dev, None);
+        assert_eq!(config.port, None);
+    }
+
+    #[test]
+    fn invalid_config_test() {
+        assert!(toml::from_str::<ClientConfig>("sever = \"typo\"").is_err());
+        assert!(toml::from_str::<ClientConfig>("port = 70000").is_err());
+        assert!(toml::from_str::<ServerConfig>("[tunnel]\ndefault_route = true").is_err());
+        let missing = ServerConfig::load(Path::new("/nonexistent/server.toml"));
+        assert!(missing.unwrap_err().to_string().contains("failed to read config file"));
+    }
+
+    #[test]
+    fn key_file_test() {
+        let path = std::env::temp_dir().join(format!("boringvpn-key-{}", std::process::id()));
+        let write = |content: &[u8], mode: u32| {
+            fs::write(&path, content).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        write(b"secret\n", 0o600);
+        assert_eq!(read_key_file(&path).unwrap(), SharedKey::Passphrase("secret".to_string()));
+        write(&[0xff; PSK_LEN], 0o640);
+        assert_eq!(read_key_file(&path).unwrap(), SharedKey::Key(vec![0xff; PSK_LEN]));
+        // Passphrases of the length of a raw key are still passphrases.
+        let passphrase = "a".repeat(PSK_LEN);
+        write(passphrase.as_bytes(), 0o600);
+        assert_eq!(read_key_file(&path).unwrap(), SharedKey::Passphrase(passphrase.clone()));
+        write(format!("{}\n", &passphrase[1..]).as_bytes(), 0o600);
+        assert_eq!(read_key_file(&path).unwrap(), SharedKey::Passphrase(passphrase[1..].to_string()));
+        write(format!("base64:{}\n", base64::encode(&[7; PSK_LEN])).as_bytes(), 0o600);
+        assert_eq!(read_key_file(&path).unwrap(), SharedKey::Key(vec![7; PSK_LEN]));
+        write(b"secret", 0o644);
+        assert!(read_key_file(&path).unwrap_err().to_string().contains("readable by everyone"));
+        write(&[0xff; 5], 0o600);
+        assert!(read_key_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn config_key_test() {
+        let path = std::env::temp_dir().join(format!("boringvpn-config-{}", std::process::id()));
+        let write = |content: &str, mode: u32| {
+            fs::write(&path, content).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        write("port = 9527\n", 0o644);
+        assert!(ServerConfig::load(&path).is_ok());
+        write("key = \"secret\"\n", 0o644);
+        assert!(ServerConfig::load(&path).unwrap_err().to_string().contains("readable by everyone"));
+        assert!(ClientConfig::load(&path).unwrap_err().to_string().contains("readable by everyone"));
+        write("key = \"secret\"\n", 0o600);
+        assert_eq!(ClientConfig::load(&path).unwrap().key, Some("secret".to_string()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn peers_test() {
+        let peers: HashMap<String,PeerConfig> = toml::from_str(r#"
+            [laptop]
+            key = "secret"
+
+            [phone]
+            key = "other"
+            revoked = true
+
+            [tablet]
+            public_key = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+
+            [broken]
+
+            [both]
+            key = "secret"
+            public_key = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+        "#).unwrap();
+        assert_eq!(peers["laptop"].key().unwrap(), PeerKey::Shared(SharedKey::Passphrase("secret".to_string())));
+        assert!(!peers["laptop"].revoked);
+        assert!(peers["phone"].revoked);
+        match peers["tablet"].key().unwrap() {
+            PeerKey::Public(key) => assert_eq!(key.as_bytes(), &[1; 32]),
+            key => panic!("unexpected {:?}", key)
+        }
+        assert!(peers["broken"].key().is_err());
+        assert!(peers["both"].key().is_err());
+        assert!(toml::from_str::<HashMap<String,PeerConfig>>("[laptop]\npublic = true").is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use log::warn;
+use ring::rand::{SecureRandom,SystemRandom};
+use std::net::{IpAddr,SocketAddr};
+use std::time::{Duration,Instant};
+
+use crate::crypto::{self,COOKIE_LEN,KEY_LEN};
+use crate::types::Error;
+
+/// Default number of hellos per second above which clients have to answer
+/// a cookie before the server spends anything on their handshake.
+pub const COOKIE_THRESHOLD: u32 = 64;
+/// How long a cookie secret is used. Cookies of the previous one are still
+/// accepted, so a client answering right after a rotation gets through.
+const COOKIE_ROTATION: Duration = Duration::from_secs(120);
+/// Hellos are counted per window of this length to detect load.
+const LOAD_WINDOW: Duration = Duration::from_secs(1);
+
+fn random_secret(rng: &SystemRandom) -> Result<[u8; KEY_LEN],Error> {
+    let mut secret = [0u8; KEY_LEN];
+    rng.fill(&mut secret).map_err(|_| Error::Crypto("Failed to generate cookie secret"))?;
+    Ok(secret)
+}
+
+fn address_bytes(address: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match address.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec()
+    };
+    bytes.extend_from_slice(&address.port().to_be_bytes());
+    bytes
+}
+
+/// Stateless cookies for UDP hellos. A flood of hellos from spoofed
+/// addresses would otherwise make the server open every one of them and
+/// answer addresses that never asked. Once more hellos than the threshold
+/// arrive per second, a hello only gets through if it carries a MAC under
+/// the cookie of its source address, which is a MAC of the address under a
+/// secret only the server knows. Nothing is stored per client. A cookie
+/// only proves the sender owns its address, copies of a captured hello are
+/// caught by `Hellos` instead.
+pub struct Cookies {
+    rng: SystemRandom,
+    secret: [u8; KEY_LEN],
+    previous: [u8; KEY_LEN],
+    rotated: Instant,
+    threshold: u32,
+    window: Instant,
+    hellos: u32,
+    last_hellos: u32
+}
+
+impl Cookies {
+    pub fn new(threshold: u32) -> Result<Self,Error> {
+        let rng = SystemRandom::new();
+        let secret = random_secret(&rng)?;
+        let now = Instant::now();
+        Ok(Cookies {
+            rng: rng,
+            secret: secret,
+            previous: secret,
+            rotated: now,
+            threshold: threshold,
+            window: now,
+            hellos: 0,
+            last_hellos: 0
+        })
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() < COOKIE_ROTATION {
+            return
+        }
+        match random_secret(&self.rng) {
+            Ok(secret) => {
+                self.previous = self.secret;
+                self.secret = secret;
+                self.rotated = Instant::now();
+            },
+            Err(e) => warn!("Keeping the cookie secret: {}", e)
+        }
+    }
+
+    /// Counts a hello and tells whether so many arrived in this or the last
+    /// window that hellos have to carry a cookie MAC. A threshold of zero
+    /// always asks for one.
+    pub fn under_load(&mut self) -> bool {
+        let elapsed = self.window.elapsed();
+        if elapsed >= LOAD_WINDOW {
+            self.last_hellos = match elapsed < LOAD_WINDOW * 2 {
+                true => self.hellos,
+                false => 0
+            };
+            self.hellos = 0;
+            self.window = Instant::now();
+        }
+        self.hellos = self.hellos.saturating_add(1);
+        std::cmp::max(self.hellos, self.last_hellos) > self.threshold
+    }
+
+    /// The cookie to send to `address`.
+    pub fn cookie(&mut self,address: &SocketAddr) -> [u8; COOKIE_LEN] {
+        self.rotate();
+        crypto::cookie_mac(&self.secret, &[&address_bytes(address)])
+    }
+
+    /// Whether `mac` is the MAC of `hello` under a cookie we sent to `address`.
+    pub fn check(&mut self,address: &SocketAddr,hello: &[u8],mac: &[u8]) -> bool {
+        self.rotate();
+        let address = address_bytes(address);
+        [self.secret, self.previous].iter().any(|secret| {
+            crypto::verify_cookie_mac(&crypto::cookie_mac(secret, &[&address]), hello, mac)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cookie::*;
+    use crate::boring;
+
+    #[test]
+    fn cookie_test() {
+        let address: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let mut cookies = Cookies::new(2).unwrap();
+        assert!(!cookies.under_load());
+        assert!(!cookies.under_load());
+        assert!(cookies.under_load());
+        // The last window still counts.
+        cookies.window -= LOAD_WINDOW;
+        assert!(cookies.under_load());
+        cookies.window -= LOAD_WINDOW * 2;
+        assert!(!cookies.under_load());
+        assert!(Cookies::new(0).unwrap().under_load());
+
+        let hello = [1u8; 40];
+        let cookie = cookies.cookie(&address);
+        let mut packet = boring::append_cookie_mac(&hello, Some(&cookie));
+        let (hello, mac) = boring::strip_cookie_mac(&mut packet).unwrap();
+        assert!(cookies.check(&address, hello, mac));
+        assert!(!cookies.check(&"192.0.2.1:4001".parse().unwrap(), hello, mac));
+        assert!(!cookies.check(&"192.0.2.2:4000".parse().unwrap(), hello, mac));
+        assert!(!cookies.check(&address, hello, &[0u8; COOKIE_LEN]));
+
+        // Cookies of the previous secret are good until the next rotation.
+        cookies.rotated -= COOKIE_ROTATION;
+        assert!(cookies.check(&address, hello, mac));
+        assert!(cookies.cookie(&address) != cookie);
+        cookies.rotated -= COOKIE_ROTATION;
+        assert!(!cookies.check(&address, hello, mac));
+    }
+}
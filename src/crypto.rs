@@ -1,15 +1,61 @@
-use ring::{aead,pbkdf2,digest,rand};
+use ring::{aead,agreement,constant_time,hkdf,hmac,pbkdf2,digest,rand};
+use serde::{Serialize,Deserialize};
 use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::fmt;
+use base64;
+use x25519_dalek;
 use ring::rand::SecureRandom;
+use untrusted;
 use crate::types::Error;
 
 const SALT: &[u8; 32] = b"junjunjunjunjunjunjunjunjunjunai";
 
+/// Bytes of the packet counter carried in front of every sealed packet. The
+/// counter is the low part of the AEAD nonce, the remaining bytes are zero.
+pub const COUNTER_LEN: usize = 8;
+
+/// Number of counters behind the highest one seen that are still accepted.
+const REPLAY_WINDOW: u64 = 1024;
+
+/// Cipher the pre-shared key seals the handshake with, before a cipher is negotiated.
+pub const HANDSHAKE_METHOD: CryptoMethod = CryptoMethod::AES256;
+
+#[derive(Debug,Clone,Copy,PartialEq,Serialize,Deserialize)]
 pub enum CryptoMethod {
     ChaCha20,
     AES256
 }
 
+impl FromStr for CryptoMethod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<CryptoMethod,Error> {
+        match s {
+            "chacha20" => Ok(CryptoMethod::ChaCha20),
+            "aes256" => Ok(CryptoMethod::AES256),
+            _ => Err(Error::Config(format!("unknown cipher '{}', expected aes256 or chacha20", s)))
+        }
+    }
+}
+
+impl CryptoMethod {
+    /// Parses a comma separated list of ciphers, most preferred first.
+    pub fn parse_list(list: &str) -> Result<Vec<CryptoMethod>,Error> {
+        let mut methods = Vec::new();
+        for name in list.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            let method = name.parse()?;
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+        }
+        if methods.is_empty() {
+            return Err(Error::Config("no cipher given".to_string()))
+        }
+        Ok(methods)
+    }
+}
+
 pub struct CryptoData {
     sealing_key: aead::SealingKey,
     opening_key: aead::OpeningKey,
@@ -17,6 +63,49 @@ pub struct CryptoData {
     key: Vec<u8>
 }
 
+/// Length of a pre-shared key given as raw bytes rather than a passphrase.
+pub const PSK_LEN: usize = 32;
+
+/// The pre-shared key sealing the handshake: either a passphrase stretched
+/// with PBKDF2, or `PSK_LEN` random bytes used as they are.
+#[derive(Clone,PartialEq)]
+pub enum SharedKey {
+    Passphrase(String),
+    Key(Vec<u8>)
+}
+
+impl SharedKey {
+    /// Reads a key given as text. `base64:` followed by the base64 encoding
+    /// of `PSK_LEN` bytes is a raw key, anything else a passphrase.
+    pub fn parse(key: &str) -> Result<SharedKey,Error> {
+        match key.starts_with("base64:") {
+            true => {
+                let bytes = base64::decode(&key["base64:".len()..]).map_err(|_| Error::Config("invalid base64 in key".to_string()))?;
+                SharedKey::from_bytes(bytes)
+            },
+            false if key.is_empty() => Err(Error::Config("key is empty".to_string())),
+            false => Ok(SharedKey::Passphrase(key.to_string()))
+        }
+    }
+
+    pub fn from_bytes(key: Vec<u8>) -> Result<SharedKey,Error> {
+        match key.len() {
+            PSK_LEN => Ok(SharedKey::Key(key)),
+            len => Err(Error::Config(format!("raw key has {} bytes, expected {}", len, PSK_LEN)))
+        }
+    }
+}
+
+// Keeps the key out of logs and panic messages.
+impl fmt::Debug for SharedKey {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SharedKey::Passphrase(_) => write!(formatter, "Passphrase(..)"),
+            SharedKey::Key(_) => write!(formatter, "Key(..)")
+        }
+    }
+}
+
 pub enum Crypto {
     None,
     ChaCha20Poly1305(CryptoData),
@@ -37,13 +126,6 @@ fn inc_nonce(nonce: &mut [u8]) {
 }
 
 impl Crypto {
-    pub fn method(&self) -> u8 {
-        match *self {
-            Crypto::None => 0,
-            Crypto::ChaCha20Poly1305 {..} => 1,
-            Crypto::AES256GCM {..} => 2
-        }
-    }
     pub fn nonce_byte(&self) -> usize {
         match *self {
             Crypto::None => 0,
@@ -67,22 +149,42 @@ impl Crypto {
             CryptoMethod::ChaCha20 => &aead::CHACHA20_POLY1305,
             CryptoMethod::AES256 => &aead::AES_256_GCM
         };
-        let mut key: Vec<u8> = Vec::with_capacity(algo.key_len());
-        for _ in 0..algo.key_len() {
-            key.push(0);
+        let mut key: Vec<u8> = vec![0; algo.key_len()];
+        pbkdf2::derive(&digest::SHA256, NonZeroU32::new(4096).unwrap(), SALT, password.as_bytes(), &mut key);
+        Crypto::from_psk_key(method, &key)
+    }
+    pub fn from_psk(method: CryptoMethod,psk: &SharedKey) -> Self {
+        match *psk {
+            SharedKey::Passphrase(ref password) => Crypto::from_shared_key(method, password),
+            SharedKey::Key(ref key) => Crypto::from_psk_key(method, key)
+        }
+    }
+    fn from_psk_key(method: CryptoMethod,key: &[u8]) -> Self {
+        let mut crypto = Crypto::from_key(method, key);
+        // Every peer seals with the shared key, so start the counter at a random
+        // point to keep them from walking over each other's nonces.
+        if let Crypto::ChaCha20Poly1305(ref mut data) | Crypto::AES256GCM(ref mut data) = crypto {
+            let l = data.nonce.len();
+            // leave the highest byte of the counter 0 so it will not overflow
+            if rand::SystemRandom::new().fill(&mut data.nonce[l - COUNTER_LEN + 1..]).is_err() {
+                log::warn!("Randomizing nonce failed");
+            }
         }
+        crypto
+    }
+
+    pub fn from_key(method: CryptoMethod,key: &[u8]) -> Self {
+        let algo = match method {
+            CryptoMethod::ChaCha20 => &aead::CHACHA20_POLY1305,
+            CryptoMethod::AES256 => &aead::AES_256_GCM
+        };
         let sealing_key = aead::SealingKey::new(algo, &key[..algo.key_len()]).expect("Failed to create key");
         let opening_key = aead::OpeningKey::new(algo, &key[..algo.key_len()]).expect("Failed to create key");
-        pbkdf2::derive(&digest::SHA256, NonZeroU32::new(4096).unwrap(), SALT, password.as_bytes(), &mut key);
         let mut nonce: Vec<u8> = Vec::with_capacity(algo.nonce_len());
         for _ in 0..algo.nonce_len() {
             nonce.push(0);
         }
-        // leave the highest byte of the nonce 0 so it will not overflow
-        // if rand::SystemRandom::new().fill(&mut nonce[1..]).is_err() {
-        //     log::warn!("Randomizing nonce failed");
-        // }
-        let data = CryptoData { sealing_key, opening_key, nonce, key };
+        let data = CryptoData { sealing_key, opening_key, nonce, key: key[..algo.key_len()].to_vec() };
         match method {
             CryptoMethod::ChaCha20 => Crypto::ChaCha20Poly1305(data),
             CryptoMethod::AES256 => Crypto::AES256GCM(data)
@@ -101,22 +203,504 @@ impl Crypto {
             }
         }
     }
-    pub fn encrypt(&mut self, buf: &mut [u8], mlen: usize, nonce_bytes: &mut [u8], header: &[u8]) -> usize {
+
+    /// Number of packets sealed so far, i.e. the counter of the last one.
+    pub fn counter(&self) -> u64 {
+        match *self {
+            Crypto::None => 0,
+            Crypto::ChaCha20Poly1305(ref data) | Crypto::AES256GCM(ref data) => {
+                let l = data.nonce.len();
+                data.nonce[l - COUNTER_LEN..].iter().fold(0u64, |counter, &b| counter << 8 | b as u64)
+            }
+        }
+    }
+
+    /// Advances the packet counter and returns the one to seal the next packet with.
+    pub fn next_counter(&mut self) -> u64 {
+        if let Crypto::ChaCha20Poly1305(ref mut data) | Crypto::AES256GCM(ref mut data) = *self {
+            inc_nonce(&mut data.nonce);
+        }
+        self.counter()
+    }
+
+    /// Seals `buf[..mlen]` in place under the nonce of `counter`, which must come
+    /// from `next_counter`. `buf` needs room for the tag behind the message.
+    pub fn seal(&self,counter: u64,buf: &mut [u8],mlen: usize,header: &[u8]) -> usize {
         let tag_len = self.additional_bytes();
         match *self {
             Crypto::None => mlen,
-            Crypto::ChaCha20Poly1305(ref mut data) | Crypto::AES256GCM(ref mut data) => {
-                // inc_nonce(&mut data.nonce);
+            Crypto::ChaCha20Poly1305(ref data) | Crypto::AES256GCM(ref data) => {
                 assert!(buf.len() - mlen >= tag_len);
                 let buf = &mut buf[.. mlen + tag_len];
-                let nonce = aead::Nonce::try_assume_unique_for_key(&data.nonce).unwrap();
-                let new_len = aead::seal_in_place(&data.sealing_key, nonce, aead::Aad::from(header), buf, tag_len).expect("Failed to encrypt");
-                nonce_bytes.clone_from_slice(&data.nonce);
-                new_len
+                let nonce = self.nonce_from_counter(counter);
+                let nonce = aead::Nonce::try_assume_unique_for_key(&nonce).unwrap();
+                aead::seal_in_place(&data.sealing_key, nonce, aead::Aad::from(header), buf, tag_len).expect("Failed to encrypt")
             }
         }
     }
 
+    /// Nonce belonging to a packet counter read off the wire.
+    pub fn nonce_from_counter(&self,counter: u64) -> Vec<u8> {
+        let mut nonce = vec![0u8; self.nonce_byte()];
+        let l = nonce.len();
+        for i in 0..COUNTER_LEN {
+            nonce[l - 1 - i] = (counter >> (8 * i)) as u8;
+        }
+        nonce
+    }
+}
+
+/// Sliding window over the packet counters received under one key.
+///
+/// Counters more than `REPLAY_WINDOW` behind the highest one accepted so far
+/// are too old, counters inside the window are accepted once each so that
+/// packets reordered by the network are not dropped.
+pub struct ReplayWindow {
+    last: u64,
+    bitmap: Vec<u64>
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        ReplayWindow {
+            last: 0,
+            bitmap: vec![0; (REPLAY_WINDOW / 64) as usize]
+        }
+    }
+
+    fn bit(&self,counter: u64) -> (usize, u64) {
+        let index = (counter % REPLAY_WINDOW) as usize;
+        (index / 64, 1 << (index % 64))
+    }
+
+    /// Whether a packet with this counter may be accepted. Only call `update`
+    /// once the packet has also been authenticated.
+    pub fn check(&self,counter: u64) -> bool {
+        if counter > self.last {
+            return true
+        }
+        if self.last - counter >= REPLAY_WINDOW {
+            return false
+        }
+        let (word, mask) = self.bit(counter);
+        self.bitmap[word] & mask == 0
+    }
+
+    pub fn update(&mut self,counter: u64) {
+        if counter > self.last {
+            if counter - self.last >= REPLAY_WINDOW {
+                for word in self.bitmap.iter_mut() {
+                    *word = 0;
+                }
+            } else {
+                for c in self.last + 1..counter {
+                    let (word, mask) = self.bit(c);
+                    self.bitmap[word] &= !mask;
+                }
+            }
+            self.last = counter;
+        }
+        let (word, mask) = self.bit(counter);
+        self.bitmap[word] |= mask;
+    }
+}
+
+/// One side of the ephemeral X25519 exchange run during the handshake.
+///
+/// The public keys travel inside handshake messages sealed with the
+/// pre-shared key, so only holders of the PSK can complete an exchange. The
+/// shared secret is then expanded with HKDF, salted with the PSK, into one key
+/// per direction that lives only as long as the session.
+pub struct KeyExchange {
+    private_key: agreement::EphemeralPrivateKey,
+    public_key: Vec<u8>
+}
+
+impl KeyExchange {
+    pub fn new() -> Result<Self,Error> {
+        let rng = rand::SystemRandom::new();
+        let private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+            .map_err(|_| Error::Crypto("Failed to generate ephemeral key"))?;
+        let public_key = private_key.compute_public_key()
+            .map_err(|_| Error::Crypto("Failed to compute public key"))?
+            .as_ref().to_vec();
+        Ok(KeyExchange { private_key, public_key })
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Consumes the ephemeral key and returns the `(sender, receiver)` pair for
+    /// this side. `initiator` is true on the client.
+    pub fn derive(self,method: CryptoMethod,psk: &[u8],peer_public_key: &[u8],initiator: bool) -> Result<(Crypto,Crypto),Error> {
+        let (client_public_key, server_public_key) = match initiator {
+            true => (self.public_key.clone(), peer_public_key.to_vec()),
+            false => (peer_public_key.to_vec(), self.public_key.clone())
+        };
+        let key_len = match method {
+            CryptoMethod::ChaCha20 => aead::CHACHA20_POLY1305.key_len(),
+            CryptoMethod::AES256 => aead::AES_256_GCM.key_len()
+        };
+        let salt = hmac::SigningKey::new(&digest::SHA256, psk);
+        agreement::agree_ephemeral(self.private_key, &agreement::X25519, untrusted::Input::from(peer_public_key),
+                                   Error::Crypto("Failed to agree on session key"), |shared_secret| {
+            let prk = hkdf::extract(&salt, shared_secret);
+            let expand = |label: &[u8]| {
+                let mut info = label.to_vec();
+                info.extend_from_slice(&client_public_key);
+                info.extend_from_slice(&server_public_key);
+                let mut key = vec![0u8; key_len];
+                hkdf::expand(&prk, &info, &mut key);
+                Crypto::from_key(method, &key)
+            };
+            let client_to_server = expand(b"boringvpn client to server");
+            let server_to_client = expand(b"boringvpn server to client");
+            match initiator {
+                true => Ok((client_to_server, server_to_client)),
+                false => Ok((server_to_client, client_to_server))
+            }
+        })
+    }
+}
+
+/// Length of Curve25519 keys and of the hashes in the Noise handshake.
+pub const KEY_LEN: usize = 32;
+
+const NOISE_PROTOCOL: &[u8; KEY_LEN] = b"Noise_IK_25519_ChaChaPoly_SHA256";
+const NOISE_PROLOGUE: &[u8] = b"boringvpn";
+
+fn decode_key(key: &str) -> Result<[u8; KEY_LEN],Error> {
+    let bytes = base64::decode(key.trim()).map_err(|_| Error::Config("invalid base64 in key".to_string()))?;
+    if bytes.len() != KEY_LEN {
+        return Err(Error::Config(format!("key has {} bytes, expected {}", bytes.len(), KEY_LEN)))
+    }
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+/// Curve25519 public key identifying one end of a static key handshake,
+/// written in base64 like `boringvpn pubkey` prints it.
+#[derive(Clone,Copy,PartialEq,Eq,Hash)]
+pub struct PublicKey([u8; KEY_LEN]);
+
+impl PublicKey {
+    pub fn parse(key: &str) -> Result<PublicKey,Error> {
+        decode_key(key).map(PublicKey)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(formatter, "{}", base64::encode(&self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(formatter, "PublicKey({})", self)
+    }
+}
+
+/// Curve25519 private key, the static identity of a client or server.
+#[derive(Clone)]
+pub struct PrivateKey(x25519_dalek::StaticSecret);
+
+impl PrivateKey {
+    pub fn generate() -> Result<PrivateKey,Error> {
+        let mut key = [0u8; KEY_LEN];
+        rand::SystemRandom::new().fill(&mut key).map_err(|_| Error::Crypto("Failed to generate private key"))?;
+        Ok(PrivateKey(x25519_dalek::StaticSecret::from(key)))
+    }
+
+    pub fn parse(key: &str) -> Result<PrivateKey,Error> {
+        decode_key(key).map(|key| PrivateKey(x25519_dalek::StaticSecret::from(key)))
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(&self.0.to_bytes())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(*x25519_dalek::PublicKey::from(&self.0).as_bytes())
+    }
+
+    /// X25519 with a peer's key, refusing the low order points that would
+    /// make the result independent of our key.
+    fn agree(&self,public_key: &PublicKey) -> Result<[u8; KEY_LEN],Error> {
+        let shared = *self.0.diffie_hellman(&x25519_dalek::PublicKey::from(public_key.0)).as_bytes();
+        match shared.iter().all(|&b| b == 0) {
+            true => Err(Error::Crypto("Invalid public key")),
+            false => Ok(shared)
+        }
+    }
+}
+
+// Keeps the key out of logs and panic messages.
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(formatter, "PrivateKey({})", self.public_key())
+    }
+}
+
+fn noise_hmac(key: &[u8],parts: &[&[u8]]) -> [u8; KEY_LEN] {
+    let mut context = hmac::SigningContext::with_key(&hmac::SigningKey::new(&digest::SHA256, key));
+    for part in parts {
+        context.update(part);
+    }
+    let mut output = [0u8; KEY_LEN];
+    output.copy_from_slice(context.sign().as_ref());
+    output
+}
+
+/// Length of a cookie and of the MAC a hello carries under it.
+pub const COOKIE_LEN: usize = 16;
+
+/// HMAC-SHA256 of `parts` under `key`, cut to the length of a cookie.
+pub fn cookie_mac(key: &[u8],parts: &[&[u8]]) -> [u8; COOKIE_LEN] {
+    let mut mac = [0u8; COOKIE_LEN];
+    mac.copy_from_slice(&noise_hmac(key, parts)[..COOKIE_LEN]);
+    mac
+}
+
+/// Whether `mac` is the MAC of `data` under `cookie`, compared in constant time.
+pub fn verify_cookie_mac(cookie: &[u8],data: &[u8],mac: &[u8]) -> bool {
+    constant_time::verify_slices_are_equal(&cookie_mac(cookie, &[data]), mac).is_ok()
+}
+
+/// The HKDF of the Noise spec, with a third output for the rekey secret.
+fn noise_hkdf(chaining_key: &[u8],input: &[u8]) -> ([u8; KEY_LEN],[u8; KEY_LEN],[u8; KEY_LEN]) {
+    let temp_key = noise_hmac(chaining_key, &[input]);
+    let output1 = noise_hmac(&temp_key, &[&[1]]);
+    let output2 = noise_hmac(&temp_key, &[&output1, &[2]]);
+    let output3 = noise_hmac(&temp_key, &[&output2, &[3]]);
+    (output1, output2, output3)
+}
+
+/// Chaining key, handshake hash and cipher key of a Noise handshake, the
+/// SymmetricState of the spec.
+#[derive(Clone)]
+struct SymmetricState {
+    chaining_key: [u8; KEY_LEN],
+    hash: [u8; KEY_LEN],
+    key: Option<[u8; KEY_LEN]>,
+    nonce: u64
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        SymmetricState {
+            chaining_key: *NOISE_PROTOCOL,
+            hash: *NOISE_PROTOCOL,
+            key: None,
+            nonce: 0
+        }
+    }
+
+    fn mix_hash(&mut self,data: &[u8]) {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(&self.hash);
+        context.update(data);
+        self.hash.copy_from_slice(context.finish().as_ref());
+    }
+
+    fn mix_key(&mut self,input: &[u8]) {
+        let (chaining_key, key, _) = noise_hkdf(&self.chaining_key, input);
+        self.chaining_key = chaining_key;
+        self.key = Some(key);
+        self.nonce = 0;
+    }
+
+    fn nonce(&mut self) -> aead::Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn encrypt_and_hash(&mut self,plaintext: &[u8]) -> Vec<u8> {
+        let key = self.key.expect("Noise cipher key not set");
+        let tag_len = aead::CHACHA20_POLY1305.tag_len();
+        let mut buf = plaintext.to_vec();
+        buf.resize(plaintext.len() + tag_len, 0);
+        let sealing_key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key).expect("Failed to create key");
+        let nonce = self.nonce();
+        aead::seal_in_place(&sealing_key, nonce, aead::Aad::from(&self.hash[..]), &mut buf, tag_len).expect("Failed to encrypt");
+        self.mix_hash(&buf);
+        buf
+    }
+
+    fn decrypt_and_hash(&mut self,ciphertext: &[u8]) -> Result<Vec<u8>,Error> {
+        let key = self.key.expect("Noise cipher key not set");
+        let mut buf = ciphertext.to_vec();
+        let opening_key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &key).expect("Failed to create key");
+        let nonce = self.nonce();
+        let len = aead::open_in_place(&opening_key, nonce, aead::Aad::from(&self.hash[..]), 0, &mut buf)
+            .map_err(|_| Error::Crypto("Failed to decrypt"))?
+            .len();
+        self.mix_hash(ciphertext);
+        buf.truncate(len);
+        Ok(buf)
+    }
+}
+
+/// One end of a Noise IK handshake (`Noise_IK_25519_ChaChaPoly_SHA256`),
+/// which authenticates both ends by their static keys:
+///
+/// ```text
+///   <- s
+///   ...
+///   -> e, es, s, ss
+///   <- e, ee, se
+/// ```
+///
+/// The client, which knows the server's public key beforehand, sends the
+/// first message with its own public key encrypted in it; the server answers
+/// with the second. Each message carries a payload, encrypted once the
+/// handshake got far enough to authenticate it. `split` then turns the
+/// handshake into the session keys.
+#[derive(Clone)]
+pub struct NoiseHandshake {
+    state: SymmetricState,
+    initiator: bool,
+    local_static: PrivateKey,
+    local_ephemeral: Option<PrivateKey>,
+    remote_static: Option<PublicKey>,
+    remote_ephemeral: Option<PublicKey>,
+    messages: u8
+}
+
+impl NoiseHandshake {
+    fn new(initiator: bool,local_static: &PrivateKey,remote_static: Option<&PublicKey>) -> Self {
+        let mut state = SymmetricState::new();
+        state.mix_hash(NOISE_PROLOGUE);
+        // The responder's static key is known beforehand.
+        let responder = remote_static.cloned().unwrap_or_else(|| local_static.public_key());
+        state.mix_hash(responder.as_bytes());
+        NoiseHandshake {
+            state: state,
+            initiator: initiator,
+            local_static: local_static.clone(),
+            local_ephemeral: None,
+            remote_static: remote_static.cloned(),
+            remote_ephemeral: None,
+            messages: 0
+        }
+    }
+
+    pub fn initiator(local_static: &PrivateKey,remote_static: &PublicKey) -> Self {
+        NoiseHandshake::new(true, local_static, Some(remote_static))
+    }
+
+    pub fn responder(local_static: &PrivateKey) -> Self {
+        NoiseHandshake::new(false, local_static, None)
+    }
+
+    /// The peer's static key, known to the responder once it read the first message.
+    pub fn remote_static(&self) -> Option<PublicKey> {
+        self.remote_static
+    }
+
+    fn ephemeral(&mut self) -> Result<PublicKey,Error> {
+        let ephemeral = PrivateKey::generate()?;
+        let public_key = ephemeral.public_key();
+        self.state.mix_hash(public_key.as_bytes());
+        self.local_ephemeral = Some(ephemeral);
+        Ok(public_key)
+    }
+
+    /// Writes the next handshake message of our side carrying `payload`.
+    pub fn write_message(&mut self,payload: &[u8]) -> Result<Vec<u8>,Error> {
+        let mut message = Vec::new();
+        match (self.initiator, self.messages) {
+            (true, 0) => {
+                let remote_static = self.remote_static.unwrap();
+                message.extend_from_slice(self.ephemeral()?.as_bytes());
+                let es = self.local_ephemeral.as_ref().unwrap().agree(&remote_static)?;
+                self.state.mix_key(&es);
+                let local_public_key = self.local_static.public_key();
+                message.extend(self.state.encrypt_and_hash(local_public_key.as_bytes()));
+                let ss = self.local_static.agree(&remote_static)?;
+                self.state.mix_key(&ss);
+            },
+            (false, 1) => {
+                let (remote_static, remote_ephemeral) = (self.remote_static.unwrap(), self.remote_ephemeral.unwrap());
+                message.extend_from_slice(self.ephemeral()?.as_bytes());
+                let ee = self.local_ephemeral.as_ref().unwrap().agree(&remote_ephemeral)?;
+                self.state.mix_key(&ee);
+                let se = self.local_ephemeral.as_ref().unwrap().agree(&remote_static)?;
+                self.state.mix_key(&se);
+            },
+            _ => return Err(Error::Crypto("Noise handshake message out of order"))
+        }
+        message.extend(self.state.encrypt_and_hash(payload));
+        self.messages += 1;
+        Ok(message)
+    }
+
+    /// Reads the peer's next handshake message and returns its payload. A
+    /// message that fails to authenticate leaves the handshake as it was.
+    pub fn read_message(&mut self,message: &[u8]) -> Result<Vec<u8>,Error> {
+        let mut next = self.clone();
+        let payload = next.read(message)?;
+        *self = next;
+        Ok(payload)
+    }
+
+    fn read(&mut self,message: &[u8]) -> Result<Vec<u8>,Error> {
+        let tag_len = aead::CHACHA20_POLY1305.tag_len();
+        let static_len = match (self.initiator, self.messages) {
+            (false, 0) => KEY_LEN + tag_len,
+            (true, 1) => 0,
+            _ => return Err(Error::Crypto("Noise handshake message out of order"))
+        };
+        if message.len() < KEY_LEN + static_len + tag_len {
+            return Err(Error::Invaildmessage("handshake message too short"))
+        }
+        let mut remote_ephemeral = [0u8; KEY_LEN];
+        remote_ephemeral.copy_from_slice(&message[..KEY_LEN]);
+        let remote_ephemeral = PublicKey(remote_ephemeral);
+        self.state.mix_hash(remote_ephemeral.as_bytes());
+        self.remote_ephemeral = Some(remote_ephemeral);
+        if self.initiator {
+            let ee = self.local_ephemeral.as_ref().unwrap().agree(&remote_ephemeral)?;
+            self.state.mix_key(&ee);
+            let se = self.local_static.agree(&remote_ephemeral)?;
+            self.state.mix_key(&se);
+        } else {
+            let es = self.local_static.agree(&remote_ephemeral)?;
+            self.state.mix_key(&es);
+            let mut remote_static = [0u8; KEY_LEN];
+            remote_static.copy_from_slice(&self.state.decrypt_and_hash(&message[KEY_LEN..KEY_LEN + static_len])?);
+            let remote_static = PublicKey(remote_static);
+            let ss = self.local_static.agree(&remote_static)?;
+            self.state.mix_key(&ss);
+            self.remote_static = Some(remote_static);
+        }
+        let payload = self.state.decrypt_and_hash(&message[KEY_LEN + static_len..])?;
+        self.messages += 1;
+        Ok(payload)
+    }
+
+    /// The `(sender, receiver)` pair of session keys for this side once both
+    /// messages went through, and a secret to salt later rekeys with.
+    pub fn split(self,method: CryptoMethod) -> Result<((Crypto,Crypto),Vec<u8>),Error> {
+        if self.messages != 2 {
+            return Err(Error::Crypto("Noise handshake not finished"))
+        }
+        let (initiator_key, responder_key, secret) = noise_hkdf(&self.state.chaining_key, &[]);
+        let initiator = Crypto::from_key(method, &initiator_key);
+        let responder = Crypto::from_key(method, &responder_key);
+        match self.initiator {
+            true => Ok(((initiator, responder), secret.to_vec())),
+            false => Ok(((responder, initiator), secret.to_vec()))
+        }
+    }
 }
 
 #[test]
@@ -130,17 +714,177 @@ fn encrypt_decrypt_aes256() {
     for i in 0..msg_bytes.len() {
         buffer[i] = msg_bytes[i];
     }
-    let mut nonce1 = [0u8; 12];
-    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce1, &header);
-    dbg!(nonce1);
+    let counter = sender.next_counter();
+    let size = sender.seal(counter, &mut buffer, msg_bytes.len(), &header);
+    let nonce1 = sender.nonce_from_counter(counter);
     assert_eq!(size, msg_bytes.len() + sender.additional_bytes());
     assert!(msg_bytes != &buffer[..msg_bytes.len()] as &[u8]);
     receiver.decrypt(&mut buffer[..size], &nonce1, &header).unwrap();
     assert_eq!(msg_bytes, &buffer[..msg_bytes.len()] as &[u8]);
-    let mut nonce2 = [0u8; 12];
-    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce2, &header);
-    // assert!(nonce1 != nonce2);
-    dbg!(nonce2);
-    receiver.decrypt(&mut buffer[..size], &nonce1, &header).unwrap();
+    for i in 0..msg_bytes.len() {
+        buffer[i] = msg_bytes[i];
+    }
+    let counter = sender.next_counter();
+    let size = sender.seal(counter, &mut buffer, msg_bytes.len(), &header);
+    let nonce2 = sender.nonce_from_counter(counter);
+    assert!(nonce1 != nonce2);
+    receiver.decrypt(&mut buffer[..size], &nonce2, &header).unwrap();
     assert_eq!(msg_bytes, &buffer[..msg_bytes.len()] as &[u8]);
 }
+
+#[test]
+fn parse_cipher_list() {
+    assert_eq!(CryptoMethod::parse_list("chacha20").unwrap(), vec![CryptoMethod::ChaCha20]);
+    assert_eq!(CryptoMethod::parse_list("aes256, chacha20,aes256").unwrap(), vec![CryptoMethod::AES256, CryptoMethod::ChaCha20]);
+    assert!(CryptoMethod::parse_list("").is_err());
+    assert!(CryptoMethod::parse_list("aes128").is_err());
+}
+
+#[test]
+fn replay_window_reorder() {
+    let mut window = ReplayWindow::new();
+    for &counter in &[1u64, 3, 2, 5, 4, 10, 6] {
+        assert!(window.check(counter));
+        window.update(counter);
+    }
+    for &counter in &[7u64, 8, 9] {
+        assert!(window.check(counter));
+    }
+    window.update(2000);
+    assert!(window.check(2000 - REPLAY_WINDOW + 1));
+    assert!(!window.check(2000 - REPLAY_WINDOW));
+    assert!(window.check(1999));
+}
+
+#[test]
+fn replay_window_replay() {
+    let mut window = ReplayWindow::new();
+    for counter in 1..100u64 {
+        window.update(counter);
+    }
+    for counter in 1..100u64 {
+        assert!(!window.check(counter));
+    }
+    window.update(REPLAY_WINDOW + 6);
+    assert!(!window.check(REPLAY_WINDOW + 6));
+    assert!(!window.check(99));
+    // Slots left behind by counters 1..5 now belong to newer counters.
+    assert!(window.check(REPLAY_WINDOW + 2));
+    assert!(window.check(100));
+    window.update(3 * REPLAY_WINDOW);
+    assert!(!window.check(REPLAY_WINDOW + 6));
+}
+
+#[test]
+fn key_exchange() {
+    let psk = Crypto::from_shared_key(CryptoMethod::AES256, "test");
+    let client = KeyExchange::new().unwrap();
+    let server = KeyExchange::new().unwrap();
+    let client_public_key = client.public_key().to_vec();
+    let server_public_key = server.public_key().to_vec();
+    let (mut client_sender, client_receiver) = client.derive(CryptoMethod::AES256, psk.get_key(), &server_public_key, true).unwrap();
+    let (mut server_sender, server_receiver) = server.derive(CryptoMethod::AES256, psk.get_key(), &client_public_key, false).unwrap();
+    assert!(client_sender.get_key() != psk.get_key());
+    assert!(client_sender.get_key() != client_receiver.get_key());
+    assert_eq!(client_sender.get_key(), server_receiver.get_key());
+    assert_eq!(server_sender.get_key(), client_receiver.get_key());
+
+    let other = Crypto::from_shared_key(CryptoMethod::AES256, "other");
+    let (_, mismatched_receiver) = KeyExchange::new().unwrap().derive(CryptoMethod::AES256, other.get_key(), &client_public_key, false).unwrap();
+    let mut buffer = [0u8; 64];
+    let counter = client_sender.next_counter();
+    let size = client_sender.seal(counter, &mut buffer, 16, &[]);
+    let nonce = client_sender.nonce_from_counter(counter);
+    let mut copy = buffer;
+    assert!(mismatched_receiver.decrypt(&mut copy[..size], &nonce, &[]).is_err());
+    server_receiver.decrypt(&mut buffer[..size], &nonce, &[]).unwrap();
+    let counter = server_sender.next_counter();
+    let size = server_sender.seal(counter, &mut buffer, 16, &[]);
+    client_receiver.decrypt(&mut buffer[..size], &server_sender.nonce_from_counter(counter), &[]).unwrap();
+}
+
+#[test]
+fn shared_key_parse() {
+    assert_eq!(SharedKey::parse("secret").unwrap(), SharedKey::Passphrase("secret".to_string()));
+    let encoded = format!("base64:{}", base64::encode(&[1u8; PSK_LEN]));
+    assert_eq!(SharedKey::parse(&encoded).unwrap(), SharedKey::Key(vec![1; PSK_LEN]));
+    assert!(SharedKey::parse("base64:AQID").is_err());
+    assert!(SharedKey::parse("base64:!!").is_err());
+    assert!(SharedKey::parse("").is_err());
+    assert_eq!(format!("{:?}", SharedKey::parse("secret").unwrap()), "Passphrase(..)");
+    // A raw key and its passphrase spelling are different keys.
+    let mut sender = Crypto::from_psk(CryptoMethod::AES256, &SharedKey::Key(vec![1; PSK_LEN]));
+    let receiver = Crypto::from_psk(CryptoMethod::AES256, &SharedKey::Key(vec![1; PSK_LEN]));
+    let other = Crypto::from_psk(CryptoMethod::AES256, &SharedKey::Passphrase(encoded));
+    let header = [0u8; 8];
+    let mut buffer = [0u8; 64];
+    buffer[..5].copy_from_slice(b"hello");
+    let counter = sender.next_counter();
+    let size = sender.seal(counter, &mut buffer, 5, &header);
+    let nonce = sender.nonce_from_counter(counter);
+    let mut copy = buffer;
+    assert!(other.decrypt(&mut copy[..size], &nonce, &header).is_err());
+    receiver.decrypt(&mut buffer[..size], &nonce, &header).unwrap();
+    assert_eq!(&buffer[..5], b"hello");
+}
+
+#[test]
+fn static_keys() {
+    let private_key = PrivateKey::generate().unwrap();
+    let parsed = PrivateKey::parse(&format!("{}\n", private_key.to_base64())).unwrap();
+    assert_eq!(parsed.public_key(), private_key.public_key());
+    let public_key = private_key.public_key();
+    assert_eq!(PublicKey::parse(&public_key.to_string()).unwrap(), public_key);
+    assert!(PublicKey::parse("AQID").is_err());
+    assert!(PrivateKey::parse("not base64!").is_err());
+    assert!(!format!("{:?}", private_key).contains(&private_key.to_base64()));
+    // A low order point would make the shared secret predictable.
+    assert!(private_key.agree(&PublicKey([0; KEY_LEN])).is_err());
+}
+
+#[test]
+fn noise_handshake() {
+    let client_key = PrivateKey::generate().unwrap();
+    let server_key = PrivateKey::generate().unwrap();
+    let mut client = NoiseHandshake::initiator(&client_key, &server_key.public_key());
+    let mut server = NoiseHandshake::responder(&server_key);
+    assert!(client.read_message(&[0; 100]).is_err());
+
+    let hello = client.write_message(b"hello").unwrap();
+    // A corrupted message is refused without spoiling the handshake.
+    let mut corrupted = hello.clone();
+    corrupted[KEY_LEN + 1] ^= 1;
+    assert!(server.read_message(&corrupted).is_err());
+    assert!(server.read_message(&hello[..KEY_LEN]).is_err());
+    assert_eq!(server.read_message(&hello).unwrap(), b"hello".to_vec());
+    assert_eq!(server.remote_static(), Some(client_key.public_key()));
+    assert!(server.clone().split(CryptoMethod::AES256).is_err());
+
+    let reply = server.write_message(b"welcome").unwrap();
+    assert_eq!(client.read_message(&reply).unwrap(), b"welcome".to_vec());
+    assert!(client.write_message(b"again").is_err());
+
+    let ((mut client_sender, client_receiver), client_secret) = client.split(CryptoMethod::AES256).unwrap();
+    let ((mut server_sender, server_receiver), server_secret) = server.split(CryptoMethod::AES256).unwrap();
+    assert_eq!(client_secret, server_secret);
+    let header = [0u8; 8];
+    for (sender, receiver) in vec![(&mut client_sender, &server_receiver), (&mut server_sender, &client_receiver)] {
+        let mut buffer = [0u8; 64];
+        buffer[..4].copy_from_slice(b"data");
+        let counter = sender.next_counter();
+        let size = sender.seal(counter, &mut buffer, 4, &header);
+        receiver.decrypt(&mut buffer[..size], &sender.nonce_from_counter(counter), &header).unwrap();
+        assert_eq!(&buffer[..4], b"data");
+    }
+}
+
+#[test]
+fn noise_handshake_wrong_server() {
+    let client_key = PrivateKey::generate().unwrap();
+    let server_key = PrivateKey::generate().unwrap();
+    let other_key = PrivateKey::generate().unwrap();
+    let mut client = NoiseHandshake::initiator(&client_key, &other_key.public_key());
+    let mut server = NoiseHandshake::responder(&server_key);
+    let hello = client.write_message(b"hello").unwrap();
+    assert!(server.read_message(&hello).is_err());
+}
@@ -3,86 +3,251 @@ use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path;
 use std::fs;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::IpAddr;
 use std::io::{Write, Read};
-use std::ffi::CString;
-use std::os::raw::c_char;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+
+use crate::netlink;
+use crate::types::Error;
 
 const IFNAMESIZE: usize = 16;
+/// Default MTU of a TUN device. A sealed packet of this size still fits a
+/// 1500 byte path over IPv6 and UDP: 1500 - 40 - 8 - 16 byte header - 16
+/// byte tag.
+pub const MTU: u32 = 1420;
+/// Bounds of `--mtu`. The packet buffers leave no room for larger ones.
+pub const MIN_MTU: u32 = 576;
+pub const MAX_MTU: u32 = 1500;
+/// The Ethernet header a TAP device's frames carry on top of its MTU.
+const ETHERNET_HEADER_LEN: u32 = 14;
+
+// From linux/if_tun.h.
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_MULTI_QUEUE: libc::c_short = 0x0100;
+const TUNSETIFF: u32 = tun_ioctl(202);
+const TUNSETPERSIST: u32 = tun_ioctl(203);
+const TUNSETOWNER: u32 = tun_ioctl(204);
+const TUNSETGROUP: u32 = tun_ioctl(206);
 
+/// `_IOW('T', nr, int)`, the direction bits differ between architectures.
+const fn tun_ioctl(nr: u32) -> u32 {
+    #[cfg(any(target_arch = "mips", target_arch = "mips64", target_arch = "powerpc", target_arch = "powerpc64", target_arch = "sparc64"))]
+    const IOC_WRITE: u32 = 4 << 29;
+    #[cfg(not(any(target_arch = "mips", target_arch = "mips64", target_arch = "powerpc", target_arch = "powerpc64", target_arch = "sparc64")))]
+    const IOC_WRITE: u32 = 1 << 30;
+    IOC_WRITE | (mem::size_of::<libc::c_int>() as u32) << 16 | (b'T' as u32) << 8 | nr
+}
 
-extern {
-    fn setup_tap_device(fd: i32, ifname: *mut u8) -> i32;
-    fn setup_tun_device(fd: i32, ifname: *mut u8) -> i32;
-    fn up_device(ifname: *mut u8) -> i32;
-    fn set_ip(ifname: *mut u8,ip: *const c_char,netmask: *const c_char) -> i32;
+/// The part of struct ifreq TUNSETIFF looks at.
+#[repr(C)]
+struct IfReq {
+    name: [u8; IFNAMESIZE],
+    flags: libc::c_short,
+    /// The rest of the union in struct ifreq.
+    _pad: [u8; 22]
 }
 
-// #[derive(Serialize, Deserialize, Debug)]
+/// The prefix length of a contiguous IPv4 netmask.
+fn prefix(netmask: IpAddr) -> io::Result<u8> {
+    match netmask {
+        IpAddr::V4(netmask) if u32::from(netmask).leading_ones() + u32::from(netmask).trailing_zeros() == 32 => {
+            Ok(u32::from(netmask).leading_ones() as u8)
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid netmask {}", netmask)))
+    }
+}
+
+/// What the tunnel carries: IP packets through a TUN device, or Ethernet
+/// frames through a TAP device.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum Type {
     Tun,
     Tap
 }
 
+impl Type {
+    /// Name of the device we create unless told otherwise, the kernel
+    /// replaces `%d` with the first free number.
+    pub fn ifname(&self) -> &'static str {
+        match *self {
+            Type::Tun => "tun%d",
+            Type::Tap => "tap%d"
+        }
+    }
+
+    /// MTU of the device unless told otherwise. A TAP device's frames come
+    /// with an Ethernet header, which goes through the tunnel too.
+    pub fn default_mtu(&self) -> u32 {
+        match *self {
+            Type::Tun => MTU,
+            Type::Tap => MTU - ETHERNET_HEADER_LEN
+        }
+    }
+}
+
+/// Checks `name` fits a device name.
+pub fn check_name(name: &str) -> Result<(),Error> {
+    if name.is_empty() || name.len() >= IFNAMESIZE || name.contains('/') || name.contains(char::is_whitespace) {
+        return Err(Error::Config(format!("invalid device name '{}', expected 1 to {} characters without '/' or spaces", name, IFNAMESIZE - 1)))
+    }
+    Ok(())
+}
+
+/// Checks `mtu` is one we can handle.
+pub fn check_mtu(mtu: u32) -> Result<(),Error> {
+    if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+        return Err(Error::Config(format!("invalid mtu {}, expected {} to {}", mtu, MIN_MTU, MAX_MTU)))
+    }
+    Ok(())
+}
+
+impl FromStr for Type {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Type,Error> {
+        match s {
+            "tun" => Ok(Type::Tun),
+            "tap" => Ok(Type::Tap),
+            _ => Err(Error::Config(format!("unknown mode '{}', expected tun or tap", s)))
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Type::Tun => write!(formatter, "tun"),
+            Type::Tap => write!(formatter, "tap")
+        }
+    }
+}
+
 pub struct Tuntap {
     if_fs: fs::File,
     if_name: String,
+    if_index: u32,
     type_device: Type,
 }
 
 impl Tuntap {
     pub fn create(ifname: &str,type_device: Type,path_device: Option<&path::Path>) -> Result<Tuntap,io::Error> {
+        Tuntap::open(ifname, type_device, path_device, 0)
+    }
+    /// Like `create`, but the device takes more queues, see `queue`.
+    pub fn create_multi_queue(ifname: &str,type_device: Type) -> Result<Tuntap,io::Error> {
+        Tuntap::open(ifname, type_device, None, IFF_MULTI_QUEUE)
+    }
+    /// Opens another queue of a device made by `create_multi_queue`. The
+    /// kernel spreads the packets it sends out of the device over the
+    /// queues by flow, so each can be read on a thread of its own.
+    pub fn queue(&self) -> Result<Tuntap,io::Error> {
+        Tuntap::open(&self.if_name, self.type_device, None, IFF_MULTI_QUEUE)
+    }
+    fn open(ifname: &str,type_device: Type,path_device: Option<&path::Path>,flags: libc::c_short) -> Result<Tuntap,io::Error> {
         let path_device = path_device.unwrap_or_else(|| path::Path::new("/dev/net/tun"));
-        let if_fs = fs::OpenOptions::new().read(true).write(true).open(path_device).expect("open tun failed");
-        let name = format!("{}",ifname);
-        let mut buf = [0u8;IFNAMESIZE];
-        buf[0..name.len()].clone_from_slice(name.as_bytes());
-        let result = match type_device {
-            Type::Tun => unsafe{ setup_tun_device(if_fs.as_raw_fd(), buf.as_mut_ptr())},
-            Type::Tap => unsafe{ setup_tap_device(if_fs.as_raw_fd(), buf.as_mut_ptr())}
-        };
-        match result {
-            0 => {
-                let size = buf.iter().position(|&r| r == 0).unwrap();
-                Ok(Self{
-                    if_fs: if_fs,
-                    if_name: String::from_utf8(buf[..size].to_vec()).unwrap(),
-                    type_device: type_device,
-                })
+        let if_fs = fs::OpenOptions::new().read(true).write(true).open(path_device)?;
+        if ifname.len() >= IFNAMESIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("device name {} is too long", ifname)))
+        }
+        let mut req = IfReq {
+            name: [0u8; IFNAMESIZE],
+            flags: flags | match type_device {
+                Type::Tun => IFF_TUN | IFF_NO_PI,
+                Type::Tap => IFF_TAP | IFF_NO_PI
             },
-            _ => Err(io::Error::last_os_error())
+            _pad: [0u8; 22]
+        };
+        req.name[..ifname.len()].copy_from_slice(ifname.as_bytes());
+        if unsafe { libc::ioctl(if_fs.as_raw_fd(), TUNSETIFF as _, &mut req) } < 0 {
+            return Err(io::Error::last_os_error())
         }
+        // The kernel filled in the name if it was a template.
+        let size = req.name.iter().position(|&r| r == 0).unwrap_or(IFNAMESIZE);
+        let if_name = String::from_utf8_lossy(&req.name[..size]).into_owned();
+        Ok(Self{
+            if_fs: if_fs,
+            if_index: netlink::index(&if_name)?,
+            if_name: if_name,
+            type_device: type_device,
+        })
     }
     pub fn ifname(&self) -> String {
         self.if_name.clone()
     }
     pub fn up(&self) -> Result<(),io::Error>{
-        let name = format!("{}",self.if_name);
-        let mut buf = [0u8;IFNAMESIZE];
-        buf[0..name.len()].clone_from_slice(name.as_bytes());
-        let err = unsafe{
-            up_device(buf.as_mut_ptr())
-        };
-        match err {
-            1 => Ok(()),
-            _ => Err(io::Error::last_os_error())
+        netlink::set_up(self.if_index)
+    }
+    fn ioctl(&self,request: u32,arg: libc::c_ulong) -> Result<(),io::Error>{
+        match unsafe { libc::ioctl(self.if_fs.as_raw_fd(), request as _, arg) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(())
         }
     }
-    pub fn set_ip(&self,ip: &str,netmask: &str) -> Result<(),io::Error>{
-        let name = format!("{}",self.if_name);
-        let mut buf = [0u8;IFNAMESIZE];
-        buf[0..name.len()].clone_from_slice(name.as_bytes());
-        let mut ifname = buf;
-        let ip_addr = CString::new(ip).expect("Cstring failed");
-        let netmask = CString::new(netmask).expect("Cstring failed");
-        let err = unsafe {
-            set_ip(ifname.as_mut_ptr(), ip_addr.as_ptr(), netmask.as_ptr())
+    /// Keeps the device when we close it, `create` attaches to it again.
+    pub fn set_persist(&self,persist: bool) -> Result<(),io::Error>{
+        self.ioctl(TUNSETPERSIST, persist as libc::c_ulong)
+    }
+    /// Lets the user `uid` attach to the device once it persists.
+    pub fn set_owner(&self,uid: u32) -> Result<(),io::Error>{
+        self.ioctl(TUNSETOWNER, uid as libc::c_ulong)
+    }
+    /// Lets the members of group `gid` attach to the device once it persists.
+    pub fn set_group(&self,gid: u32) -> Result<(),io::Error>{
+        self.ioctl(TUNSETGROUP, gid as libc::c_ulong)
+    }
+    /// Makes reads fail with `WouldBlock` instead of waiting for a packet,
+    /// to drain the device in bursts.
+    pub fn set_nonblocking(&self,nonblocking: bool) -> Result<(),io::Error>{
+        let fd = self.if_fs.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error())
+        }
+        let flags = match nonblocking {
+            true => flags | libc::O_NONBLOCK,
+            false => flags & !libc::O_NONBLOCK
         };
-        match err {
-            1 => Ok(()),
-            _ => Err(io::Error::last_os_error())
+        match unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(())
         }
     }
+    pub fn set_mtu(&self,mtu: u32) -> Result<(),io::Error>{
+        netlink::set_mtu(self.if_index, mtu)
+    }
+    /// Brings the device up with `ip` as its only IPv4 address.
+    pub fn set_ip(&self,ip: IpAddr,netmask: IpAddr) -> Result<(),io::Error>{
+        let prefix = prefix(netmask)?;
+        self.up()?;
+        let mut present = false;
+        for (address, address_prefix) in netlink::addresses(self.if_index)? {
+            match (address, address_prefix) {
+                (IpAddr::V4(_), _) if (address, address_prefix) == (ip, prefix) => present = true,
+                (IpAddr::V4(_), _) => netlink::delete_address(self.if_index, address, address_prefix)?,
+                _ => ()
+            }
+        }
+        match present {
+            true => Ok(()),
+            false => netlink::add_address(self.if_index, ip, prefix)
+        }
+    }
+    /// Adds an IPv6 address. Unlike the IPv4 one it does not replace the
+    /// addresses the device already has, see `del_ip6`. A persistent device
+    /// may have the address from before, which is fine.
+    pub fn set_ip6(&self,ip: IpAddr,prefix: u8) -> Result<(),io::Error>{
+        match netlink::add_address(self.if_index, ip, prefix) {
+            Err(ref e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            result => result
+        }
+    }
+    pub fn del_ip6(&self,ip: IpAddr,prefix: u8) -> Result<(),io::Error>{
+        netlink::delete_address(self.if_index, ip, prefix)
+    }
 }
 
 
@@ -111,33 +276,100 @@ impl Write for Tuntap {
 #[cfg(test)]
 mod tests {
     use crate::device::*;
+    use crate::netlink;
     use crate::utils::*;
-    use std::process;
-    use std::{thread, time};
     #[test]
     fn create_tun_test() {
         assert!(is_root());
         let tun = Tuntap::create("tun1", Type::Tun, None).unwrap();
-        let name = tun.if_name;
-        let output = process::Command::new("ifconfig")
-            .arg(name)
-            .output()
-            .expect("failed to create tun device");
-        assert!(output.status.success());
+        // ARPHRD_NONE
+        let kind = fs::read_to_string(format!("/sys/class/net/{}/type", tun.ifname())).unwrap();
+        assert_eq!(kind.trim(), "65534");
+        assert_eq!(tun.if_index, netlink::index("tun1").unwrap());
+    }
+    #[test]
+    fn create_tap_test() {
+        assert!(is_root());
+        let tap = Tuntap::create("tap2", Type::Tap, None).unwrap();
+        // ARPHRD_ETHER
+        let kind = fs::read_to_string(format!("/sys/class/net/{}/type", tap.ifname())).unwrap();
+        assert_eq!(kind.trim(), "1");
+        assert_eq!("tap".parse::<Type>().unwrap(), Type::Tap);
+        assert!("tan".parse::<Type>().is_err());
+    }
+    #[test]
+    fn set_mtu_test() {
+        assert!(is_root());
+        let tun = Tuntap::create("tun4", Type::Tun, None).unwrap();
+        tun.set_mtu(1280).unwrap();
+        let mtu = fs::read_to_string("/sys/class/net/tun4/mtu").unwrap();
+        assert_eq!(mtu.trim(), "1280");
+        assert!(check_mtu(MTU).is_ok());
+        assert!(check_mtu(9000).is_err());
+        assert!(check_name("tun%d").is_ok());
+        assert!(check_name("a-very-long-name").is_err());
+        assert!(check_name("../tun").is_err());
+    }
+    #[test]
+    fn multi_queue_test() {
+        assert!(is_root());
+        let tun = Tuntap::create_multi_queue("tun7", Type::Tun).unwrap();
+        let queue = tun.queue().unwrap();
+        assert_eq!(queue.ifname(), "tun7");
+        assert_eq!(queue.if_index, tun.if_index);
+        let flags = fs::read_to_string("/sys/class/net/tun7/tun_flags").unwrap();
+        assert_eq!(u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).unwrap() & IFF_MULTI_QUEUE as u32, IFF_MULTI_QUEUE as u32);
+        // A device of one queue takes no more.
+        let single = Tuntap::create("tun8", Type::Tun, None).unwrap();
+        assert!(single.queue().is_err());
+    }
+    #[test]
+    fn nonblocking_test() {
+        assert!(is_root());
+        let mut tun = Tuntap::create("tun9", Type::Tun, None).unwrap();
+        tun.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 1600];
+        assert_eq!(tun.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+    #[test]
+    fn persist_test() {
+        assert!(is_root());
+        let tun = Tuntap::create("tun5", Type::Tun, None).unwrap();
+        tun.set_persist(true).unwrap();
+        tun.set_owner(65534).unwrap();
+        drop(tun);
+        assert!(path::Path::new("/sys/class/net/tun5").exists());
+        let owner = fs::read_to_string("/sys/class/net/tun5/owner").unwrap();
+        assert_eq!(owner.trim(), "65534");
+        let tun = Tuntap::create("tun5", Type::Tun, None).unwrap();
+        tun.set_persist(false).unwrap();
+        drop(tun);
+        assert!(!path::Path::new("/sys/class/net/tun5").exists());
+    }
+    fn ipv4(tun: &Tuntap) -> Vec<(IpAddr,u8)> {
+        netlink::addresses(tun.if_index).unwrap().into_iter().filter(|&(ip, _)| ip.is_ipv4()).collect()
     }
     #[test]
     fn set_ip_test() {
         assert!(is_root());
         let tun = Tuntap::create("tun2", Type::Tun, None).unwrap();
-        let ip = format!("{}","192.168.1.2");
-        let netmask = format!("{}","255.255.255.0");
-        tun.set_ip(&ip,&netmask).unwrap();
-        let name = tun.ifname();
-        let output = process::Command::new("ifconfig")
-            .arg(name)
-            .output()
-            .expect("failed to create tun device");
-        assert!(output.status.success());
-        assert!(String::from_utf8_lossy(&output.stdout).contains("192.168.1.2"));
+        tun.set_ip("192.168.1.2".parse().unwrap(), "255.255.255.0".parse().unwrap()).unwrap();
+        assert_eq!(ipv4(&tun), vec![("192.168.1.2".parse().unwrap(), 24)]);
+        // A new address replaces the old one.
+        tun.set_ip("192.168.2.2".parse().unwrap(), "255.255.0.0".parse().unwrap()).unwrap();
+        assert_eq!(ipv4(&tun), vec![("192.168.2.2".parse().unwrap(), 16)]);
+        assert!(tun.set_ip("192.168.1.2".parse().unwrap(), "255.0.255.0".parse().unwrap()).is_err());
+    }
+    #[test]
+    fn set_ip6_test() {
+        assert!(is_root());
+        let tun = Tuntap::create("tun3", Type::Tun, None).unwrap();
+        tun.up().unwrap();
+        let ip6 = "fd00:1::2".parse().unwrap();
+        tun.set_ip6(ip6, 64).unwrap();
+        tun.set_ip6(ip6, 64).unwrap();
+        assert!(netlink::addresses(tun.if_index).unwrap().contains(&(ip6, 64)));
+        tun.del_ip6(ip6, 64).unwrap();
+        assert!(!netlink::addresses(tun.if_index).unwrap().contains(&(ip6, 64)));
     }
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+use ring::digest;
+use std::collections::HashMap;
+
+use crate::boring::SessionId;
+use crate::types::Error;
+use crate::utils;
+
+/// How far the time of a hello may be off ours. Clients whose clock is off
+/// by more are rejected, a captured hello is of no use after this long.
+pub const HELLO_WINDOW: u64 = 10 * 60;
+
+/// What became of a hello seen before.
+enum Answer {
+    /// A worker is still running its handshake.
+    Pending,
+    /// It started this session, and was answered with this response.
+    Session(SessionId,Vec<u8>)
+}
+
+/// What to do with an authenticated hello.
+#[derive(Debug,PartialEq)]
+pub enum Hello {
+    /// Run the handshake.
+    New,
+    /// A copy of a hello that started this session, which the client sends
+    /// again if the response was lost. It gets the same response, the server
+    /// leases no address for it.
+    Answered(SessionId,Vec<u8>),
+    /// A copy of a hello still being handled.
+    Pending
+}
+
+/// The hellos of the last `HELLO_WINDOW`, so each one starts at most one
+/// session. Everyone who saw a hello can send it again, which would
+/// otherwise run another handshake and lease another address each time,
+/// whether or not cookies are asked for. Hellos are told apart by a digest
+/// of the sealed request, which no one without the key can make up, and
+/// carry the time they were sent, so older ones are refused instead of
+/// remembered forever.
+pub struct Hellos {
+    seen: HashMap<Vec<u8>,(u64,Answer)>
+}
+
+/// Digest of a sealed hello, without the cookie MAC.
+pub fn digest(hello: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, hello).as_ref().to_vec()
+}
+
+impl Hellos {
+    pub fn new() -> Self {
+        Hellos {
+            seen: HashMap::new()
+        }
+    }
+
+    /// Checks a hello with `digest` that the client sent at `time`. A new
+    /// one is remembered as pending until `answered` or `failed` is called.
+    pub fn check(&mut self,digest: &[u8],time: u64) -> Result<Hello,Error> {
+        self.check_at(digest, time, utils::unix_time())
+    }
+
+    fn check_at(&mut self,digest: &[u8],time: u64,now: u64) -> Result<Hello,Error> {
+        self.seen.retain(|_, (sent, _)| now <= sent.saturating_add(HELLO_WINDOW));
+        if time.saturating_add(HELLO_WINDOW) < now || time > now.saturating_add(HELLO_WINDOW) {
+            let (ahead, off) = match time > now {
+                true => ("ahead of", time - now),
+                false => ("behind", now - time)
+            };
+            return Err(Error::Rejected(format!("client clock is {} s {} the server's", off, ahead)))
+        }
+        match self.seen.get(digest) {
+            Some((_, Answer::Session(id, response))) => Ok(Hello::Answered(*id, response.clone())),
+            Some((_, Answer::Pending)) => Ok(Hello::Pending),
+            None => {
+                self.seen.insert(digest.to_vec(), (time, Answer::Pending));
+                Ok(Hello::New)
+            }
+        }
+    }
+
+    /// The hello with `digest` started session `id`, answered with `response`.
+    pub fn answered(&mut self,digest: &[u8],id: SessionId,response: &[u8]) {
+        if let Some((_, answer)) = self.seen.get_mut(digest) {
+            *answer = Answer::Session(id, response.to_vec());
+        }
+    }
+
+    /// The handshake of the pending hello with `digest` failed, a copy of
+    /// it is handled as a new one.
+    pub fn failed(&mut self,digest: &[u8]) {
+        if let Some((_, Answer::Pending)) = self.seen.get(digest) {
+            self.seen.remove(digest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hello::*;
+
+    #[test]
+    fn hellos_test() {
+        let now = 1_000_000;
+        let mut hellos = Hellos::new();
+        let first = digest(b"first");
+        assert_eq!(hellos.check_at(&first, now, now).unwrap(), Hello::New);
+        assert_eq!(hellos.check_at(&first, now, now).unwrap(), Hello::Pending);
+        hellos.answered(&first, 7, b"response");
+        assert_eq!(hellos.check_at(&first, now, now + 1).unwrap(), Hello::Answered(7, b"response".to_vec()));
+
+        // A failed handshake may be tried again.
+        let second = digest(b"second");
+        assert_eq!(hellos.check_at(&second, now, now).unwrap(), Hello::New);
+        hellos.failed(&second);
+        assert_eq!(hellos.check_at(&second, now, now).unwrap(), Hello::New);
+        hellos.failed(&first);
+        assert_eq!(hellos.check_at(&first, now, now).unwrap(), Hello::Answered(7, b"response".to_vec()));
+
+        // Too old or too far ahead, and forgotten once out of the window.
+        assert!(hellos.check_at(&digest(b"old"), now - HELLO_WINDOW - 1, now).is_err());
+        assert!(hellos.check_at(&digest(b"ahead"), now + HELLO_WINDOW + 1, now).is_err());
+        assert!(hellos.check_at(&first, now, now + HELLO_WINDOW + 1).is_err());
+        assert!(hellos.seen.is_empty());
+    }
+}
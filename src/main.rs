@@ -1,24 +1,34 @@
-use serde::{Serialize, Deserialize};
 use env_logger;
-use libc;
+use std::process;
 
 mod utils;
 mod crypto;
 mod device;
+mod netlink;
+mod batch;
 mod types;
 mod boring;
+mod pool;
+mod config;
+mod peers;
+mod cookie;
+mod hello;
+mod switch;
 mod client;
 mod server;
 mod cli;
 
 fn main() {
     env_logger::init();
-    if !utils::is_root() {
-        panic!("Please run as root");
+    let result = cli::get_args().and_then(|args| match args {
+        cli::Args::GenKey => cli::genkey(),
+        cli::Args::PubKey => cli::pubkey(),
+        _ if !utils::is_root() => Err(types::Error::Config("please run as root".to_string())),
+        cli::Args::Client(mut client) => client.run(),
+        cli::Args::Server(mut server) => server.run()
+    });
+    if let Err(e) = result {
+        eprintln!("boringvpn: {}", e);
+        process::exit(1);
     }
-    match cli::get_args().unwrap() {
-        cli::Args::Client(mut client) => client.connect_udp().unwrap(),
-        cli::Args::Server(mut server) => server.server_udp().unwrap()
-    }
-    println!("Hello, world!");
 }
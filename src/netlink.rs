@@ -0,0 +1,480 @@
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+
+// From linux/netlink.h and linux/rtnetlink.h, not every libc has them.
+const NETLINK_ROUTE: libc::c_int = 0;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLMSG_HDRLEN: usize = 16;
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_GETADDR: u16 = 22;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_GETROUTE: u16 = 26;
+const IFLA_MTU: u16 = 4;
+const IFLA_MASTER: u16 = 10;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_BROADCAST: u16 = 4;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_TABLE: u16 = 15;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+const IFF_UP: u32 = 0x1;
+/// Large enough for any message the kernel sends us in one go.
+const RECV_BUF_SIZE: usize = 32768;
+
+/// A route of the main table.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Route {
+    pub destination: IpAddr,
+    pub prefix: u8,
+    pub gateway: Option<IpAddr>,
+    /// Index of the device it goes out of.
+    pub device: Option<u32>,
+    pub priority: Option<u32>,
+    pub protocol: u8
+}
+
+impl Route {
+    /// The default route through `gateway`.
+    pub fn default_via(gateway: IpAddr) -> Route {
+        let destination = match gateway {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        Route {
+            destination: destination,
+            prefix: 0,
+            gateway: Some(gateway),
+            device: None,
+            priority: None,
+            protocol: RTPROT_BOOT
+        }
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.prefix {
+            0 => write!(formatter, "default")?,
+            prefix => write!(formatter, "{}/{}", self.destination, prefix)?
+        }
+        if let Some(gateway) = self.gateway {
+            write!(formatter, " via {}", gateway)?;
+        }
+        if let Some(device) = self.device {
+            write!(formatter, " dev {}", ifname(device))?;
+        }
+        if let Some(priority) = self.priority {
+            write!(formatter, " metric {}", priority)?;
+        }
+        Ok(())
+    }
+}
+
+/// A request to the kernel: the netlink header, the fixed header of the
+/// message type and its attributes.
+struct Request {
+    buf: Vec<u8>
+}
+
+impl Request {
+    fn new(kind: u16,flags: u16) -> Request {
+        let mut buf = vec![0u8; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&kind.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags | NLM_F_REQUEST).to_ne_bytes());
+        Request { buf: buf }
+    }
+
+    fn header(mut self,header: &[u8]) -> Request {
+        self.buf.extend_from_slice(header);
+        self
+    }
+
+    fn attr(mut self,kind: u16,data: &[u8]) -> Request {
+        self.buf.extend_from_slice(&(4 + data.len() as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        let padded = align(self.buf.len());
+        self.buf.resize(padded, 0);
+        self
+    }
+
+    /// Sends the request and collects the replies, without their netlink
+    /// headers, until the kernel acknowledges it or finishes the dump.
+    fn send(mut self) -> io::Result<Vec<Vec<u8>>> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        let socket = Socket::open()?;
+        socket.send(&self.buf)?;
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; RECV_BUF_SIZE];
+        loop {
+            let size = socket.recv(&mut buf)?;
+            let mut messages = &buf[..size];
+            while messages.len() >= NLMSG_HDRLEN {
+                let len = u32::from_ne_bytes([messages[0], messages[1], messages[2], messages[3]]) as usize;
+                let kind = u16::from_ne_bytes([messages[4], messages[5]]);
+                if len < NLMSG_HDRLEN || len > messages.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated netlink message"))
+                }
+                let payload = &messages[NLMSG_HDRLEN..len];
+                match kind {
+                    NLMSG_DONE => return Ok(replies),
+                    NLMSG_ERROR if payload.len() >= 4 => {
+                        // Zero acknowledges the request, anything else is a negated errno.
+                        return match i32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]]) {
+                            0 => Ok(replies),
+                            errno => Err(io::Error::from_raw_os_error(-errno))
+                        }
+                    },
+                    _ => replies.push(payload.to_vec())
+                }
+                messages = &messages[align(len).min(messages.len())..];
+            }
+        }
+    }
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A NETLINK_ROUTE socket, closed when dropped.
+struct Socket {
+    fd: RawFd
+}
+
+impl Socket {
+    fn open() -> io::Result<Socket> {
+        match unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, NETLINK_ROUTE) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Socket { fd: fd })
+        }
+    }
+
+    /// An unconnected netlink socket sends to the kernel.
+    fn send(&self,buf: &[u8]) -> io::Result<()> {
+        match unsafe { libc::send(self.fd, buf.as_ptr() as *const libc::c_void, buf.len(), 0) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(())
+        }
+    }
+
+    fn recv(&self,buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) } {
+                -1 => match io::Error::last_os_error() {
+                    ref e if e.kind() == io::ErrorKind::Interrupted => continue,
+                    e => return Err(e)
+                },
+                size => return Ok(size as usize)
+            }
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// The attributes following the fixed header of a reply.
+fn attrs(mut data: &[u8]) -> Vec<(u16,&[u8])> {
+    let mut attrs = Vec::new();
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let kind = u16::from_ne_bytes([data[2], data[3]]);
+        if len < 4 || len > data.len() {
+            break
+        }
+        attrs.push((kind, &data[4..len]));
+        data = &data[align(len).min(data.len())..];
+    }
+    attrs
+}
+
+fn family(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8
+    }
+}
+
+fn octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec()
+    }
+}
+
+fn to_ip(data: &[u8]) -> Option<IpAddr> {
+    match data.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(data);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        },
+        _ => None
+    }
+}
+
+fn to_u32(data: &[u8]) -> Option<u32> {
+    match data.len() {
+        4 => Some(u32::from_ne_bytes([data[0], data[1], data[2], data[3]])),
+        _ => None
+    }
+}
+
+/// The index of device `name`.
+pub fn index(name: &str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "device name contains a NUL byte"))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index)
+    }
+}
+
+/// The name of device `index`, or the index if it is gone.
+fn ifname(index: u32) -> String {
+    let mut buf = [0u8; libc::IF_NAMESIZE];
+    match unsafe { libc::if_indextoname(index, buf.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+        true => index.to_string(),
+        false => {
+            let size = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[..size]).into_owned()
+        }
+    }
+}
+
+/// A struct ifinfomsg changing the flags in `change` to `flags`.
+fn link(index: u32,flags: u32,change: u32) -> Vec<u8> {
+    let mut header = vec![0u8; 8];
+    header[4..8].copy_from_slice(&index.to_ne_bytes());
+    header.extend_from_slice(&flags.to_ne_bytes());
+    header.extend_from_slice(&change.to_ne_bytes());
+    header
+}
+
+pub fn set_up(index: u32) -> io::Result<()> {
+    Request::new(RTM_NEWLINK, NLM_F_ACK).header(&link(index, IFF_UP, IFF_UP)).send()?;
+    Ok(())
+}
+
+pub fn set_mtu(index: u32,mtu: u32) -> io::Result<()> {
+    Request::new(RTM_NEWLINK, NLM_F_ACK).header(&link(index, 0, 0)).attr(IFLA_MTU, &mtu.to_ne_bytes()).send()?;
+    Ok(())
+}
+
+/// Makes device `index` a port of bridge `master`.
+pub fn set_master(index: u32,master: u32) -> io::Result<()> {
+    Request::new(RTM_NEWLINK, NLM_F_ACK).header(&link(index, 0, 0)).attr(IFLA_MASTER, &master.to_ne_bytes()).send()?;
+    Ok(())
+}
+
+/// A struct ifaddrmsg.
+fn address(family: u8,prefix: u8,index: u32) -> Vec<u8> {
+    let mut header = vec![family, prefix, 0, RT_SCOPE_UNIVERSE];
+    header.extend_from_slice(&index.to_ne_bytes());
+    header
+}
+
+/// The addresses of device `index` and their prefix lengths.
+pub fn addresses(index: u32) -> io::Result<Vec<(IpAddr,u8)>> {
+    let replies = Request::new(RTM_GETADDR, NLM_F_DUMP).header(&address(libc::AF_UNSPEC as u8, 0, 0)).send()?;
+    let mut addresses = Vec::new();
+    for reply in replies.iter().filter(|reply| reply.len() >= 8) {
+        if to_u32(&reply[4..8]) != Some(index) {
+            continue
+        }
+        let attrs = attrs(&reply[8..]);
+        // The local address, the peer's of a point to point link is in IFA_ADDRESS.
+        let ip = attrs.iter().find(|&&(kind, _)| kind == IFA_LOCAL)
+            .or_else(|| attrs.iter().find(|&&(kind, _)| kind == IFA_ADDRESS))
+            .and_then(|&(_, data)| to_ip(data));
+        if let Some(ip) = ip {
+            addresses.push((ip, reply[1]));
+        }
+    }
+    Ok(addresses)
+}
+
+/// Adds `ip/prefix` to device `index`. An IPv4 address gets the broadcast
+/// address of its network too.
+pub fn add_address(index: u32,ip: IpAddr,prefix: u8) -> io::Result<()> {
+    let mut request = Request::new(RTM_NEWADDR, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL)
+        .header(&address(family(ip), prefix, index))
+        .attr(IFA_LOCAL, &octets(ip))
+        .attr(IFA_ADDRESS, &octets(ip));
+    if let IpAddr::V4(ip) = ip {
+        if prefix < 31 {
+            let broadcast = u32::from(ip) | (!0u32 >> prefix);
+            request = request.attr(IFA_BROADCAST, &Ipv4Addr::from(broadcast).octets());
+        }
+    }
+    request.send()?;
+    Ok(())
+}
+
+pub fn delete_address(index: u32,ip: IpAddr,prefix: u8) -> io::Result<()> {
+    Request::new(RTM_DELADDR, NLM_F_ACK)
+        .header(&address(family(ip), prefix, index))
+        .attr(IFA_LOCAL, &octets(ip))
+        .send()?;
+    Ok(())
+}
+
+/// A struct rtmsg.
+fn route_header(family: u8,prefix: u8,protocol: u8,scope: u8,kind: u8) -> Vec<u8> {
+    vec![family, prefix, 0, 0, RT_TABLE_MAIN, protocol, scope, kind, 0, 0, 0, 0]
+}
+
+fn parse_route(reply: &[u8]) -> Option<(Route,u32,u8)> {
+    if reply.len() < 12 {
+        return None
+    }
+    let attrs = attrs(&reply[12..]);
+    let attr = |wanted: u16| attrs.iter().find(|&&(kind, _)| kind == wanted).map(|&(_, data)| data);
+    let destination = match attr(RTA_DST).and_then(to_ip) {
+        Some(destination) => destination,
+        None if reply[0] == libc::AF_INET as u8 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        None => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    let route = Route {
+        destination: destination,
+        prefix: reply[1],
+        gateway: attr(RTA_GATEWAY).and_then(to_ip),
+        device: attr(RTA_OIF).and_then(to_u32),
+        priority: attr(RTA_PRIORITY).and_then(to_u32),
+        protocol: reply[5]
+    };
+    let table = attr(RTA_TABLE).and_then(to_u32).unwrap_or(u32::from(reply[4]));
+    Some((route, table, reply[7]))
+}
+
+/// The route the kernel picks for packets to `ip`, as a host route.
+pub fn route_to(ip: IpAddr) -> io::Result<Route> {
+    let full = match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128
+    };
+    let replies = Request::new(RTM_GETROUTE, NLM_F_ACK)
+        .header(&route_header(family(ip), full, 0, 0, 0))
+        .attr(RTA_DST, &octets(ip))
+        .send()?;
+    match replies.first().and_then(|reply| parse_route(reply)) {
+        Some((route, _, _)) => Ok(Route {
+            destination: ip,
+            prefix: full,
+            priority: None,
+            protocol: RTPROT_BOOT,
+            ..route
+        }),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "no route in the reply"))
+    }
+}
+
+/// The IPv4 or IPv6 default route of the main table, the preferred one if
+/// there are several.
+pub fn default_route(ipv6: bool) -> io::Result<Option<Route>> {
+    let family = if ipv6 { libc::AF_INET6 } else { libc::AF_INET } as u8;
+    let replies = Request::new(RTM_GETROUTE, NLM_F_DUMP).header(&route_header(family, 0, 0, 0, 0)).send()?;
+    let default = replies.iter()
+        .filter_map(|reply| parse_route(reply))
+        .filter(|&(ref route, table, kind)| route.prefix == 0 && table == u32::from(RT_TABLE_MAIN) && kind == RTN_UNICAST)
+        .map(|(route, _, _)| route)
+        .min_by_key(|route| route.priority.unwrap_or(0));
+    Ok(default)
+}
+
+fn route_request(kind: u16,flags: u16,route: &Route) -> Request {
+    // Only a route through a gateway reaches beyond the link.
+    let scope = match (route.gateway, route.destination) {
+        (None, IpAddr::V4(_)) => RT_SCOPE_LINK,
+        _ => RT_SCOPE_UNIVERSE
+    };
+    let mut request = Request::new(kind, NLM_F_ACK | flags)
+        .header(&route_header(family(route.destination), route.prefix, route.protocol, scope, RTN_UNICAST));
+    if route.prefix > 0 {
+        request = request.attr(RTA_DST, &octets(route.destination));
+    }
+    if let Some(gateway) = route.gateway {
+        request = request.attr(RTA_GATEWAY, &octets(gateway));
+    }
+    if let Some(device) = route.device {
+        request = request.attr(RTA_OIF, &device.to_ne_bytes());
+    }
+    if let Some(priority) = route.priority {
+        request = request.attr(RTA_PRIORITY, &priority.to_ne_bytes());
+    }
+    request
+}
+
+/// Adds `route`, with `replace` in place of one to the same destination
+/// and of the same priority.
+pub fn add_route(route: &Route,replace: bool) -> io::Result<()> {
+    let flags = match replace {
+        true => NLM_F_CREATE | NLM_F_REPLACE,
+        false => NLM_F_CREATE | NLM_F_EXCL
+    };
+    route_request(RTM_NEWROUTE, flags, route).send()?;
+    Ok(())
+}
+
+pub fn delete_route(route: &Route) -> io::Result<()> {
+    route_request(RTM_DELROUTE, 0, route).send()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::netlink::*;
+    use crate::device::{Tuntap, Type};
+    use crate::utils::is_root;
+
+    #[test]
+    fn route_test() {
+        assert!(is_root());
+        let lo = route_to("127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(lo.device, Some(index("lo").unwrap()));
+
+        let tun = Tuntap::create("tun6", Type::Tun, None).unwrap();
+        tun.set_ip("10.99.0.1".parse().unwrap(), "255.255.255.0".parse().unwrap()).unwrap();
+        let tun_index = index("tun6").unwrap();
+        let route = Route {
+            destination: "198.51.100.0".parse().unwrap(),
+            prefix: 24,
+            gateway: Some("10.99.0.2".parse().unwrap()),
+            device: Some(tun_index),
+            priority: Some(10),
+            protocol: RTPROT_BOOT
+        };
+        add_route(&route, false).unwrap();
+        assert!(add_route(&route, false).is_err());
+        let found = route_to("198.51.100.7".parse().unwrap()).unwrap();
+        assert_eq!(found.gateway, route.gateway);
+        assert_eq!(found.device, Some(tun_index));
+        delete_route(&route).unwrap();
+        assert_eq!(delete_route(&route).unwrap_err().raw_os_error(), Some(libc::ESRCH));
+    }
+}
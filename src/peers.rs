@@ -0,0 +1,182 @@
+use log::{info,warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::time::{Duration,Instant,SystemTime};
+
+use crate::config::{self,PeerKey};
+use crate::crypto::{Crypto,PublicKey,SharedKey,HANDSHAKE_METHOD};
+use crate::types::Error;
+
+/// How often the peer database is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A client with its own key.
+struct Peer {
+    key: PeerKey,
+    /// The handshake cipher of a pre-shared key.
+    psk: Option<Crypto>,
+    revoked: bool
+}
+
+/// Keys the server accepts handshakes under: the shared key for clients
+/// that send no id, the pre-shared keys of the peer database for those that
+/// do, and its public keys for clients in the static key mode. The database
+/// is read again whenever it changes, so clients can be added and revoked
+/// while the server runs.
+pub struct Peers {
+    shared: Option<Crypto>,
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+    peers: HashMap<String,Peer>,
+    public_keys: HashMap<PublicKey,String>
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// The peers by id, and the ids of those with a public key by that key.
+type Loaded = (HashMap<String,Peer>,HashMap<PublicKey,String>);
+
+fn load(path: &Path) -> Result<Loaded,Error> {
+    let mut peers = HashMap::new();
+    let mut public_keys = HashMap::new();
+    for (id, entry) in config::load_peers(path)? {
+        let key = entry.key().map_err(|e| Error::Config(format!("peer {}: {}", id, e)))?;
+        let psk = match key {
+            PeerKey::Shared(ref key) => Some(Crypto::from_psk(HANDSHAKE_METHOD, key)),
+            PeerKey::Public(public_key) => {
+                if let Some(other) = public_keys.insert(public_key, id.clone()) {
+                    return Err(Error::Config(format!("peers {} and {} have the same public key", other, id)))
+                }
+                None
+            }
+        };
+        peers.insert(id, Peer { key: key, psk: psk, revoked: entry.revoked });
+    }
+    Ok((peers,public_keys))
+}
+
+impl Peers {
+    pub fn new(shared: Option<&SharedKey>,path: Option<&Path>) -> Result<Self,Error> {
+        let mut peers = Peers {
+            shared: shared.map(|key| Crypto::from_psk(HANDSHAKE_METHOD, key)),
+            path: path.map(Path::to_path_buf),
+            modified: None,
+            checked: Instant::now(),
+            peers: HashMap::new(),
+            public_keys: HashMap::new()
+        };
+        if let Some(path) = path {
+            peers.modified = modified(path);
+            let (loaded, public_keys) = load(path)?;
+            peers.peers = loaded;
+            peers.public_keys = public_keys;
+            info!("Loaded {} peers from {}.", peers.peers.len(), path.display());
+        }
+        Ok(peers)
+    }
+
+    /// The key a handshake from client `id` is sealed with and whether the
+    /// client was revoked, `None` for a client we do not know.
+    pub fn key(&mut self,id: Option<&str>) -> Option<(&mut Crypto,bool)> {
+        match id {
+            None => self.shared.as_mut().map(|psk| (psk, false)),
+            Some(id) => self.peers.get_mut(id).and_then(|peer| {
+                let revoked = peer.revoked;
+                peer.psk.as_mut().map(|psk| (psk, revoked))
+            })
+        }
+    }
+
+    /// The id of the client with `public_key` and whether it was revoked.
+    pub fn public(&self,public_key: &PublicKey) -> Option<(String,bool)> {
+        self.public_keys.get(public_key).map(|id| (id.clone(), self.peers[id].revoked))
+    }
+
+    /// Reads the database again if it changed and returns the clients whose
+    /// sessions have to end: those revoked, removed or given another key.
+    /// A database that fails to load leaves the previous one in place.
+    pub fn reload(&mut self) -> Vec<String> {
+        let path = match self.path {
+            Some(ref path) if self.checked.elapsed() >= RELOAD_INTERVAL => path.clone(),
+            _ => return Vec::new()
+        };
+        self.checked = Instant::now();
+        let modified = modified(&path);
+        if modified == self.modified {
+            return Vec::new()
+        }
+        self.modified = modified;
+        let (peers, public_keys) = match load(&path) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                warn!("Failed to reload peers, keeping the previous ones: {}", e);
+                return Vec::new()
+            }
+        };
+        let ended: Vec<String> = self.peers.iter()
+            .filter(|&(id, peer)| peers.get(id).is_none_or(|new| new.revoked || new.key != peer.key))
+            .map(|(id, _)| id.clone())
+            .collect();
+        info!("Reloaded {} peers from {}.", peers.len(), path.display());
+        self.peers = peers;
+        self.public_keys = public_keys;
+        ended
+    }
+
+    /// Time until the database is due to be checked for changes.
+    pub fn next_check(&self) -> Option<Duration> {
+        self.path.as_ref().map(|_| RELOAD_INTERVAL.checked_sub(self.checked.elapsed()).unwrap_or(Duration::from_secs(0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peers::*;
+    use crate::crypto::PrivateKey;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn reload_test() {
+        let path = std::env::temp_dir().join(format!("boringvpn-peers-{}", std::process::id()));
+        let write = |content: &str| {
+            fs::write(&path, content).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        };
+        let desktop = PrivateKey::generate().unwrap().public_key();
+        write(&format!("[laptop]\nkey = \"one\"\n[phone]\nkey = \"two\"\n[tablet]\nkey = \"three\"\n[desktop]\npublic_key = \"{}\"\n", desktop));
+        let mut peers = Peers::new(None, Some(&path)).unwrap();
+        assert!(peers.key(None).is_none());
+        assert!(peers.key(Some("nas")).is_none());
+        assert!(peers.key(Some("desktop")).is_none());
+        assert_eq!(peers.key(Some("laptop")).map(|(_, revoked)| revoked), Some(false));
+        assert_eq!(peers.public(&desktop), Some(("desktop".to_string(), false)));
+        assert_eq!(peers.public(&PrivateKey::generate().unwrap().public_key()), None);
+        // Not due yet.
+        assert!(peers.reload().is_empty());
+
+        write(&format!("[laptop]\nkey = \"one\"\n[phone]\nkey = \"two\"\nrevoked = true\n[tablet]\nkey = \"four\"\n[desktop]\npublic_key = \"{}\"\nrevoked = true\n", desktop));
+        peers.checked -= RELOAD_INTERVAL;
+        peers.modified = None;
+        let mut ended = peers.reload();
+        ended.sort();
+        assert_eq!(ended, vec!["desktop".to_string(), "phone".to_string(), "tablet".to_string()]);
+        assert_eq!(peers.key(Some("phone")).map(|(_, revoked)| revoked), Some(true));
+        assert_eq!(peers.public(&desktop), Some(("desktop".to_string(), true)));
+
+        write(&format!("[laptop]\npublic_key = \"{0}\"\n[desktop]\npublic_key = \"{0}\"\n", desktop));
+        peers.checked -= RELOAD_INTERVAL;
+        peers.modified = None;
+        assert!(peers.reload().is_empty());
+
+        write("[laptop]\nkey = ");
+        peers.checked -= RELOAD_INTERVAL;
+        peers.modified = None;
+        assert!(peers.reload().is_empty());
+        assert!(peers.key(Some("laptop")).is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{HashSet,VecDeque};
+use std::fmt;
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
+use std::str::FromStr;
+
+use crate::types::Error;
+
+/// An IP network in CIDR notation, like `10.8.0.0/16` or `fd00:8::/64`.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8
+}
+
+/// An address as integer, together with its width in bits.
+fn to_bits(ip: IpAddr) -> (u128,u8) {
+    match ip {
+        IpAddr::V4(ipv4) => (u128::from(u32::from(ipv4)), 32),
+        IpAddr::V6(ipv6) => (u128::from(ipv6), 128)
+    }
+}
+
+fn from_bits(bits: u128,width: u8) -> IpAddr {
+    match width {
+        32 => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+        _ => IpAddr::V6(Ipv6Addr::from(bits))
+    }
+}
+
+fn mask(prefix: u8,width: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => (!0u128 >> (128 - width)) & (!0u128 << (width - prefix))
+    }
+}
+
+impl Cidr {
+    /// The network of `ip` with a prefix of `prefix` bits, host bits are cleared.
+    pub fn new(ip: IpAddr,prefix: u8) -> Result<Cidr,Error> {
+        let (bits, width) = to_bits(ip);
+        if prefix > width {
+            return Err(Error::Config(format!("invalid prefix length /{} for {}", prefix, ip)))
+        }
+        Ok(Cidr {
+            network: from_bits(bits & mask(prefix, width), width),
+            prefix: prefix
+        })
+    }
+
+    /// The network of `ip` under `netmask`, which has to be contiguous.
+    pub fn from_netmask(ip: IpAddr,netmask: IpAddr) -> Result<Cidr,Error> {
+        let (bits, width) = to_bits(netmask);
+        let prefix = (!bits << (128 - width)).leading_zeros().min(width as u32) as u8;
+        if ip.is_ipv4() != netmask.is_ipv4() || mask(prefix, width) != bits {
+            return Err(Error::Config(format!("invalid netmask {}", netmask)))
+        }
+        Cidr::new(ip, prefix)
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    fn width(&self) -> u8 {
+        to_bits(self.network).1
+    }
+
+    pub fn netmask(&self) -> IpAddr {
+        from_bits(mask(self.prefix, self.width()), self.width())
+    }
+
+    pub fn contains(&self,ip: IpAddr) -> bool {
+        let (bits, width) = to_bits(ip);
+        width == self.width() && bits & mask(self.prefix, width) == to_bits(self.network).0
+    }
+
+    /// Number of addresses in the network, including the first and last one.
+    /// Saturates for an IPv6 /0.
+    fn size(&self) -> u128 {
+        1u128.checked_shl((self.width() - self.prefix) as u32).unwrap_or(u128::max_value())
+    }
+
+    /// The `n`th address of the network.
+    pub fn nth(&self,n: u128) -> IpAddr {
+        from_bits(to_bits(self.network).0 + n, self.width())
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr,Error> {
+        let invalid = || Error::Config(format!("invalid network '{}', expected an address and prefix length like 10.8.0.0/16", s));
+        let mut parts = s.splitn(2, '/');
+        let ip: IpAddr = parts.next().unwrap().parse().map_err(|_| invalid())?;
+        let prefix: u8 = parts.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+        Cidr::new(ip, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(formatter, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Addresses the server leases to its clients: every address of its network
+/// except the first, the last and the server's own.
+///
+/// Addresses never leased before are handed out first and released ones go
+/// to the back of a queue, so an address stays unused for as long as possible
+/// after its client left and a reconnecting client asking for it usually gets
+/// it back.
+pub struct AddressPool {
+    network: Cidr,
+    server_ip: IpAddr,
+    /// Offset of the next address that was never leased.
+    next: u128,
+    released: VecDeque<IpAddr>,
+    leased: HashSet<IpAddr>
+}
+
+impl AddressPool {
+    pub fn new(network: Cidr,server_ip: IpAddr) -> Result<Self,Error> {
+        if network.width() - network.prefix() < 2 {
+            return Err(Error::Config(format!("address pool {} is too small", network)))
+        }
+        let broadcast = network.nth(network.size() - 1);
+        if !network.contains(server_ip) || server_ip == network.network() || server_ip == broadcast {
+            return Err(Error::Config(format!("server address {} is not a host address of the pool {}", server_ip, network)))
+        }
+        Ok(AddressPool {
+            network: network,
+            server_ip: server_ip,
+            next: 1,
+            released: VecDeque::new(),
+            leased: HashSet::new()
+        })
+    }
+
+    fn is_free(&self,ip: IpAddr) -> bool {
+        self.network.contains(ip)
+            && ip != self.network.nth(0)
+            && ip != self.network.nth(self.network.size() - 1)
+            && ip != self.server_ip
+            && !self.leased.contains(&ip)
+    }
+
+    /// Leases `requested` if it is free, otherwise the next free address.
+    /// Returns `None` once the pool is exhausted.
+    pub fn lease(&mut self,requested: Option<IpAddr>) -> Option<IpAddr> {
+        let mut candidates = requested.into_iter();
+        let ip = loop {
+            let candidate = match candidates.next() {
+                Some(ip) => ip,
+                None if self.next < self.network.size() - 1 => {
+                    self.next += 1;
+                    self.network.nth(self.next - 1)
+                },
+                None => self.released.pop_front()?
+            };
+            if self.is_free(candidate) {
+                break candidate
+            }
+        };
+        self.leased.insert(ip);
+        Some(ip)
+    }
+
+    /// Returns an address to the pool once the session holding it is gone.
+    pub fn release(&mut self,ip: IpAddr) {
+        if self.leased.remove(&ip) {
+            self.released.push_back(ip);
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        std::cmp::min(self.network.size() - 3, usize::max_value() as u128) as usize - self.leased.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pool::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 10, 10, last))
+    }
+
+    #[test]
+    fn cidr_test() {
+        let cidr: Cidr = "10.8.3.7/16".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.8.0.0/16");
+        assert_eq!(cidr.netmask(), "255.255.0.0".parse::<IpAddr>().unwrap());
+        assert!(cidr.contains("10.8.255.254".parse().unwrap()));
+        assert!(!cidr.contains("10.9.0.1".parse().unwrap()));
+        assert_eq!(Cidr::from_netmask(ip(9), "255.255.252.0".parse().unwrap()).unwrap().to_string(), "10.10.8.0/22");
+        assert!(Cidr::from_netmask(ip(9), "255.0.255.0".parse().unwrap()).is_err());
+        assert!("10.8.0.0".parse::<Cidr>().is_err());
+        assert!("10.8.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.8.0/16".parse::<Cidr>().is_err());
+        assert!(Cidr::from_netmask(ip(9), "255.255.255.255".parse().unwrap()).is_ok());
+        assert!(Cidr::from_netmask(ip(9), "0.0.0.0".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn cidr_v6_test() {
+        let cidr: Cidr = "fd00:8::7/64".parse().unwrap();
+        assert_eq!(cidr.to_string(), "fd00:8::/64");
+        assert_eq!(cidr.netmask(), "ffff:ffff:ffff:ffff::".parse::<IpAddr>().unwrap());
+        assert!(cidr.contains("fd00:8::ffff:1".parse().unwrap()));
+        assert!(!cidr.contains("fd00:9::1".parse().unwrap()));
+        assert!(!cidr.contains(ip(1)));
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert_eq!("::/0".parse::<Cidr>().unwrap().size(), u128::max_value());
+    }
+
+    #[test]
+    fn lease_release_test() {
+        let mut pool = AddressPool::new("10.10.10.0/24".parse().unwrap(), ip(1)).unwrap();
+        assert_eq!(pool.available(), 253);
+        assert_eq!(pool.lease(None), Some(ip(2)));
+        assert_eq!(pool.lease(None), Some(ip(3)));
+        pool.release(ip(2));
+        // A released address is handed out last.
+        assert_eq!(pool.lease(None), Some(ip(4)));
+        // Releasing twice or an address never leased does not duplicate it.
+        pool.release(ip(2));
+        pool.release(ip(1));
+        assert_eq!(pool.available(), 251);
+    }
+
+    #[test]
+    fn requested_address_test() {
+        let mut pool = AddressPool::new("10.10.10.0/24".parse().unwrap(), ip(1)).unwrap();
+        assert_eq!(pool.lease(Some(ip(100))), Some(ip(100)));
+        // Taken already, or not a client address of the pool at all.
+        assert_eq!(pool.lease(Some(ip(100))), Some(ip(2)));
+        assert_eq!(pool.lease(Some(ip(1))), Some(ip(3)));
+        assert_eq!(pool.lease(Some(ip(255))), Some(ip(4)));
+        pool.release(ip(100));
+        assert_eq!(pool.lease(Some(ip(100))), Some(ip(100)));
+    }
+
+    #[test]
+    fn exhausted_pool_test() {
+        let mut pool = AddressPool::new("10.10.10.16/29".parse().unwrap(), ip(20)).unwrap();
+        let leased: Vec<IpAddr> = (0..5).map(|_| pool.lease(None).unwrap()).collect();
+        assert_eq!(leased, vec![ip(17), ip(18), ip(19), ip(21), ip(22)]);
+        assert_eq!(pool.lease(None), None);
+        pool.release(ip(19));
+        assert_eq!(pool.lease(None), Some(ip(19)));
+        assert_eq!(pool.lease(None), None);
+    }
+
+    #[test]
+    fn large_pool_test() {
+        let server_ip = "10.8.0.1".parse().unwrap();
+        let mut pool = AddressPool::new("10.8.0.0/16".parse().unwrap(), server_ip).unwrap();
+        assert_eq!(pool.available(), 65533);
+        let leased: HashSet<IpAddr> = (0..3000).map(|_| pool.lease(None).unwrap()).collect();
+        assert_eq!(leased.len(), 3000);
+        assert!(leased.contains(&"10.8.11.185".parse().unwrap()));
+        assert!(AddressPool::new("10.9.0.0/16".parse().unwrap(), server_ip).is_err());
+    }
+
+    #[test]
+    fn v6_pool_test() {
+        let server_ip = "fd00:8::1".parse().unwrap();
+        let mut pool = AddressPool::new("fd00:8::/112".parse().unwrap(), server_ip).unwrap();
+        assert_eq!(pool.available(), 65533);
+        assert_eq!(pool.lease(None), Some("fd00:8::2".parse().unwrap()));
+        let requested = "fd00:8::abcd".parse().unwrap();
+        assert_eq!(pool.lease(Some(requested)), Some(requested));
+        assert!(AddressPool::new("fd00:8::/127".parse().unwrap(), server_ip).is_err());
+    }
+}
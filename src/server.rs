@@ -1,41 +1,523 @@
-use bincode;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::collections::HashMap;
 use std::io::{self,Write,Read};
-use dns_lookup;
 use log::*;
-use bincode::{serialize, deserialize};
 use std::os::unix::io::AsRawFd;
+use std::time::{Duration,Instant};
+use std::path::PathBuf;
+use std::sync::{Arc,Mutex,RwLock};
+use std::thread;
 use mio;
+use net2;
+use net2::unix::UnixUdpBuilderExt;
+use signal_hook::iterator::Signals;
 use rand::{thread_rng, Rng};
-use transient_hashmap::TransientHashMap;
+use rand::rngs::ThreadRng;
 
+use crate::batch;
 use crate::device;
 use crate::utils;
 use crate::boring;
-use crate::crypto::{Crypto,CryptoData,CryptoMethod};
-use crate::types::Error;
-
-type Token = u64;
+use crate::pool::{AddressPool,Cidr};
+use crate::peers::Peers;
+use crate::cookie::{Cookies,COOKIE_THRESHOLD};
+use crate::hello::{self,Hello,Hellos};
+use crate::switch::{Forward,MacTable,Port};
+use crate::crypto::{Crypto,CryptoMethod,KeyExchange,NoiseHandshake,PrivateKey,SharedKey};
+use crate::types::{Error,Proto};
 
 #[derive(Debug,Clone)]
 pub struct Server {
     ip: IpAddr,
     netmask: IpAddr,
+    pool: Option<Cidr>,
+    /// Our IPv6 address and the network IPv6 client addresses are leased
+    /// from, if the tunnel is dual-stack.
+    ip6: Option<(IpAddr,Cidr)>,
     dns: IpAddr,
     host: IpAddr,
-    secret: String,
-    port: u16
-} 
+    /// Key of the clients that send no id.
+    secret: Option<SharedKey>,
+    /// Database of the clients with their own key.
+    peers: Option<PathBuf>,
+    /// Our static key, if clients may authenticate by public key.
+    private_key: Option<PrivateKey>,
+    port: u16,
+    proto: Proto,
+    /// Whether the tunnel carries IP packets or Ethernet frames.
+    mode: device::Type,
+    /// Linux bridge to attach the TAP device to.
+    bridge: Option<String>,
+    /// Name and MTU of the device, if not the default ones.
+    dev: Option<String>,
+    mtu: Option<u32>,
+    /// Whether the device outlives us, and who may attach to it then.
+    persist: bool,
+    owner: Option<u32>,
+    group: Option<u32>,
+    ciphers: Vec<CryptoMethod>,
+    keepalive: Duration,
+    dead_peer_timeout: Duration,
+    /// Hellos per second above which UDP clients have to answer a cookie.
+    cookie_threshold: u32,
+    /// Threads serving UDP clients, each with a queue of the TUN device.
+    threads: usize,
+    /// Datagrams a UDP worker moves per syscall.
+    batch: usize
+}
+
+/// Most threads `--threads` takes, the queues a TUN device has at most.
+const MAX_THREADS: usize = 256;
+/// How long a TCP client has to complete the handshake once connected.
+const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the TCP server stops accepting after accepting failed.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Writes a packet to the device, which takes it whole or not at all. One
+/// it refuses is dropped as the network would.
+fn write_tun(tun: &mut device::Tuntap,data: &[u8]) {
+    if let Err(e) = tun.write(data) {
+        warn!("Failed to write to {}: {}", tun.ifname(), e);
+    }
+}
+
+/// Keys and return path of one connected client. `E` is whatever identifies
+/// the client on the transport: its address for UDP, its connection for TCP.
+struct Session<E> {
+    endpoint: E,
+    /// Id of the client in the peer database, if it has its own key.
+    peer: Option<String>,
+    /// Addresses leased to the client.
+    ip: IpAddr,
+    ip6: Option<IpAddr>,
+    keys: boring::SessionKeys
+}
+
+impl<E> Session<E> {
+    fn addresses(&self) -> impl Iterator<Item=IpAddr> {
+        Some(self.ip).into_iter().chain(self.ip6)
+    }
+
+    /// Opens a packet of this session in place. In TUN mode the IP packet it
+    /// carries is written to the TUN device if its source is an address
+    /// leased to the client, in TAP mode the Ethernet frame is left to the
+    /// switch. Returns what is left to do with the packet, or an error if it
+    /// did not authenticate or the client disconnected.
+    fn forward(&mut self,tun: &mut device::Tuntap,mode: device::Type,packet: &mut [u8],address: &SocketAddr) -> Result<Forwarded,Error> {
+        let (header, len) = self.keys.open(packet)?;
+        let payload = &packet[boring::HEADER_LEN..boring::HEADER_LEN + len];
+        if header.kind == boring::PacketType::Data {
+            if mode == device::Type::Tap {
+                return Ok(Forwarded::Frame(len))
+            }
+            match source(payload) {
+                Some(ip) if self.addresses().any(|leased| leased == ip) => write_tun(tun, payload),
+                _ => warn!("Dropping packet from {} not sourced from {}.", address, self.ip)
+            }
+            return Ok(Forwarded::Reply(None))
+        }
+        match boring::decode(payload).and_then(|msg| self.keys.handle_control(msg)) {
+            Ok(reply) => Ok(Forwarded::Reply(reply)),
+            Err(Error::Disconnected) => Err(Error::Disconnected),
+            Err(e) => {
+                warn!("Invalid message from {}: {}", address, e);
+                Ok(Forwarded::Reply(None))
+            }
+        }
+    }
+
+    /// Returns a rekey request when the session key is due for rotation.
+    fn rekey(&mut self) -> Option<Vec<u8>> {
+        match self.keys.start_rekey() {
+            Ok(rekey) => rekey,
+            Err(e) => {
+                warn!("Failed to start rekey: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// What is left to do with a packet `Session::forward` opened.
+enum Forwarded {
+    /// Send back the reply, if there is one.
+    Reply(Option<Vec<u8>>),
+    /// Switch the Ethernet frame of this length following the header.
+    Frame(usize)
+}
+
+/// Our side of a client's handshake: the pre-shared key it is sealed with,
+/// or the static key handshake.
+enum Handshake<'a> {
+    Psk(&'a mut Crypto),
+    Noise(Box<NoiseHandshake>)
+}
+
+/// The sealed response, the session keys and the secret later rekeys are
+/// salted with.
+type Finished = (Vec<u8>,(Crypto,Crypto),Vec<u8>);
+
+impl<'a> Handshake<'a> {
+    /// Seals a reply to the client's request.
+    fn seal(&mut self,msg: &boring::Message) -> Result<Vec<u8>,Error> {
+        match *self {
+            Handshake::Psk(ref mut psk) => Ok(boring::seal_handshake(msg, None, psk)),
+            Handshake::Noise(ref mut noise) => boring::seal_noise(msg, noise)
+        }
+    }
+
+    /// Seals the response completing the handshake and returns it with the
+    /// session keys and the secret later rekeys are salted with. `exchanged`
+    /// are the keys of the pre-shared key mode's key exchange.
+    fn finish(&mut self,response: &boring::Message,cipher: CryptoMethod,exchanged: Option<(Crypto,Crypto)>) -> Result<Finished,Error> {
+        match *self {
+            Handshake::Psk(ref mut psk) => {
+                let keys = exchanged.ok_or(Error::Crypto("Missing key exchange"))?;
+                Ok((boring::seal_handshake(response, None, psk), keys, psk.get_key().to_vec()))
+            },
+            Handshake::Noise(ref mut noise) => {
+                let reply = boring::seal_noise(response, noise)?;
+                let (keys, secret) = noise.clone().split(cipher)?;
+                Ok((reply, keys, secret))
+            }
+        }
+    }
+}
+
+/// Addresses leased to the connected clients and the session each of them
+/// routes to. In TAP mode frames go to the session their MAC address was
+/// learned behind instead.
+struct Leases {
+    pool: AddressPool,
+    pool6: Option<AddressPool>,
+    routes: HashMap<IpAddr,boring::SessionId>,
+    macs: MacTable
+}
+
+impl Leases {
+    /// Leases an address from each pool, the requested ones if they are free.
+    fn lease(&mut self,requested: Option<IpAddr>,requested6: Option<IpAddr>) -> Result<(IpAddr,Option<IpAddr>),Error> {
+        let exhausted = || Error::Unavailable("address pool exhausted".to_string());
+        let ip = self.pool.lease(requested).ok_or_else(exhausted)?;
+        let ip6 = match self.pool6 {
+            None => None,
+            Some(ref mut pool6) => match pool6.lease(requested6) {
+                Some(ip6) => Some(ip6),
+                None => {
+                    self.pool.release(ip);
+                    return Err(exhausted())
+                }
+            }
+        };
+        Ok((ip,ip6))
+    }
+
+    fn insert<E>(&mut self,session: &Session<E>) {
+        for ip in session.addresses() {
+            self.routes.insert(ip, session.keys.session());
+        }
+    }
+
+    /// Returns the addresses of a closed session to the pools.
+    fn release<E>(&mut self,session: &Session<E>) {
+        for ip in session.addresses() {
+            self.routes.remove(&ip);
+        }
+        self.macs.forget(session.keys.session());
+        self.unlease(session.ip, session.ip6);
+    }
+
+    /// Returns addresses to the pools that never made it into a session.
+    fn unlease(&mut self,ip: IpAddr,ip6: Option<IpAddr>) {
+        self.pool.release(ip);
+        if let (Some(pool6), Some(ip6)) = (self.pool6.as_mut(), ip6) {
+            pool6.release(ip6);
+        }
+    }
+
+    fn route(&self,ip: IpAddr) -> Option<boring::SessionId> {
+        self.routes.get(&ip).cloned()
+    }
+
+    fn available(&self) -> usize {
+        self.pool.available()
+    }
+}
+
+/// Reads the address at byte `v4` of an IPv4 or byte `v6` of an IPv6 header.
+fn address(data: &[u8],v4: usize,v6: usize) -> Option<IpAddr> {
+    match data.first().map(|b| b >> 4) {
+        Some(4) if data.len() >= 20 => {
+            let mut ip = [0u8; 4];
+            ip.copy_from_slice(&data[v4..v4 + 4]);
+            Some(IpAddr::from(ip))
+        },
+        Some(6) if data.len() >= 40 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&data[v6..v6 + 16]);
+            Some(IpAddr::from(ip))
+        },
+        _ => None
+    }
+}
+
+/// Source address of an IP packet received through the tunnel.
+fn source(data: &[u8]) -> Option<IpAddr> {
+    address(data, 12, 8)
+}
+
+/// Destination address of an IP packet read from the TUN device.
+fn destination(data: &[u8]) -> Option<IpAddr> {
+    address(data, 16, 24)
+}
+
+/// Removes the sessions whose client stopped responding and returns them.
+fn expire<E>(client_info: &mut HashMap<boring::SessionId,Session<E>>) -> Vec<Session<E>> {
+    let dead: Vec<boring::SessionId> = client_info.iter()
+        .filter(|(_, session)| session.keys.is_dead())
+        .map(|(&id, _)| id)
+        .collect();
+    dead.into_iter()
+        .filter_map(|id| client_info.remove(&id))
+        .collect()
+}
+
+/// Removes the sessions of the clients that lost their key and returns them.
+fn revoke<E>(client_info: &mut HashMap<boring::SessionId,Session<E>>,clients: &[String]) -> Vec<Session<E>> {
+    let revoked: Vec<boring::SessionId> = client_info.iter()
+        .filter(|(_, session)| session.peer.as_ref().map_or(false, |peer| clients.contains(peer)))
+        .map(|(&id, _)| id)
+        .collect();
+    revoked.into_iter()
+        .filter_map(|id| client_info.remove(&id))
+        .collect()
+}
+
+/// The message telling a client its key was revoked.
+fn revoked() -> boring::Message {
+    boring::Message::Reject { reason: "client key revoked".to_string(), retry: false }
+}
+
+/// Poll timeout until the next keepalive or dead-peer check of any session,
+/// or the next check of the peer database.
+fn next_check<E>(client_info: &HashMap<boring::SessionId,Session<E>>,peers: &Peers) -> Option<Duration> {
+    client_info.values().map(|session| session.keys.next_check()).chain(peers.next_check()).min()
+}
+
+/// When the client sent a hello, as it says.
+fn hello_time(msg: &boring::Message) -> u64 {
+    match *msg {
+        boring::Message::Request{ time, .. } => time,
+        _ => 0
+    }
+}
+
+/// The `Reject` to answer a failed handshake with, if the client should hear about it.
+fn rejection(e: &Error) -> Option<boring::Message> {
+    match *e {
+        Error::Rejected(ref reason) => Some(boring::Message::Reject { reason: reason.clone(), retry: false }),
+        Error::Unavailable(ref reason) => Some(boring::Message::Reject { reason: reason.clone(), retry: true }),
+        _ => None
+    }
+}
+
+/// Binds the server's UDP socket. The unspecified IPv6 address `[::]` takes
+/// IPv4 clients too, whatever `net.ipv6.bindv6only` says. With `reuse_port`
+/// the sockets of the workers share the port, the kernel spreads clients
+/// over them by address.
+fn bind_udp(addr: &SocketAddr,reuse_port: bool) -> io::Result<std::net::UdpSocket> {
+    let builder = match *addr {
+        SocketAddr::V4(_) => net2::UdpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = net2::UdpBuilder::new_v6()?;
+            builder.only_v6(false)?;
+            builder
+        }
+    };
+    builder.reuse_port(reuse_port)?.bind(addr)
+}
+
+fn bind_tcp(addr: &SocketAddr) -> io::Result<std::net::TcpListener> {
+    let builder = match *addr {
+        SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = net2::TcpBuilder::new_v6()?;
+            builder.only_v6(false)?;
+            builder
+        }
+    };
+    builder.reuse_address(true)?.bind(addr)?.listen(1024)
+}
+
+/// Where the switch sends an Ethernet frame that came in on `from`: our TAP
+/// device and the sessions of the other clients, which are `sessions`.
+fn switch_ports<I>(leases: &mut Leases,sessions: I,frame: &[u8],from: Port) -> Vec<Port> where I: Iterator<Item=boring::SessionId> {
+    match leases.macs.forward(frame, from) {
+        Forward::Drop => Vec::new(),
+        Forward::To(port) => vec![port],
+        Forward::Flood => Some(Port::Local).into_iter()
+            .chain(sessions.map(Port::Session))
+            .filter(|&port| port != from)
+            .collect()
+    }
+}
+
+/// Seals an Ethernet frame for `session` in `buf` and sends it with `send`,
+/// after a rekey request if one is due.
+fn send_frame<E,F>(session: &mut Session<E>,buf: &mut [u8],frame: &[u8],send: &mut F) where F: FnMut(&Session<E>,&[u8]) {
+    if let Some(rekey) = session.rekey() {
+        send(session, &rekey);
+    }
+    buf[boring::HEADER_LEN..boring::HEADER_LEN + frame.len()].copy_from_slice(frame);
+    let size = session.keys.seal_data(buf, frame.len());
+    send(session, &buf[..size]);
+}
+
+/// Hands an Ethernet frame that came in on `from` to where the switch sends
+/// it: our TAP device and the sessions of the other clients, whose packets
+/// `send` sends.
+fn switch_frame<E,F>(leases: &mut Leases,tap: &mut device::Tuntap,client_info: &mut HashMap<boring::SessionId,Session<E>>,frame: &[u8],from: Port,mut send: F) where F: FnMut(&Session<E>,&[u8]) {
+    let ports = switch_ports(leases, client_info.keys().cloned(), frame, from);
+    let mut buf = vec![0u8; boring::HEADER_LEN + frame.len() + boring::TAG_LEN];
+    for port in ports {
+        match port {
+            Port::Local => write_tun(tap, frame),
+            Port::Session(id) => if let Some(session) = client_info.get_mut(&id) {
+                send_frame(session, &mut buf, frame, &mut send);
+            }
+        }
+    }
+}
+
+/// TCP connections by token, with the peer address, the session
+/// established over them and when they were accepted.
+type Connections = HashMap<mio::Token,(boring::Framed,SocketAddr,Option<boring::SessionId>,Instant)>;
+
+/// Tokens of the TCP connections that did not complete the handshake in time.
+fn unestablished(conns: &Connections) -> Vec<mio::Token> {
+    conns.iter()
+        .filter(|(_, (_, _, established, accepted))| established.is_none() && accepted.elapsed() >= TCP_HANDSHAKE_TIMEOUT)
+        .map(|(&token, _)| token)
+        .collect()
+}
+
+/// Time until the next TCP connection without a session times out.
+fn next_unestablished(conns: &Connections) -> Option<Duration> {
+    conns.values()
+        .filter(|(_, _, established, _)| established.is_none())
+        .map(|(_, _, _, accepted)| TCP_HANDSHAKE_TIMEOUT.checked_sub(accepted.elapsed()).unwrap_or_default())
+        .min()
+}
+
+/// Queues a packet on the TCP connection of a client.
+fn send_tcp(poll: &mio::Poll,conns: &mut Connections,token: mio::Token,packet: &[u8]) {
+    if let Some((conn, address, _, _)) = conns.get_mut(&token) {
+        if let Err(e) = conn.send(packet) {
+            warn!("Failed to send to {}: {}", address, e);
+        }
+        poll.reregister(conn.stream(), token, conn.interest(), mio::PollOpt::level()).unwrap();
+    }
+}
+
+/// Picks an unused, non-zero id for a new session.
+fn new_session_id<V>(rng: &mut ThreadRng,client_info: &HashMap<boring::SessionId,V>) -> boring::SessionId {
+    loop {
+        let id = rng.gen::<boring::SessionId>();
+        if id != 0 && !client_info.contains_key(&id) {
+            return id
+        }
+    }
+}
+
+/// Stops all workers when the one holding it returns or panics, so the
+/// server does not go on with part of its queues unserved.
+struct StopOnExit<'a>(&'a Shared);
+
+impl<'a> Drop for StopOnExit<'a> {
+    fn drop(&mut self) {
+        self.0.stop();
+    }
+}
+
+/// A UDP client's session, shared by the workers.
+type SharedSession = Arc<Mutex<Session<SocketAddr>>>;
+
+/// What the workers of a UDP server share. Each session is locked on its
+/// own, so workers handle packets of different clients at once. The locks
+/// are taken in the order of the fields, the one of a session after
+/// `sessions` and before `leases`, and never two sessions at once.
+struct Shared {
+    peers: Mutex<Peers>,
+    cookies: Mutex<Cookies>,
+    sessions: RwLock<HashMap<boring::SessionId,SharedSession>>,
+    leases: Mutex<Leases>,
+    hellos: Mutex<Hellos>,
+    /// Wakes up the first worker to look after a new session.
+    wake: mio::SetReadiness,
+    /// Tells each worker to stop, once one of them is gone.
+    stop: Vec<mio::SetReadiness>
+}
+
+impl Shared {
+    fn session(&self,id: boring::SessionId) -> Option<SharedSession> {
+        self.sessions.read().unwrap().get(&id).cloned()
+    }
+
+    /// Removes the sessions `remove` picks and returns them.
+    fn remove<F>(&self,remove: F) -> Vec<SharedSession> where F: Fn(&Session<SocketAddr>) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        let removed: Vec<boring::SessionId> = sessions.iter()
+            .filter(|(_, session)| remove(&session.lock().unwrap()))
+            .map(|(&id, _)| id)
+            .collect();
+        removed.into_iter()
+            .filter_map(|id| sessions.remove(&id))
+            .collect()
+    }
+
+    fn stop(&self) {
+        for stop in &self.stop {
+            let _ = stop.set_readiness(mio::Ready::readable());
+        }
+    }
+
+    /// Poll timeout until the next keepalive or dead-peer check of any
+    /// session, or the next check of the peer database.
+    fn next_check(&self) -> Option<Duration> {
+        let sessions = self.sessions.read().unwrap().values()
+            .map(|session| session.lock().unwrap().keys.next_check())
+            .min();
+        sessions.into_iter().chain(self.peers.lock().unwrap().next_check()).min()
+    }
+}
 
 impl Server {
     pub fn new() -> Self{
         Server {
             ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
             netmask: IpAddr::V4(Ipv4Addr::new(255, 255, 255, 0)),
+            pool: None,
+            ip6: None,
             dns: IpAddr::V4(Ipv4Addr::new(114, 114, 114, 114)),
             host: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-            secret: String::new(),
-            port: 0 as u16
+            secret: None,
+            peers: None,
+            private_key: None,
+            port: 0 as u16,
+            proto: Proto::Udp,
+            mode: device::Type::Tun,
+            bridge: None,
+            dev: None,
+            mtu: None,
+            persist: false,
+            owner: None,
+            group: None,
+            ciphers: vec![CryptoMethod::AES256, CryptoMethod::ChaCha20],
+            keepalive: boring::KEEPALIVE,
+            dead_peer_timeout: boring::DEAD_PEER_TIMEOUT,
+            cookie_threshold: COOKIE_THRESHOLD,
+            threads: 1,
+            batch: batch::BATCH
         }
     }
 
@@ -49,174 +531,993 @@ impl Server {
         Ok(())
     }
 
+    /// Leases client addresses from `pool` instead of the network of `--ip` and
+    /// `--netmask`. Our own address defaults to the first one of the pool.
+    pub fn parse_pool(&mut self,pool: &str,ip: Option<&str>) -> Result<(),Error>{
+        let pool: Cidr = pool.parse()?;
+        self.netmask = pool.netmask();
+        match ip {
+            Some(ip) => self.parse_ip(ip)?,
+            None => self.ip = pool.nth(1)
+        }
+        self.pool = Some(pool);
+        Ok(())
+    }
+
+    /// Also leases IPv6 client addresses from `pool`, which makes the tunnel
+    /// dual-stack. Our own IPv6 address defaults to the first one of the pool.
+    pub fn parse_pool6(&mut self,pool: &str,ip: Option<&str>) -> Result<(),Error>{
+        let pool: Cidr = pool.parse()?;
+        if !pool.network().is_ipv6() {
+            return Err(Error::Config(format!("{} is not an IPv6 network", pool)))
+        }
+        let ip = match ip {
+            Some(ip) => ip.parse().map_err(|e| Error::Parse("failed to parse ipaddr from string",e))?,
+            None => pool.nth(1)
+        };
+        self.ip6 = Some((ip, pool));
+        Ok(())
+    }
+
+    fn leases(&self) -> Result<Leases,Error> {
+        let network = match self.pool {
+            Some(pool) => pool,
+            None => Cidr::from_netmask(self.ip, self.netmask)?
+        };
+        let pool6 = match self.ip6 {
+            Some((ip6, pool6)) => Some(AddressPool::new(pool6, ip6)?),
+            None => None
+        };
+        Ok(Leases {
+            pool: AddressPool::new(network, self.ip)?,
+            pool6: pool6,
+            routes: HashMap::new(),
+            macs: MacTable::new()
+        })
+    }
+
     pub fn parse_dns(&mut self,dns: &str) -> Result<(),Error>{
         self.dns = dns.parse().map_err(|e| Error::Parse("failed to parse dns from string",e))?;
         Ok(())
     }
 
-    pub fn parse_key(&mut self,key: &str) {
-        self.secret = key.to_string()
+    pub fn parse_key(&mut self,key: SharedKey) {
+        self.secret = Some(key)
+    }
+
+    pub fn parse_peers(&mut self,path: &str) {
+        self.peers = Some(PathBuf::from(path));
+    }
+
+    fn load_peers(&self) -> Result<Peers,Error> {
+        Peers::new(self.secret.as_ref(), self.peers.as_ref().map(PathBuf::as_path))
+    }
+
+    pub fn parse_private_key(&mut self,key: PrivateKey) {
+        info!("Public key: {}", key.public_key());
+        self.private_key = Some(key);
+    }
+
+    /// Opens the hello request starting a handshake and checks it comes from
+    /// the client it claims to. Returns our side of the handshake with the
+    /// request, the client's id in the peer database and whether it was
+    /// revoked. Handshakes of clients we do not know fail without a reply.
+    fn open_hello<'a>(&self,packet: &mut [u8],kind: boring::PacketType,peers: &'a mut Peers) -> Result<(Handshake<'a>,boring::Message,Option<String>,bool),Error> {
+        if kind == boring::PacketType::Noise {
+            let private_key = self.private_key.as_ref().ok_or(Error::Invaildmessage("static key handshake while we have no private key"))?;
+            let mut noise = NoiseHandshake::responder(private_key);
+            let msg = boring::open_noise(packet, &mut noise)?;
+            let public_key = noise.remote_static().unwrap();
+            let (peer, revoked) = peers.public(&public_key).ok_or_else(|| Error::Config(format!("unknown public key {}", public_key)))?;
+            match msg {
+                // The id comes from the peer database, not from the client.
+                boring::Message::Request{ id: None, .. } => Ok((Handshake::Noise(Box::new(noise)), msg, Some(peer), revoked)),
+                _ => Err(Error::Invaildmessage("error shakehand message"))
+            }
+        } else {
+            let peer = boring::handshake_id(packet)?;
+            let (psk, revoked) = peers.key(peer.as_ref().map(String::as_str)).ok_or_else(|| Error::Config(format!("unknown client id {:?}", peer)))?;
+            let msg = boring::open_handshake(packet, psk)?;
+            match msg {
+                boring::Message::Request{ ref id, .. } if *id == peer => {},
+                _ => return Err(Error::Invaildmessage("error shakehand message"))
+            }
+            Ok((Handshake::Psk(psk), msg, peer, revoked))
+        }
     }
 
     pub fn parse_port(&mut self,port: u16) {
         self.port = port;
     }
 
+    pub fn parse_proto(&mut self,proto: &str) -> Result<(),Error> {
+        self.proto = proto.parse()?;
+        Ok(())
+    }
+
+    pub fn parse_mode(&mut self,mode: &str) -> Result<(),Error> {
+        self.mode = mode.parse()?;
+        Ok(())
+    }
+
+    /// Attaches the TAP device to the Linux bridge `bridge`, which then holds
+    /// our address instead of the device.
+    pub fn parse_bridge(&mut self,bridge: &str) -> Result<(),Error> {
+        if self.mode != device::Type::Tap {
+            return Err(Error::Config("bridge needs tap mode".to_string()))
+        }
+        self.bridge = Some(bridge.to_string());
+        Ok(())
+    }
+
+    pub fn parse_dev(&mut self,dev: &str) -> Result<(),Error> {
+        device::check_name(dev)?;
+        self.dev = Some(dev.to_string());
+        Ok(())
+    }
+
+    pub fn parse_mtu(&mut self,mtu: u32) -> Result<(),Error> {
+        device::check_mtu(mtu)?;
+        self.mtu = Some(mtu);
+        Ok(())
+    }
+
+    fn mtu(&self) -> u32 {
+        self.mtu.unwrap_or_else(|| self.mode.default_mtu())
+    }
+
+    /// Keeps the device when we exit, for `owner` and `group` to attach to.
+    pub fn parse_persist(&mut self,owner: Option<&str>,group: Option<&str>) -> Result<(),Error> {
+        if self.dev.is_none() {
+            return Err(Error::Config("persist needs dev to be set, to find the device again".to_string()))
+        }
+        self.owner = owner.map(utils::user_id).transpose()?;
+        self.group = group.map(utils::group_id).transpose()?;
+        self.persist = true;
+        Ok(())
+    }
+
+    pub fn parse_ciphers(&mut self,ciphers: &str) -> Result<(),Error> {
+        self.ciphers = CryptoMethod::parse_list(ciphers)?;
+        Ok(())
+    }
+
+    pub fn parse_keepalive(&mut self,keepalive: u64,dead_peer_timeout: u64) -> Result<(),Error> {
+        if keepalive == 0 || dead_peer_timeout <= keepalive {
+            return Err(Error::Config(format!("dead peer timeout of {}s must be longer than the keepalive interval of {}s", dead_peer_timeout, keepalive)))
+        }
+        self.keepalive = Duration::from_secs(keepalive);
+        self.dead_peer_timeout = Duration::from_secs(dead_peer_timeout);
+        Ok(())
+    }
+
+    pub fn parse_cookie_threshold(&mut self,threshold: u32) {
+        self.cookie_threshold = threshold;
+    }
+
+    /// Serves UDP clients on `threads` threads, which needs `--proto udp`
+    /// to be set first.
+    pub fn parse_threads(&mut self,threads: usize) -> Result<(),Error> {
+        if threads == 0 || threads > MAX_THREADS {
+            return Err(Error::Config(format!("invalid threads {}, expected 1 to {}", threads, MAX_THREADS)))
+        }
+        if threads > 1 && self.proto != Proto::Udp {
+            return Err(Error::Config("threads need proto udp, tcp is served on one thread".to_string()))
+        }
+        self.threads = threads;
+        Ok(())
+    }
+
+    pub fn parse_batch(&mut self,batch: usize) -> Result<(),Error> {
+        batch::check_batch(batch)?;
+        self.batch = batch;
+        Ok(())
+    }
+
+    /// The address to listen on, IPv6 ones may be in brackets like `[::]`.
     pub fn parse_host(&mut self,host: &str) -> Result<(),Error>{
-        self.dns = host.parse().map_err(|e| Error::Parse("failed to parse dns from string",e))?;
+        self.host = host.trim_start_matches('[').trim_end_matches(']').parse().map_err(|e| Error::Parse("failed to parse host from string",e))?;
         Ok(())
     }
-    pub fn create_tun(&mut self) -> Result<device::Tuntap,io::Error>{
-        let tun = device::Tuntap::create("tun1", device::Type::Tun, None).expect("failed to create tun");
-        // self.parse_ip(ipaddr).unwrap();
-        // self.parse_dns(dns).unwrap();
-        // self.parse_netmask(netmask).unwrap();
-        // self.parse_dns(dns).expect("parse dns failed");
-        tun.set_ip(&self.ip.to_string(),&self.netmask.to_string()).expect("failed to set ip to tun device");
-        utils::set_dns(&self.dns.to_string()).expect("set dns failed");
+    pub fn create_tun(&mut self) -> Result<device::Tuntap,Error>{
+        let name = self.dev.as_ref().map(String::as_str).unwrap_or_else(|| self.mode.ifname());
+        let tun = match self.threads {
+            1 => device::Tuntap::create(name, self.mode, None),
+            _ => device::Tuntap::create_multi_queue(name, self.mode)
+        }.map_err(|e| Error::TunTapDev("failed to create tun device",e))?;
+        // A port of a bridge has no address, the bridge has ours.
+        if self.bridge.is_none() {
+            tun.set_ip(self.ip,self.netmask).map_err(|e| Error::TunTapDev("failed to set ip to tun device",e))?;
+        }
+        utils::set_dns(self.dns).map_err(|e| Error::File("failed to set dns", e))?;
+        Ok(tun)
+    }
+    /// Answers a hello request of client `peer`: leases an address, runs our
+    /// half of the key exchange and returns the new session with the sealed
+    /// response to send back.
+    fn shakehand<E>(&self,msg: boring::Message,peer: Option<String>,endpoint: E,handshake: &mut Handshake,leases: &mut Leases,id: boring::SessionId) -> Result<(Session<E>,Vec<u8>),Error> {
+        let (public_key, ciphers, requested, requested6, mode) = match msg {
+            boring::Message::Request{ref msg, ref public_key, ref ciphers, ip, ip6, mode, ..} if msg == "hello" => (public_key, ciphers, ip, ip6, mode),
+            _ => return Err(Error::Invaildmessage("error shakehand message"))
+        };
+        if mode != self.mode {
+            return Err(Error::Rejected(format!("server runs in {} mode, not {}", self.mode, mode)))
+        }
+        // Our preference order decides among the ciphers both sides support.
+        let cipher = match self.ciphers.iter().find(|cipher| ciphers.contains(cipher)) {
+            Some(&cipher) => cipher,
+            None => return Err(Error::Rejected(format!("no common cipher, server supports {:?}", self.ciphers)))
+        };
+        // The static key handshake already agreed on the keys.
+        let (server_public_key, exchanged) = match *handshake {
+            Handshake::Psk(ref psk) => {
+                let kx = KeyExchange::new()?;
+                let server_public_key = kx.public_key().to_vec();
+                (server_public_key, Some(kx.derive(cipher, psk.get_key(), public_key, false)?))
+            },
+            Handshake::Noise(_) => (Vec::new(), None)
+        };
+        let (client_ip, client_ip6) = leases.lease(requested, requested6)?;
+        let response_msg = boring::Message::Response {
+            ip: client_ip,
+            netmask: self.netmask,
+            gateway: self.ip,
+            ip6: self.ip6.and_then(|(ip6, pool6)| client_ip6.map(|client_ip6| (client_ip6, pool6.prefix(), ip6))),
+            session: id,
+            dns: self.dns,
+            public_key: server_public_key,
+            cipher: cipher,
+            mtu: self.mtu()
+        };
+        let (reply, keys, secret) = match handshake.finish(&response_msg, cipher, exchanged) {
+            Ok(finished) => finished,
+            Err(e) => {
+                leases.unlease(client_ip, client_ip6);
+                return Err(e)
+            }
+        };
+        let mut session = Session {
+            endpoint: endpoint,
+            peer: peer,
+            ip: client_ip,
+            ip6: client_ip6,
+            keys: boring::SessionKeys::new(cipher, false, &secret, id, keys)
+        };
+        session.keys.set_keepalive(self.keepalive, self.dead_peer_timeout);
+        Ok((session,reply))
+    }
+
+    fn setup_tun(&mut self) -> Result<device::Tuntap,Error> {
+        info!("Enabling kernel's IPv4 forwarding.");
+        utils::enable_ipv4_forwarding().map_err(|e| Error::File("failed to enable ipv4 forwarding", e))?;
+
+        info!("Bringing up {} device.", self.mode);
+        let tun = self.create_tun()?;
+        info!("tun device create successful,set ip: {} netmask: {}",self.ip.to_string(),self.netmask.to_string());
+        tun.set_persist(self.persist).map_err(|e| Error::TunTapDev("failed to set persistence of tun device",e))?;
+        if let Some(uid) = self.owner {
+            tun.set_owner(uid).map_err(|e| Error::TunTapDev("failed to set owner of tun device",e))?;
+        }
+        if let Some(gid) = self.group {
+            tun.set_group(gid).map_err(|e| Error::TunTapDev("failed to set group of tun device",e))?;
+        }
+        tun.set_mtu(self.mtu()).map_err(|e| Error::TunTapDev("failed to set mtu of tun device",e))?;
+        tun.up().map_err(|e| Error::TunTapDev("failed to bring up tun device",e))?;
+        if let Some(ref bridge) = self.bridge {
+            utils::attach_to_bridge(&tun.ifname(), bridge).map_err(|e| Error::Route("failed to attach tap device to bridge", e))?;
+            info!("Attached {} to bridge {}.", tun.ifname(), bridge);
+        }
+        if let (Some((ip6, pool6)), None) = (self.ip6, self.bridge.as_ref()) {
+            info!("Enabling kernel's IPv6 forwarding.");
+            utils::enable_ipv6_forwarding().map_err(|e| Error::File("failed to enable ipv6 forwarding", e))?;
+            tun.set_ip6(ip6, pool6.prefix()).map_err(|e| Error::TunTapDev("failed to set ipv6 address to tun device",e))?;
+            info!("set ipv6: {}/{}",ip6,pool6.prefix());
+        }
+        info!("TUN device {} initialized. Internal IP: {} {}, MTU {}.",tun.ifname(),self.ip,self.netmask,self.mtu());
         Ok(tun)
     }
+
+    pub fn run(&mut self) -> Result<(),Error> {
+        match self.proto {
+            Proto::Udp => self.server_udp(),
+            Proto::Tcp => self.server_tcp()
+        }
+    }
+
     pub fn server_udp(&mut self) -> Result<(),Error> {
         info!("start server");
         info!("server {}:{}",self.host.to_string(),self.port.to_string());
-        info!("Enabling kernel's IPv4 forwarding.");
-        utils::enable_ipv4_forwarding().unwrap();
+        let (wake, set_wake) = mio::Registration::new2();
+        let (stops, set_stops): (Vec<_>, Vec<_>) = (0..self.threads).map(|_| mio::Registration::new2()).unzip();
+        let shared = Arc::new(Shared {
+            peers: Mutex::new(self.load_peers()?),
+            cookies: Mutex::new(Cookies::new(self.cookie_threshold)?),
+            sessions: RwLock::new(HashMap::new()),
+            leases: Mutex::new(self.leases()?),
+            hellos: Mutex::new(Hellos::new()),
+            wake: set_wake,
+            stop: set_stops
+        });
+        let _resolv_conf = utils::ResolvConf::save();
+        let tun = self.setup_tun()?;
+        let mut queues = Vec::new();
+        for _ in 1..self.threads {
+            queues.push(tun.queue().map_err(|e| Error::TunTapDev("failed to open a queue of tun device",e))?);
+        }
 
-        info!("Bringing up TUN device.");
-        let mut tun = self.create_tun().unwrap();
-        info!("tun device create successful,set ip: {} netmask: {}",self.ip.to_string(),self.netmask.to_string());
-        tun.up().unwrap();
-        let tun_raw_fd = tun.as_raw_fd();
+        let addr = SocketAddr::new(self.host, self.port);
+        let mut workers = Vec::new();
+        for tun in Some(tun).into_iter().chain(queues) {
+            let socket = bind_udp(&addr, self.threads > 1).map_err(|e| Error::Socket("failed to bind socket",e))?;
+            // Every worker hears about the signals on its own.
+            let signals = utils::shutdown_signals().map_err(|e| Error::Signal("failed to catch signals",e))?;
+            workers.push((tun, mio::net::UdpSocket::from_socket(socket).unwrap(), signals));
+        }
+        info!("Listening on: {}.", addr);
+        if self.threads > 1 {
+            info!("Serving clients on {} threads.", self.threads);
+        }
 
-        let tunfd = mio::unix::EventedFd(&tun_raw_fd);
-        info!("TUN device {} initialized. Internal IP: {} {}.",self.ip,self.netmask,tun.ifname());
+        let mut result = Ok(());
+        let mut handles = Vec::new();
+        let mut wake = Some(wake);
+        for (n, ((tun, sockfd, signals), stop)) in workers.into_iter().zip(stops).enumerate() {
+            let server = self.clone();
+            let state = shared.clone();
+            // The first worker looks after the sessions.
+            let wake = wake.take();
+            let name = format!("worker-{}", n);
+            let spawned = thread::Builder::new()
+                .name(name.clone())
+                .spawn(move || server.udp_worker(tun, sockfd, signals, wake, stop, &state));
+            match spawned {
+                Ok(handle) => handles.push((name, handle)),
+                Err(e) => {
+                    shared.stop();
+                    result = Err(Error::Socket("failed to start worker thread",e));
+                    break
+                }
+            }
+        }
+        // The first worker to go stops the others, the first error is ours.
+        for (name, handle) in handles {
+            match handle.join().unwrap_or_else(|_| Err(Error::Worker(name))) {
+                Err(e) if result.is_ok() => result = Err(e),
+                Err(e) => warn!("{}", e),
+                Ok(()) => {}
+            }
+        }
+        result
+    }
 
-        let addr = format!("0.0.0.0:{}", self.port.to_string()).parse().unwrap();
-        let sockfd = mio::net::UdpSocket::bind(&addr).unwrap();
-        info!("Listening on: 0.0.0.0:{}.", self.port);
+    /// Serves UDP clients on one thread: sends the packets read from `tun`,
+    /// a queue of the TUN device, to their clients and handles the packets
+    /// `sockfd`, one of the sockets sharing our port, receives, both up to
+    /// `--batch` at a time. The first worker, the one `wake` wakes up, also
+    /// keeps the sessions alive and disconnects the clients when we shut down.
+    /// Returns once a signal arrives or `stop` says another worker is gone.
+    fn udp_worker(&self,mut tun: device::Tuntap,sockfd: mio::net::UdpSocket,signals: Signals,wake: Option<mio::Registration>,stop: mio::Registration,shared: &Shared) -> Result<(),Error> {
+        let _stop = StopOnExit(shared);
+        // Drained in bursts of up to a batch once readable.
+        tun.set_nonblocking(true).map_err(|e| Error::TunTapDev("failed to set tun device non-blocking",e))?;
+        let tun_raw_fd = tun.as_raw_fd();
+        let tunfd = mio::unix::EventedFd(&tun_raw_fd);
 
         let poll = mio::Poll::new().unwrap();
         const TUN_TOKEN: mio::Token = mio::Token(0);
         const SOCK_TOKEN: mio::Token = mio::Token(1);
+        const SIGNAL_TOKEN: mio::Token = mio::Token(2);
+        const WAKE_TOKEN: mio::Token = mio::Token(3);
+        const STOP_TOKEN: mio::Token = mio::Token(4);
         poll.register(&sockfd, SOCK_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
         poll.register(&tunfd, TUN_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+        poll.register(&signals, SIGNAL_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).map_err(|e| Error::Signal("failed to wait for signals",e))?;
+        poll.register(&stop, STOP_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+        if let Some(ref wake) = wake {
+            poll.register(wake, WAKE_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+        }
+        let first = wake.is_some();
 
         let mut events = mio::Events::with_capacity(1024);
         let mut rng = thread_rng();
-        let mut available_ids: Vec<u8> = (2..254).collect();
-        let mut client_info: TransientHashMap<IpAddr, (Token, SocketAddr)> = TransientHashMap::new(60);
+        // A full socket buffer or a spoofed, unreachable source loses the
+        // packet as the network would, it is no reason to stop serving.
+        let send_to = |packet: &[u8], address: &SocketAddr| {
+            if let Err(e) = sockfd.send_to(packet, address) {
+                warn!("Failed to send to {}: {}", address, e);
+            }
+        };
+        let mut send = |session: &Session<SocketAddr>, packet: &[u8]| send_to(packet, &session.endpoint);
 
-
-        let mut buf = [0u8; 1600];
-        let mut nonce = [0u8; 12];
-        let add = [0u8; 8];
-        let mut sender =  Crypto::from_shared_key(CryptoMethod::AES256, &self.secret);
-        let receiver = Crypto::from_shared_key(CryptoMethod::AES256, &self.secret);
+        let mut receiver = batch::Receiver::new(&sockfd, self.batch);
+        let mut sender = batch::Sender::new(&sockfd, self.batch);
+        let mut frame_buf = [0u8; 1600];
 
         loop {
-            // available_ids.append(&mut client_info.prune());
-            poll.poll(&mut events, None).expect("poll failed");
+            let timeout = match first {
+                true => shared.next_check(),
+                false => None
+            };
+            poll.poll(&mut events, timeout).expect("poll failed");
             for event in events.iter() {
                 match event.token() {
                     SOCK_TOKEN => {
-                        let (len,address) = sockfd.recv_from(&mut buf).unwrap();
-                        let decrypted_buf_len = receiver.decrypt(&mut buf[..len], &nonce, &add).unwrap();
-                        let msg: boring::Message = deserialize(&buf[..decrypted_buf_len]).unwrap();
-                        match msg {
-                            boring::Message::Request{msg} => {
-                                if msg == "hello" {
-                                    let client_id: u8 = available_ids.pop().unwrap();
-                                    let mut ipaddr_oct = [0u8;4];
-                                    match self.ip {
-                                        IpAddr::V4(ipv4) => {
-                                            ipaddr_oct = ipv4.octets();
-                                        },
-                                        IpAddr::V6(ipv6) => {},
-                                    };
-                                    ipaddr_oct[3] = client_id;
-                                    let client_ip = Ipv4Addr::new(ipaddr_oct[0], ipaddr_oct[1], ipaddr_oct[2], ipaddr_oct[3]);
-                                    let client_token: Token = rng.gen::<Token>();
-                                    client_info.insert(IpAddr::V4(client_ip), (client_token, address));
-
-                                    info!("Got request from {}. Assigning IP address: {}.",
-                                      addr,
-                                      client_ip.to_string());
-                                    let response_msg = boring::Message::Response {
-                                        ip: IpAddr::V4(client_ip),
-                                        netmask: self.netmask,
-                                        token: client_token,
-                                        dns: self.dns
-                                    };
-                                    let encoded_data = serialize(&response_msg).unwrap();
-                                    let mut encrypted_msg = encoded_data.clone();
-                                    encrypted_msg.resize(encrypted_msg.len() + sender.additional_bytes(), 0);
-                                    let add = [0u8; 8];
-                                    let data_len = sender.encrypt(&mut encrypted_msg, encoded_data.len(), &mut nonce, &add);
-                                    let mut sent_len = 0;
-                                    while sent_len < data_len {
-                                        sent_len += sockfd.send_to(&encrypted_msg[sent_len..data_len], &address).unwrap();
-                                    };
-                                } else {
-                                    warn!("Invalid message {:?} from {}", msg, address);
+                        match receiver.recv(&sockfd) {
+                            Ok(_) => {},
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                            Err(e) => return Err(Error::Socket("failed to receive from clients",e))
+                        }
+                        for (packet, address) in receiver.datagrams() {
+                            let header = match boring::Header::parse(packet) {
+                                Ok(header) => header,
+                                Err(e) => {
+                                    warn!("Invalid packet from {}: {}", address, e);
+                                    continue
                                 }
-                            },
-                            boring::Message::Response{ip: _,netmask: _,token: _,dns: _} => {
-                                warn!("Invalid message {:?} from {}", msg, address);
-                            },
-                            boring::Message::Data {ip,token, data} => {
-                                match client_info.get(&ip) {
-                                    None => warn!("Unknown data with token {} from ip {}.", token, ip),
-                                    Some(&(t, _)) => {
-                                        if t != token {
-                                            warn!("Unknown data with mismatched token {} from ip {}. \
-                                                   Expected: {}",
-                                                token,
-                                                ip,
-                                                t);
-                                        } else {
-                                            let data_len = data.len();
-                                            let mut sent_len = 0;
-                                            while sent_len < data_len {
-                                                sent_len += tun.write(&data[sent_len..data_len]).unwrap();
+                            };
+                            // Everything but a hello carries the id of its session in the header.
+                            if header.kind != boring::PacketType::Handshake && header.kind != boring::PacketType::Noise {
+                                let frame = match shared.session(header.session) {
+                                    Some(session) => {
+                                        let mut session = session.lock().unwrap();
+                                        match session.forward(&mut tun, self.mode, packet, &address) {
+                                            Ok(forwarded) => {
+                                                // The client roamed. Only a packet that authenticated
+                                                // and was not seen before may move its session.
+                                                if session.endpoint != address {
+                                                    info!("Client {} moved from {} to {}.", session.ip, session.endpoint, address);
+                                                    session.endpoint = address;
+                                                }
+                                                match forwarded {
+                                                    Forwarded::Reply(reply) => {
+                                                        if let Some(reply) = reply {
+                                                            send_to(&reply, &address);
+                                                        }
+                                                        None
+                                                    },
+                                                    Forwarded::Frame(size) => Some(size)
+                                                }
+                                            },
+                                            Err(Error::Disconnected) => {
+                                                info!("Client {} at {} disconnected.", session.ip, address);
+                                                shared.leases.lock().unwrap().release(&session);
+                                                drop(session);
+                                                shared.sessions.write().unwrap().remove(&header.session);
+                                                None
+                                            },
+                                            Err(e) => {
+                                                warn!("Invalid packet from {}: {}", address, e);
+                                                None
                                             }
                                         }
+                                    },
+                                    None => {
+                                        warn!("Packet for unknown session {} from {}.", header.session, address);
+                                        None
+                                    }
+                                };
+                                if let Some(size) = frame {
+                                    let frame = &packet[boring::HEADER_LEN..boring::HEADER_LEN + size];
+                                    self.switch_udp(shared, &mut tun, frame, Port::Session(header.session), &mut frame_buf, &mut send);
+                                }
+                                continue
+                            }
+                            let (hello, mac) = match boring::strip_cookie_mac(packet) {
+                                Ok(stripped) => stripped,
+                                Err(e) => {
+                                    warn!("Invalid packet from {}: {}", address, e);
+                                    continue
+                                }
+                            };
+                            // Under load the client has to prove it owns its address first.
+                            let cookie = {
+                                let mut cookies = shared.cookies.lock().unwrap();
+                                match cookies.under_load() && !cookies.check(&address, hello, mac) {
+                                    true => Some(cookies.cookie(&address)),
+                                    false => None
+                                }
+                            };
+                            if let Some(cookie) = cookie {
+                                debug!("Sending cookie to {}.", address);
+                                send_to(&boring::seal_cookie(&cookie), &address);
+                                continue
+                            }
+                            // Taken before the hello is opened in place.
+                            let digest = hello::digest(hello);
+                            let mut peers = shared.peers.lock().unwrap();
+                            let (mut handshake, msg, peer, revoked) = match self.open_hello(hello, header.kind, &mut peers) {
+                                Ok(opened) => opened,
+                                Err(e) => {
+                                    warn!("Invalid handshake from {}: {}", address, e);
+                                    continue
+                                }
+                            };
+                            let id = new_session_id(&mut rng, &shared.sessions.read().unwrap());
+                            let seen = shared.hellos.lock().unwrap().check(&digest, hello_time(&msg));
+                            let result = match (revoked, seen) {
+                                (true, _) => Err(Error::Rejected("client key revoked".to_string())),
+                                (false, Ok(Hello::New)) => self.shakehand(msg, peer, address, &mut handshake, &mut shared.leases.lock().unwrap(), id),
+                                // The client lost our response, a replay of an expired session gets nothing.
+                                (false, Ok(Hello::Answered(id, response))) => {
+                                    if shared.session(id).is_some() {
+                                        debug!("Answering repeated hello from {} again.", address);
+                                        send_to(&response, &address);
+                                    }
+                                    continue
+                                },
+                                (false, Ok(Hello::Pending)) => continue,
+                                (false, Err(e)) => Err(e)
+                            };
+                            match result {
+                                Ok((session,encrypted_msg)) => {
+                                    let mut sessions = shared.sessions.write().unwrap();
+                                    let mut leases = shared.leases.lock().unwrap();
+                                    // Another worker may have picked the same id meanwhile.
+                                    if sessions.contains_key(&id) {
+                                        warn!("Session id {} of {} taken, dropping its handshake.", id, address);
+                                        leases.unlease(session.ip, session.ip6);
+                                        shared.hellos.lock().unwrap().failed(&digest);
+                                        continue
+                                    }
+                                    info!("Got request from {}. Assigning IP address: {}, {} left in pool.",
+                                      address,
+                                      session.addresses().map(|ip| ip.to_string()).collect::<Vec<_>>().join(" "),
+                                      leases.available());
+                                    leases.insert(&session);
+                                    sessions.insert(id, Arc::new(Mutex::new(session)));
+                                    shared.hellos.lock().unwrap().answered(&digest, id, &encrypted_msg);
+                                    send_to(&encrypted_msg, &address);
+                                    shared.wake.set_readiness(mio::Ready::readable()).unwrap();
+                                },
+                                Err(e) => {
+                                    shared.hellos.lock().unwrap().failed(&digest);
+                                    match rejection(&e) {
+                                        Some(reject) => {
+                                            warn!("Rejected request from {}: {}", address, e);
+                                            match handshake.seal(&reject) {
+                                                Ok(encrypted_msg) => send_to(&encrypted_msg, &address),
+                                                Err(e) => warn!("Failed to seal rejection for {}: {}", address, e)
+                                            }
+                                        },
+                                        None => warn!("Invalid message from {}: {}", address, e)
                                     }
                                 }
                             }
                         }
                     },
                     TUN_TOKEN => {
-                        let len: usize = tun.read(&mut buf).unwrap();
-                        let data = &buf[..len];
-                        // dbg!(data);
-                        let client_ip: Vec<u8> = data[16..20].to_vec();
-                        let client_ip = IpAddr::V4(Ipv4Addr::new(client_ip[0], client_ip[1], client_ip[2], client_ip[3]));
-                        match client_info.get(&client_ip) {
-                            None => warn!("Unknown data to ip {}.", client_ip.to_string()),
-                            Some(&(token,addr)) => {
-                                let msg = boring::Message::Data {
-                                    ip: self.ip,
-                                    token: token,
-                                    data: data.to_vec()
-                                };
-                                let encoded_data = serialize(&msg).unwrap();
-                                let mut encrypted_msg = encoded_data.clone();
-                                encrypted_msg.resize(encrypted_msg.len() + sender.additional_bytes(), 0);
-                                let add = [0u8; 8];
-                                let data_len = sender.encrypt(&mut encrypted_msg, encoded_data.len(), &mut nonce, &add);
-                                let mut sent_len = 0;
-                                while sent_len < data_len {
-                                    sent_len += sockfd.send_to(&encrypted_msg[sent_len..data_len], &addr).unwrap();
+                        for _ in 0..self.batch {
+                            let buf = sender.next();
+                            let end = buf.len() - boring::TAG_LEN;
+                            let len: usize = match tun.read(&mut buf[boring::HEADER_LEN..end]) {
+                                Ok(len) => len,
+                                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                                Err(e) => return Err(Error::TunTapDev("failed to read from tun device",e))
+                            };
+                            if self.mode == device::Type::Tap {
+                                let frame = &buf[boring::HEADER_LEN..boring::HEADER_LEN + len];
+                                self.switch_udp(shared, &mut tun, frame, Port::Local, &mut frame_buf, &mut send);
+                                continue
+                            }
+                            let client_ip = match destination(&buf[boring::HEADER_LEN..boring::HEADER_LEN + len]) {
+                                Some(client_ip) => client_ip,
+                                None => {
+                                    warn!("Dropping malformed packet from {}.", tun.ifname());
+                                    continue
+                                }
+                            };
+                            let route = shared.leases.lock().unwrap().route(client_ip);
+                            match route.and_then(|id| shared.session(id)) {
+                                None => warn!("Unknown data to ip {}.", client_ip.to_string()),
+                                Some(session) => {
+                                    let mut session = session.lock().unwrap();
+                                    // Sent right away, ahead of the batch it was due in.
+                                    if let Some(rekey) = session.rekey() {
+                                        send_to(&rekey, &session.endpoint);
                                     }
+                                    let size = session.keys.seal_data(buf, len);
+                                    sender.push(size, session.endpoint);
                                 }
+                            }
+                        }
+                        if let Err(e) = sender.flush(&sockfd) {
+                            warn!("Failed to send to clients: {}", e);
                         }
                     },
+                    STOP_TOKEN | SIGNAL_TOKEN if event.token() == STOP_TOKEN || utils::interrupted(&signals) => {
+                        if !first {
+                            return Ok(())
+                        }
+                        let sessions: Vec<SharedSession> = shared.sessions.write().unwrap().drain().map(|(_, session)| session).collect();
+                        info!("Shutting down, disconnecting {} clients.", sessions.len());
+                        for session in sessions {
+                            let mut session = session.lock().unwrap();
+                            send_to(&session.keys.seal(&boring::Message::Disconnect), &session.endpoint);
+                            shared.leases.lock().unwrap().release(&session);
+                        }
+                        return Ok(())
+                    },
+                    SIGNAL_TOKEN => {},
+                    WAKE_TOKEN => shared.wake.set_readiness(mio::Ready::empty()).unwrap(),
                     _ => unreachable!()
                 }
             }
+            if !first {
+                continue
+            }
+            for session in shared.remove(|session| session.keys.is_dead()) {
+                let session = session.lock().unwrap();
+                info!("Client {} at {} stopped responding, dropping its session.", session.ip, session.endpoint);
+                shared.leases.lock().unwrap().release(&session);
+            }
+            let clients = shared.peers.lock().unwrap().reload();
+            for session in shared.remove(|session| session.peer.as_ref().map_or(false, |peer| clients.contains(peer))) {
+                let mut session = session.lock().unwrap();
+                info!("Key of client {} at {} was revoked, dropping its session.", session.ip, session.endpoint);
+                send_to(&session.keys.seal(&revoked()), &session.endpoint);
+                shared.leases.lock().unwrap().release(&session);
+            }
+            let sessions: Vec<SharedSession> = shared.sessions.read().unwrap().values().cloned().collect();
+            for session in sessions {
+                let mut session = session.lock().unwrap();
+                if let Some(ping) = session.keys.keepalive() {
+                    send_to(&ping, &session.endpoint);
+                }
+            }
+        }
+    }
 
+    /// `switch_frame` for the workers of a UDP server, which share the
+    /// switch and the sessions.
+    fn switch_udp<F>(&self,shared: &Shared,tap: &mut device::Tuntap,frame: &[u8],from: Port,buf: &mut [u8],send: &mut F) where F: FnMut(&Session<SocketAddr>,&[u8]) {
+        let ids: Vec<boring::SessionId> = shared.sessions.read().unwrap().keys().cloned().collect();
+        let ports = switch_ports(&mut shared.leases.lock().unwrap(), ids.into_iter(), frame, from);
+        for port in ports {
+            match port {
+                Port::Local => write_tun(tap, frame),
+                Port::Session(id) => if let Some(session) = shared.session(id) {
+                    send_frame(&mut session.lock().unwrap(), &mut buf[..boring::HEADER_LEN + frame.len() + boring::TAG_LEN], frame, send);
+                }
+            }
         }
-        Ok(())
     }
 
-}
\ No newline at end of file
+    pub fn server_tcp(&mut self) -> Result<(),Error> {
+        info!("start server");
+        info!("server {}:{}",self.host.to_string(),self.port.to_string());
+        let mut leases = self.leases()?;
+        let mut peers = self.load_peers()?;
+        let signals = utils::shutdown_signals().map_err(|e| Error::Signal("failed to catch signals",e))?;
+        let _resolv_conf = utils::ResolvConf::save();
+        let mut tun = self.setup_tun()?;
+        let tun_raw_fd = tun.as_raw_fd();
+        let tunfd = mio::unix::EventedFd(&tun_raw_fd);
+
+        let addr = SocketAddr::new(self.host, self.port);
+        let listener = bind_tcp(&addr).map_err(|e| Error::Socket("failed to bind tcp listener",e))?;
+        let listener = mio::net::TcpListener::from_std(listener).unwrap();
+        info!("Listening on: {} (tcp).", addr);
+
+        let poll = mio::Poll::new().unwrap();
+        const TUN_TOKEN: mio::Token = mio::Token(0);
+        const LISTEN_TOKEN: mio::Token = mio::Token(1);
+        const SIGNAL_TOKEN: mio::Token = mio::Token(2);
+        poll.register(&listener, LISTEN_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+        poll.register(&tunfd, TUN_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+        poll.register(&signals, SIGNAL_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).map_err(|e| Error::Signal("failed to wait for signals",e))?;
+
+        let mut events = mio::Events::with_capacity(1024);
+        let mut rng = thread_rng();
+        let mut client_info: HashMap<boring::SessionId, Session<mio::Token>> = HashMap::new();
+        let mut conns: Connections = HashMap::new();
+        let mut hellos = Hellos::new();
+        let mut next_conn: usize = 3;
+        // Since when the listener is not polled, after accepting failed.
+        let mut accept_paused: Option<Instant> = None;
+
+        let mut buf = [0u8; 1600];
+
+        loop {
+            let timeout = next_check(&client_info, &peers).into_iter()
+                .chain(next_unestablished(&conns))
+                .chain(accept_paused.map(|since| ACCEPT_BACKOFF.checked_sub(since.elapsed()).unwrap_or_default()))
+                .min();
+            poll.poll(&mut events, timeout).expect("poll failed");
+            for event in events.iter() {
+                match event.token() {
+                    LISTEN_TOKEN => {
+                        loop {
+                            match listener.accept() {
+                                Ok((stream, address)) => {
+                                    if let Err(e) = stream.set_nodelay(true) {
+                                        warn!("Failed to set TCP_NODELAY for {}: {}", address, e);
+                                        continue
+                                    }
+                                    let conn = boring::Framed::new(stream);
+                                    let token = mio::Token(next_conn);
+                                    next_conn += 1;
+                                    if let Err(e) = poll.register(conn.stream(), token, conn.interest(), mio::PollOpt::level()) {
+                                        warn!("Failed to wait for connection from {}: {}", address, e);
+                                        continue
+                                    }
+                                    info!("Accepted connection from {}.", address);
+                                    conns.insert(token, (conn, address, None, Instant::now()));
+                                },
+                                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                                // Out of file descriptors, for one. The listener stays readable,
+                                // it is left alone for a while instead of failing over and over.
+                                Err(e) => {
+                                    warn!("Failed to accept connection: {}", e);
+                                    poll.deregister(&listener).unwrap();
+                                    accept_paused = Some(Instant::now());
+                                    break
+                                }
+                            }
+                        }
+                    },
+                    TUN_TOKEN => {
+                        let end = buf.len() - boring::TAG_LEN;
+                        let len: usize = match tun.read(&mut buf[boring::HEADER_LEN..end]) {
+                            Ok(len) => len,
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(e) => return Err(Error::TunTapDev("failed to read from tun device",e))
+                        };
+                        if self.mode == device::Type::Tap {
+                            let frame = &buf[boring::HEADER_LEN..boring::HEADER_LEN + len];
+                            switch_frame(&mut leases, &mut tun, &mut client_info, frame, Port::Local, |session, packet| send_tcp(&poll, &mut conns, session.endpoint, packet));
+                            continue
+                        }
+                        let client_ip = match destination(&buf[boring::HEADER_LEN..boring::HEADER_LEN + len]) {
+                            Some(client_ip) => client_ip,
+                            None => {
+                                warn!("Dropping malformed packet from {}.", tun.ifname());
+                                continue
+                            }
+                        };
+                        match leases.route(client_ip).and_then(|id| client_info.get_mut(&id)) {
+                            None => warn!("Unknown data to ip {}.", client_ip.to_string()),
+                            Some(session) => {
+                                if let Some((conn, address, _, _)) = conns.get_mut(&session.endpoint) {
+                                    let rekey = session.rekey();
+                                    let size = session.keys.seal_data(&mut buf, len);
+                                    for packet in rekey.iter().map(|rekey| &rekey[..]).chain(Some(&buf[..size])) {
+                                        if let Err(e) = conn.send(packet) {
+                                            warn!("Failed to send to {}: {}", address, e);
+                                        }
+                                    }
+                                    poll.reregister(conn.stream(), session.endpoint, conn.interest(), mio::PollOpt::level()).unwrap();
+                                }
+                            }
+                        }
+                    },
+                    SIGNAL_TOKEN if utils::interrupted(&signals) => {
+                        info!("Shutting down, disconnecting {} clients.", client_info.len());
+                        for (_, mut session) in client_info.drain() {
+                            if let Some((conn, address, _, _)) = conns.get_mut(&session.endpoint) {
+                                if let Err(e) = conn.send(&session.keys.seal(&boring::Message::Disconnect)) {
+                                    warn!("Failed to send to {}: {}", address, e);
+                                }
+                            }
+                            leases.release(&session);
+                        }
+                        return Ok(())
+                    },
+                    SIGNAL_TOKEN => {},
+                    conn_token => {
+                        let mut closed = false;
+                        // Frames of TAP mode, switched once the connection is no longer borrowed.
+                        let mut switched = Vec::new();
+                        if let Some((conn, address, established, _)) = conns.get_mut(&conn_token) {
+                            let address = *address;
+                            if event.readiness().is_writable() {
+                                if let Err(e) = conn.flush() {
+                                    warn!("Failed to send to {}: {}", address, e);
+                                    closed = true;
+                                }
+                            }
+                            let frames = match event.readiness().is_readable() && !closed {
+                                true => conn.read_frames(),
+                                false => Ok(Vec::new())
+                            };
+                            match frames {
+                                Err(e) => {
+                                    info!("Connection from {} closed: {}", address, e);
+                                    closed = true;
+                                },
+                                Ok(frames) => for mut frame in frames {
+                                    // Once a connection completed the handshake everything on it
+                                    // is sealed with the session key.
+                                    if let Some(id) = *established {
+                                        match client_info.get_mut(&id) {
+                                            Some(session) if session.endpoint == conn_token => {
+                                                match session.forward(&mut tun, self.mode, &mut frame, &address) {
+                                                    Ok(Forwarded::Reply(Some(reply))) => {
+                                                        if let Err(e) = conn.send(&reply) {
+                                                            warn!("Failed to send to {}: {}", address, e);
+                                                        }
+                                                    },
+                                                    Ok(Forwarded::Reply(None)) => {},
+                                                    Ok(Forwarded::Frame(size)) => {
+                                                        frame.truncate(boring::HEADER_LEN + size);
+                                                        frame.drain(..boring::HEADER_LEN);
+                                                        switched.push((id, frame));
+                                                    },
+                                                    // Closing the connection releases the session.
+                                                    Err(Error::Disconnected) => {
+                                                        info!("Client {} at {} disconnected.", session.ip, address);
+                                                        closed = true;
+                                                        break
+                                                    },
+                                                    Err(e) => warn!("Invalid packet from {}: {}", address, e)
+                                                }
+                                            },
+                                            _ => {
+                                                info!("Session of {} expired.", address);
+                                                closed = true;
+                                                break
+                                            }
+                                        }
+                                        continue
+                                    }
+                                    // The TCP handshake already proved the client's address,
+                                    // cookies are not needed here.
+                                    let len = match boring::strip_cookie_mac(&mut frame) {
+                                        Ok((hello, _)) => hello.len(),
+                                        Err(e) => {
+                                            warn!("Invalid frame from {}: {}", address, e);
+                                            closed = true;
+                                            break
+                                        }
+                                    };
+                                    frame.truncate(len);
+                                    let kind = match boring::Header::parse(&frame) {
+                                        Ok(header) => header.kind,
+                                        Err(e) => {
+                                            warn!("Invalid frame from {}: {}", address, e);
+                                            closed = true;
+                                            break
+                                        }
+                                    };
+                                    let digest = hello::digest(&frame);
+                                    let (mut handshake, msg, peer, revoked) = match self.open_hello(&mut frame, kind, &mut peers) {
+                                        Ok(opened) => opened,
+                                        Err(e) => {
+                                            warn!("Invalid handshake from {}: {}", address, e);
+                                            closed = true;
+                                            break
+                                        }
+                                    };
+                                    let id = new_session_id(&mut rng, &client_info);
+                                    let result = match (revoked, hellos.check(&digest, hello_time(&msg))) {
+                                        (true, _) => Err(Error::Rejected("client key revoked".to_string())),
+                                        (false, Ok(Hello::New)) => self.shakehand(msg, peer, conn_token, &mut handshake, &mut leases, id),
+                                        // A client sends its hello once per connection, this one is a copy.
+                                        (false, Ok(_)) => {
+                                            warn!("Replayed hello from {}, closing the connection.", address);
+                                            closed = true;
+                                            break
+                                        },
+                                        (false, Err(e)) => Err(e)
+                                    };
+                                    match result {
+                                        Ok((session,encrypted_msg)) => {
+                                            info!("Got request from {}. Assigning IP address: {}, {} left in pool.",
+                                              address,
+                                              session.addresses().map(|ip| ip.to_string()).collect::<Vec<_>>().join(" "),
+                                              leases.available());
+                                            leases.insert(&session);
+                                            client_info.insert(id, session);
+                                            hellos.answered(&digest, id, &encrypted_msg);
+                                            *established = Some(id);
+                                            if let Err(e) = conn.send(&encrypted_msg) {
+                                                warn!("Failed to send to {}: {}", address, e);
+                                                closed = true;
+                                            }
+                                        },
+                                        Err(e) => {
+                                            hellos.failed(&digest);
+                                            match rejection(&e) {
+                                                Some(reject) => {
+                                                    warn!("Rejected request from {}: {}", address, e);
+                                                    let sent = handshake.seal(&reject).and_then(|encrypted_msg| {
+                                                        conn.send(&encrypted_msg).map_err(|e| Error::Socket("failed to send rejection",e))
+                                                    });
+                                                    if let Err(e) = sent {
+                                                        warn!("Failed to send to {}: {}", address, e);
+                                                    }
+                                                },
+                                                None => {
+                                                    warn!("Invalid message from {}: {}", address, e);
+                                                    closed = true;
+                                                    break
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            if !closed {
+                                poll.reregister(conn.stream(), conn_token, conn.interest(), mio::PollOpt::level()).unwrap();
+                            }
+                        }
+                        for (id, frame) in switched {
+                            switch_frame(&mut leases, &mut tun, &mut client_info, &frame, Port::Session(id), |session, packet| send_tcp(&poll, &mut conns, session.endpoint, packet));
+                        }
+                        if closed {
+                            if let Some((conn, _, established, _)) = conns.remove(&conn_token) {
+                                poll.deregister(conn.stream()).unwrap();
+                                if let Some(session) = established.and_then(|id| client_info.remove(&id)) {
+                                    leases.release(&session);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            if accept_paused.is_some_and(|since| since.elapsed() >= ACCEPT_BACKOFF) {
+                poll.register(&listener, LISTEN_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+                accept_paused = None;
+            }
+            for token in unestablished(&conns) {
+                if let Some((conn, address, _, _)) = conns.remove(&token) {
+                    info!("Connection from {} did not complete the handshake, closing it.", address);
+                    poll.deregister(conn.stream()).unwrap();
+                }
+            }
+            for session in expire(&mut client_info) {
+                leases.release(&session);
+                if let Some((conn, address, _, _)) = conns.remove(&session.endpoint) {
+                    info!("Client {} at {} stopped responding, closing its connection.", session.ip, address);
+                    poll.deregister(conn.stream()).unwrap();
+                }
+            }
+            for mut session in revoke(&mut client_info, &peers.reload()) {
+                leases.release(&session);
+                if let Some((mut conn, address, _, _)) = conns.remove(&session.endpoint) {
+                    info!("Key of client {} at {} was revoked, closing its connection.", session.ip, address);
+                    if let Err(e) = conn.send(&session.keys.seal(&revoked())) {
+                        warn!("Failed to send to {}: {}", address, e);
+                    }
+                    poll.deregister(conn.stream()).unwrap();
+                }
+            }
+            for session in client_info.values_mut() {
+                if let Some(ping) = session.keys.keepalive() {
+                    if let Some((conn, address, _, _)) = conns.get_mut(&session.endpoint) {
+                        if let Err(e) = conn.send(&ping) {
+                            warn!("Failed to send to {}: {}", address, e);
+                        }
+                        poll.reregister(conn.stream(), session.endpoint, conn.interest(), mio::PollOpt::level()).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::*;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn packet_address_test() {
+        let mut ipv4 = [0u8; 20];
+        ipv4[0] = 0x45;
+        ipv4[12..16].copy_from_slice(&[10, 10, 10, 2]);
+        ipv4[16..20].copy_from_slice(&[8, 8, 8, 8]);
+        assert_eq!(source(&ipv4), Some("10.10.10.2".parse().unwrap()));
+        assert_eq!(destination(&ipv4), Some("8.8.8.8".parse().unwrap()));
+        assert_eq!(source(&ipv4[..19]), None);
+
+        let mut ipv6 = [0u8; 40];
+        ipv6[0] = 0x60;
+        ipv6[8..24].copy_from_slice(&"fd00:8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[24..40].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        assert_eq!(source(&ipv6), Some("fd00:8::2".parse().unwrap()));
+        assert_eq!(destination(&ipv6), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(destination(&ipv6[..39]), None);
+        assert_eq!(destination(&[0u8; 40]), None);
+    }
+}
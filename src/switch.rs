@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::time::{Duration,Instant};
+
+use crate::boring::SessionId;
+
+/// How long an address is remembered without a frame from it, the default
+/// ageing time of a Linux bridge.
+const AGEING_TIME: Duration = Duration::from_secs(300);
+/// Addresses learned at most. A client sending from ever new addresses
+/// would otherwise grow the table without bound, frames to addresses that
+/// did not fit are flooded.
+const MAX_ENTRIES: usize = 4096;
+
+pub type Mac = [u8; 6];
+
+/// Where a frame enters or leaves the switch.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Port {
+    /// Our TAP device and whatever it is bridged to.
+    Local,
+    Session(SessionId)
+}
+
+/// What to do with a frame.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Forward {
+    Drop,
+    To(Port),
+    /// Send it out of every port but the one it came in on.
+    Flood
+}
+
+fn mac(frame: &[u8],offset: usize) -> Option<Mac> {
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(frame.get(offset..offset + 6)?);
+    Some(mac)
+}
+
+/// Broadcast is a multicast address too.
+fn is_multicast(mac: &Mac) -> bool {
+    mac[0] & 1 == 1
+}
+
+/// The TAP mode's learning switch between our TAP device and the clients.
+/// It remembers which port the source address of a frame is behind, so
+/// frames to that address only go there. Frames to broadcast, multicast or
+/// unknown addresses go everywhere else.
+pub struct MacTable {
+    entries: HashMap<Mac,(Port,Instant)>
+}
+
+impl MacTable {
+    pub fn new() -> Self {
+        MacTable {
+            entries: HashMap::new()
+        }
+    }
+
+    fn learn(&mut self,source: Mac,port: Port) {
+        let now = Instant::now();
+        if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&source) {
+            self.entries.retain(|_, &mut (_, seen)| now.duration_since(seen) < AGEING_TIME);
+            if self.entries.len() >= MAX_ENTRIES {
+                return
+            }
+        }
+        // An address showing up behind another port moved there.
+        self.entries.insert(source, (port, now));
+    }
+
+    /// Learns the source of an Ethernet frame that came in on `from` and
+    /// decides where it goes. Frames too short for an Ethernet header or
+    /// claiming a multicast source are dropped.
+    pub fn forward(&mut self,frame: &[u8],from: Port) -> Forward {
+        let (destination, source) = match (mac(frame, 0), mac(frame, 6)) {
+            (Some(destination), Some(source)) if frame.len() >= 14 && !is_multicast(&source) => (destination, source),
+            _ => return Forward::Drop
+        };
+        self.learn(source, from);
+        if is_multicast(&destination) {
+            return Forward::Flood
+        }
+        match self.entries.get(&destination) {
+            Some(&(port, seen)) if seen.elapsed() < AGEING_TIME => match port == from {
+                true => Forward::Drop,
+                false => Forward::To(port)
+            },
+            _ => Forward::Flood
+        }
+    }
+
+    /// Forgets the addresses behind a closed session.
+    pub fn forget(&mut self,session: SessionId) {
+        self.entries.retain(|_, &mut (port, _)| port != Port::Session(session));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::switch::*;
+
+    fn frame(destination: Mac,source: Mac) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&destination);
+        frame.extend_from_slice(&source);
+        frame.extend_from_slice(&[0x08, 0x06]);
+        frame
+    }
+
+    #[test]
+    fn mac_table_test() {
+        let a = [2, 0, 0, 0, 0, 1];
+        let b = [2, 0, 0, 0, 0, 2];
+        let local = [2, 0, 0, 0, 0, 3];
+        let broadcast = [0xff; 6];
+        let mut table = MacTable::new();
+
+        assert_eq!(table.forward(&frame(broadcast, a), Port::Session(1)), Forward::Flood);
+        assert_eq!(table.forward(&frame(a, b), Port::Session(2)), Forward::To(Port::Session(1)));
+        assert_eq!(table.forward(&frame(b, local), Port::Local), Forward::To(Port::Session(2)));
+        assert_eq!(table.forward(&frame(local, a), Port::Session(1)), Forward::To(Port::Local));
+        assert_eq!(table.forward(&frame([2, 0, 0, 0, 0, 9], a), Port::Session(1)), Forward::Flood);
+        assert_eq!(table.forward(&frame([0x33, 0x33, 0, 0, 0, 1], a), Port::Session(1)), Forward::Flood);
+        // Both ends behind the same port.
+        assert_eq!(table.forward(&frame(a, [2, 0, 0, 0, 0, 4]), Port::Session(1)), Forward::Drop);
+        assert_eq!(table.forward(&frame(a, broadcast), Port::Session(2)), Forward::Drop);
+        assert_eq!(table.forward(&frame(a, b)[..13], Port::Session(2)), Forward::Drop);
+
+        // a moved to another client.
+        assert_eq!(table.forward(&frame(b, a), Port::Session(3)), Forward::To(Port::Session(2)));
+        assert_eq!(table.forward(&frame(a, local), Port::Local), Forward::To(Port::Session(3)));
+
+        table.forget(3);
+        assert_eq!(table.forward(&frame(a, local), Port::Local), Forward::Flood);
+        table.entries.get_mut(&b).unwrap().1 -= AGEING_TIME;
+        assert_eq!(table.forward(&frame(b, local), Port::Local), Forward::Flood);
+    }
+}
@@ -1,6 +1,26 @@
 use std::fmt;
-use std::io::{self, Write};
+use std::io;
 use std::net::AddrParseError;
+use std::str::FromStr;
+
+/// Transport carrying the tunnel between client and server.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Proto {
+    Udp,
+    Tcp
+}
+
+impl FromStr for Proto {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Proto,Error> {
+        match s {
+            "udp" => Ok(Proto::Udp),
+            "tcp" => Ok(Proto::Tcp),
+            _ => Err(Error::Config(format!("unknown protocol '{}', expected udp or tcp", s)))
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
@@ -13,7 +33,18 @@ pub enum Error {
     Beacon(&'static str, io::Error),
     Shakehand(&'static str,io::Error),
     Invaildmessage(&'static str),
-    Route(&'static str)
+    Route(&'static str, io::Error),
+    Config(String),
+    Rejected(String),
+    Unavailable(String),
+    DeadPeer,
+    /// The peer told us it is shutting down.
+    Disconnected,
+    Signal(&'static str, io::Error),
+    /// SIGINT or SIGTERM arrived, we are shutting down.
+    Interrupted,
+    /// A worker thread of the server panicked.
+    Worker(String)
 }
 
 impl fmt::Display for Error {
@@ -28,7 +59,15 @@ impl fmt::Display for Error {
             Error::Beacon(msg, ref err) => write!(formatter, "{}: {:?}", msg, err),
             Error::Shakehand(msg,ref err) => write!(formatter, "{}: {:?}", msg, err),
             Error::Invaildmessage(msg) => write!(formatter, "{}", msg),
-            Error::Route(msg) => write!(formatter, "{}", msg)
+            Error::Route(msg, ref err) => write!(formatter, "{}: {:?}", msg, err),
+            Error::Config(ref msg) => write!(formatter, "{}", msg),
+            Error::Rejected(ref reason) => write!(formatter, "rejected by server: {}", reason),
+            Error::Unavailable(ref reason) => write!(formatter, "server unavailable: {}", reason),
+            Error::DeadPeer => write!(formatter, "peer stopped responding"),
+            Error::Disconnected => write!(formatter, "peer disconnected"),
+            Error::Signal(msg, ref err) => write!(formatter, "{}: {:?}", msg, err),
+            Error::Interrupted => write!(formatter, "interrupted by a signal"),
+            Error::Worker(ref name) => write!(formatter, "thread {} panicked", name)
         }
     }
 }
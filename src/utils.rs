@@ -1,206 +1,254 @@
-use std::process;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
 use libc;
-use log::info;
+use log::{info,warn};
+use signal_hook::iterator::Signals;
+
+use crate::netlink::{self, Route};
+use crate::types::Error;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const IPV4_FORWARDING: &str = "/proc/sys/net/ipv4/ip_forward";
+const IPV6_FORWARDING: &str = "/proc/sys/net/ipv6/conf/all/forwarding";
 
 
 pub fn is_root() -> bool {
     unsafe { libc::geteuid() == 0 }
 }
-pub fn enable_ipv4_forwarding() -> Result<(),String> {
-    let sysctl_arg = "net.ipv4.ip_forward=1";
-    info!("Enable IPv4 Forwarding");
-    let status = process::Command::new("sysctl")
-        .arg("-w")
-        .arg(sysctl_arg)
-        .status()
-        .unwrap();
-    if status.success() {
-        Ok(())
-    } else {
-        Err(format!("sysctl: {}",status))
-    }
-}
 
-pub fn get_default_gateway() -> Result<String,String> {
-    let cmd = "ip -4 route list 0/0 | awk '{print $3}'";
-    let output = process::Command::new("bash")
-        .arg("-c")
-        .arg(cmd)
-        .output()
-        .unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap().trim_right().to_string())
-    } else {
-        Err(String::from_utf8(output.stderr).unwrap())
-    }
+/// Seconds since the Unix epoch, zero if the clock is before it.
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
 }
 
-pub fn add_route(route_type: RouteType,route: &str,gateway: &str) -> Result<(),String> {
-    let mode = match route_type {
-        RouteType::Net => "-net",
-        RouteType::Host => "-host"
-    };
-    info!("Adding route: {} {} gateway {}",mode,route,gateway);
-    let status = process::Command::new("route")
-        .arg("-n")
-        .arg("add")
-        .arg(mode)
-        .arg(route)
-        .arg("gw")
-        .arg(gateway)
-        .status()
-        .unwrap();
-    if status.success() {
-        Ok(())
-    } else {
-        Err(format!("route: {}",status))
+/// The uid of `user`, a name or a number.
+pub fn user_id(user: &str) -> Result<u32,Error> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid)
+    }
+    let unknown = || Error::Config(format!("unknown user '{}'", user));
+    let name = CString::new(user).map_err(|_| unknown())?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    match passwd.is_null() {
+        true => Err(unknown()),
+        false => Ok(unsafe { (*passwd).pw_uid })
     }
 }
 
-pub fn delete_route(route_type: RouteType,route: &str) -> Result<(),String> {
-    let mode = match route_type {
-        RouteType::Net => "-net",
-        RouteType::Host => "-host"
-    };
-    info!("Deleting route: {} {}",mode,route);
-    let status = process::Command::new("route")
-        .arg("-n")
-        .arg("del")
-        .arg(mode)
-        .arg(route)
-        .status()
-        .unwrap();
-    if status.success() {
-        Ok(())
-    } else {
-        Err(format!("route: {}",status))
+/// The gid of `group`, a name or a number.
+pub fn group_id(group: &str) -> Result<u32,Error> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid)
+    }
+    let unknown = || Error::Config(format!("unknown group '{}'", group));
+    let name = CString::new(group).map_err(|_| unknown())?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    match entry.is_null() {
+        true => Err(unknown()),
+        false => Ok(unsafe { (*entry).gr_gid })
     }
 }
 
-pub fn set_default_gateway(gateway: &str) -> Result<(),String> {
-    add_route(RouteType::Net, "default", gateway)
+pub fn enable_ipv4_forwarding() -> Result<(),io::Error> {
+    info!("Enable IPv4 Forwarding");
+    fs::write(IPV4_FORWARDING, "1")
 }
 
-pub fn delete_default_gateway() -> Result<(),String> {
-    delete_route(RouteType::Net, "default")
+pub fn enable_ipv6_forwarding() -> Result<(),io::Error> {
+    info!("Enable IPv6 Forwarding");
+    fs::write(IPV6_FORWARDING, "1")
 }
 
-pub enum RouteType {
-    Net,
-    Host,
+pub fn attach_to_bridge(ifname: &str,bridge: &str) -> Result<(),io::Error> {
+    info!("Attaching {} to bridge {}",ifname,bridge);
+    netlink::set_master(netlink::index(ifname)?, netlink::index(bridge)?)
 }
 
+/// Keeps the server reachable outside the tunnel through a host route and,
+/// with `default`, points the IPv4 default route at the tunnel. The original
+/// routes are restored when dropped.
 pub struct DefaultGateWay {
-    origin: String,
-    remote: String,
-    default: bool
+    /// The host route we added, None if the host had one already.
+    host: Option<Route>,
+    /// The default route we replaced, if the host had one.
+    origin: Option<Route>,
+    default: Option<Route>
 }
 
 impl DefaultGateWay {
-    pub fn create(gateway: &str,remote: &str,default: bool) -> Result<DefaultGateWay,String> {
-        let origin = get_default_gateway().expect("default no found");
-        info!("Original default gateway: {}",origin);
-        add_route(RouteType::Host, remote, &origin).map_err(|err| err.to_string())?;
+    pub fn create(gateway: IpAddr,remote: IpAddr,default: bool) -> Result<DefaultGateWay,Error> {
+        // Pin the route `remote` takes right now, so it stays outside the
+        // tunnel once the default route points into it.
+        let host = netlink::route_to(remote).map_err(|e| Error::Route("failed to look up the route to the server", e))?;
+        info!("Adding route: {}",host);
+        // A host route someone else put there is left alone, now and on drop.
+        let host = match netlink::add_route(&host, false) {
+            Ok(()) => Some(host),
+            Err(ref e) if e.raw_os_error() == Some(libc::EEXIST) => {
+                info!("Route to {} exists already, keeping it",remote);
+                None
+            },
+            Err(e) => return Err(Error::Route("failed to add the route to the server", e))
+        };
+        let mut routes = DefaultGateWay {
+            host: host,
+            origin: None,
+            default: None
+        };
         if default {
-            delete_default_gateway().map_err(|err| err.to_string())?;
-            set_default_gateway(gateway).map_err(|err| err.to_string())?;
+            // None if the host only has IPv6 connectivity.
+            let origin = netlink::default_route(false).map_err(|e| Error::Route("failed to read the default route", e))?;
+            if let Some(origin) = origin {
+                info!("Original default route: {}",origin);
+                netlink::delete_route(&origin).map_err(|e| Error::Route("failed to remove the default route", e))?;
+                routes.origin = Some(origin);
+            }
+            let route = Route::default_via(gateway);
+            info!("Adding route: {}",route);
+            netlink::add_route(&route, false).map_err(|e| Error::Route("failed to add the default route", e))?;
+            routes.default = Some(route);
         }
-        Ok(DefaultGateWay {
-            origin: origin,
-            remote: String::from(remote),
-            default: default
-        })
+        Ok(routes)
     }
 }
 
 impl Drop for DefaultGateWay {
     fn drop(&mut self) {
-        if self.default {
-            delete_default_gateway().unwrap();
-            set_default_gateway(&self.origin).unwrap();
+        // Our default route is gone already if the TUN device went first.
+        if let Some(ref default) = self.default {
+            if let Err(e) = netlink::delete_route(default) {
+                warn!("Failed to remove route {}: {}",default,e);
+            }
+        }
+        if let Some(ref origin) = self.origin {
+            info!("Restoring route: {}",origin);
+            if let Err(e) = netlink::add_route(origin, false) {
+                warn!("Failed to restore route {}: {}",origin,e);
+            }
+        }
+        if let Some(ref host) = self.host {
+            if let Err(e) = netlink::delete_route(host) {
+                warn!("Failed to remove route {}: {}",host,e);
+            }
         }
-        delete_route(RouteType::Host, &self.remote).unwrap();
     }
 }
-pub struct HostRoute {
-    remote: String
+
+/// Points the IPv6 default route at the tunnel and puts the original one back when dropped.
+pub struct DefaultGateWay6 {
+    origin: Option<Route>,
+    route: Route
 }
 
-impl HostRoute {
-    pub fn create(remote: &str) -> HostRoute {
-        let gateway = get_default_gateway().unwrap();
-        info!("default gateway: {}",gateway);
-        add_route(RouteType::Host, remote, &gateway).unwrap();
-        HostRoute {
-            remote: remote.to_string()
+impl DefaultGateWay6 {
+    pub fn create(gateway: IpAddr) -> Result<DefaultGateWay6,Error> {
+        let origin = netlink::default_route(true).map_err(|e| Error::Route("failed to read the ipv6 default route", e))?;
+        // Of the same priority, ours takes the place of the original.
+        let route = Route {
+            priority: origin.as_ref().and_then(|origin| origin.priority),
+            ..Route::default_via(gateway)
+        };
+        if let Some(ref origin) = origin {
+            info!("Original IPv6 default route: {}",origin);
         }
+        info!("Replacing route: {}",route);
+        netlink::add_route(&route, true).map_err(|e| Error::Route("failed to add the ipv6 default route", e))?;
+        Ok(DefaultGateWay6 {
+            origin: origin,
+            route: route
+        })
     }
 }
 
-impl Drop for HostRoute {
+impl Drop for DefaultGateWay6 {
     fn drop(&mut self) {
-        delete_route(RouteType::Host, &self.remote).unwrap();
+        // Our route is gone already if the TUN device went first.
+        let result = match self.origin {
+            Some(ref origin) => netlink::add_route(origin, true),
+            None => netlink::delete_route(&self.route)
+        };
+        if let Err(err) = result {
+            warn!("Failed to restore the IPv6 default route: {}",err);
+        }
     }
 }
 
-fn get_route_gateway(route: &str) -> Result<String,String> {
-    let cmd = format!("ip -4 route list {}",route);
-    let output = process::Command::new("bash")
-        .arg("-c")
-        .arg(cmd)
-        .output()
-        .unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap().trim_right().to_string())
-    } else {
-        Err(String::from_utf8(output.stderr).unwrap())
-    }
-
+/// The resolver configuration from before `set_dns` overwrote it, written
+/// back when dropped.
+pub struct ResolvConf {
+    original: Vec<u8>
 }
 
-pub fn get_public_ip() -> Result<String, String> {
-    let output = process::Command::new("curl")
-        .arg("ipecho.net/plain")
-        .output()
-        .unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap())
-    } else {
-        Err(String::from_utf8(output.stderr).unwrap())
+impl ResolvConf {
+    pub fn save() -> Option<ResolvConf> {
+        match fs::read(RESOLV_CONF) {
+            Ok(original) => Some(ResolvConf { original: original }),
+            Err(e) => {
+                warn!("Failed to read {}, it will not be restored: {}", RESOLV_CONF, e);
+                None
+            }
+        }
     }
 }
 
-pub fn set_dns(dns: &str) -> Result<String,String> {
-    let cmd = format!("echo nameserver {} > /etc/resolv.conf",dns);
-    let output = process::Command::new("bash")
-        .arg("-c")
-        .arg(cmd)
-        .output()
-        .unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap())
-    } else {
-        Err(String::from_utf8(output.stderr).unwrap())
+impl Drop for ResolvConf {
+    fn drop(&mut self) {
+        match fs::write(RESOLV_CONF, &self.original) {
+            Ok(()) => info!("Restored {}.", RESOLV_CONF),
+            Err(e) => warn!("Failed to restore {}: {}", RESOLV_CONF, e)
+        }
     }
 }
 
+/// Catches SIGINT and SIGTERM, so we shut down cleanly instead of dying with
+/// the routes still pointing into the tunnel. Register the result with the
+/// poll of a loop to hear about them.
+pub fn shutdown_signals() -> io::Result<Signals> {
+    Signals::new([signal_hook::SIGINT, signal_hook::SIGTERM])
+}
+
+/// Whether a signal caught by `signals` arrived since the last check.
+pub fn interrupted(signals: &Signals) -> bool {
+    signals.pending().next().is_some()
+}
+
+/// Sleeps for `timeout` unless a signal caught by `signals` arrives first,
+/// returns whether one did.
+pub fn sleep_or_signal(signals: &Signals,timeout: Duration) -> io::Result<bool> {
+    let poll = mio::Poll::new()?;
+    poll.register(signals, mio::Token(0), mio::Ready::readable(), mio::PollOpt::level())?;
+    let mut events = mio::Events::with_capacity(1);
+    poll.poll(&mut events, Some(timeout))?;
+    Ok(interrupted(signals))
+}
+
+pub fn set_dns(dns: IpAddr) -> Result<(),io::Error> {
+    fs::write(RESOLV_CONF, format!("nameserver {}\n", dns))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::utils::*;
-    
-    #[test]
-    fn get_default_gateway_test() {
-        let a = get_default_gateway().unwrap();
-        assert!(get_route_gateway("0/0").unwrap().contains(&*a))
-    }
 
     #[test]
-    fn route_test() {
+    fn host_route_test() {
         assert!(is_root());
-        let gw = get_default_gateway().unwrap();
-        add_route(RouteType::Host, "1.1.1.1", &gw).unwrap();
-        assert!(get_route_gateway("1.1.1.1").unwrap().contains(&*gw));
-        delete_route(RouteType::Host, "1.1.1.1").unwrap();
-        assert!(!get_route_gateway("1.1.1.1").unwrap().contains(&*gw));
+        let remote = "198.51.100.9".parse().unwrap();
+        let current = netlink::route_to(remote).unwrap();
+        let routes = DefaultGateWay::create("10.98.0.1".parse().unwrap(), remote, false).unwrap();
+        assert_eq!(routes.host.as_ref().unwrap().destination, remote);
+        assert_eq!(netlink::route_to(remote).unwrap().gateway, current.gateway);
+        drop(routes);
+        assert!(netlink::delete_route(&current).is_err());
+
+        // One that was there before outlives us.
+        netlink::add_route(&current, false).unwrap();
+        let routes = DefaultGateWay::create("10.98.0.1".parse().unwrap(), remote, false).unwrap();
+        assert!(routes.host.is_none());
+        drop(routes);
+        netlink::delete_route(&current).unwrap();
     }
-}
\ No newline at end of file
+}